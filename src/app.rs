@@ -23,6 +23,7 @@ impl Plugin for AppPlugin {
 
 
         app.add_plugins(crate::plugins::camera_plugin::CameraPlugin);
+        app.add_plugins(crate::plugins::floating_origin_plugin::FloatingOriginPlugin);
         app.add_plugins(crate::plugins::ui_plugin::UiPlugin);
 
         app.add_plugins(crate::plugins::environment_plugin::EnvironmentPlugin);
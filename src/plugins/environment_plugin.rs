@@ -2,18 +2,33 @@
 use bevy::app::{App, Plugin, Startup};
 use bevy::color::palettes::basic::{GREEN, YELLOW};
 use bevy::color::palettes::css::RED;
+use bevy::diagnostic::RegisterDiagnostic;
 use bevy::prelude::*;
 use crate::systems::environment_system::*;
-use crate::systems::voxels::structure::{OctreeNode, SparseVoxelOctree};
+use crate::systems::voxels::events::{RegionChanged, VoxelInserted, VoxelRemoved};
+use crate::systems::voxels::material::MaterialPalette;
+use crate::systems::voxels::stats::octree_diagnostics;
+use crate::systems::voxels::structure::{OctreeNode, SparseVoxelOctree, Voxel};
 
 pub struct EnvironmentPlugin;
 impl Plugin for EnvironmentPlugin {
     fn build(&self, app: &mut App) {
 
         app.add_systems(Startup, (setup).chain());
-        app.add_systems(Update, (crate::systems::voxels::rendering::render,crate::systems::voxels::debug::visualize_octree_system.run_if(should_visualize_octree), crate::systems::voxels::debug::draw_grid.run_if(should_draw_grid)).chain());
+        app.add_systems(Update, (crate::systems::voxels::events::emit_voxel_events::<Voxel>, crate::systems::voxels::stats::update_octree_stats::<Voxel>, crate::systems::voxels::rendering::render,crate::systems::voxels::debug::visualize_octree_system.run_if(should_visualize_octree), crate::systems::voxels::debug::draw_grid.run_if(should_draw_grid)).chain());
+
+        app.init_resource::<MaterialPalette>();
+
+        app.add_event::<VoxelInserted>();
+        app.add_event::<VoxelRemoved>();
+        app.add_event::<RegionChanged>();
+
+        for diagnostic in octree_diagnostics() {
+            app.register_diagnostic(diagnostic);
+        }
 
         app.register_type::<SparseVoxelOctree>();
+        app.register_type::<MaterialPalette>();
 
     }
 
@@ -21,15 +36,16 @@ impl Plugin for EnvironmentPlugin {
 
 }
 
+// Each octree has its own toggles; the systems run if any octree wants them and skip the others.
 fn should_visualize_octree(octree_query: Query<&SparseVoxelOctree>,) -> bool {
-    octree_query.single().show_wireframe
+    octree_query.iter().any(|octree| octree.show_wireframe)
 }
 
 fn should_draw_grid(octree_query: Query<&SparseVoxelOctree>,) -> bool {
-    octree_query.single().show_world_grid
+    octree_query.iter().any(|octree| octree.show_world_grid)
 }
 
 fn should_visualize_chunks(octree_query: Query<&SparseVoxelOctree>,) -> bool {
-    octree_query.single().show_chunks
+    octree_query.iter().any(|octree| octree.show_chunks)
 }
 
@@ -0,0 +1,21 @@
+use bevy::app::{App, Plugin, PostUpdate};
+use bevy::prelude::{IntoSystemConfigs, TransformSystem};
+use crate::systems::double_transform::DoubleTransform;
+use crate::systems::floating_origin_system::*;
+
+/// Keeps rendering close to (0,0,0): entities are placed with a `DoubleTransform`, and their
+/// `Transform` is derived relative to a `RenderOrigin` that jumps to `MainCamera` whenever the
+/// camera gets further than `FloatingOrigin::recenter_distance` away from it.
+pub struct FloatingOriginPlugin;
+impl Plugin for FloatingOriginPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(PostUpdate, (recenter_origin, sync_double_transforms).chain().before(TransformSystem::TransformPropagate));
+
+        app.init_resource::<RenderOrigin>();
+        app.init_resource::<FloatingOrigin>();
+
+        app.register_type::<DoubleTransform>();
+        app.register_type::<RenderOrigin>();
+        app.register_type::<FloatingOrigin>();
+    }
+}
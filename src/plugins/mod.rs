@@ -1,4 +1,5 @@
 
 pub mod camera_plugin;
 pub mod ui_plugin;
-pub mod environment_plugin;
\ No newline at end of file
+pub mod environment_plugin;
+pub mod floating_origin_plugin;
\ No newline at end of file
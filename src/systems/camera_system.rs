@@ -6,7 +6,9 @@ use bevy_render::camera::{OrthographicProjection, Projection, ScalingMode};
 use bevy_window::CursorGrabMode;
 use crate::helper::egui_dock::MainCamera;
 use crate::InspectorVisible;
-use crate::systems::voxels::structure::{Ray, SparseVoxelOctree, Voxel};
+use crate::systems::double_transform::DoubleTransform;
+use crate::systems::voxels::material::{MaterialId, MaterialPalette};
+use crate::systems::voxels::structure::{SparseVoxelOctree, Voxel};
 
 #[derive(Component)]
 pub struct CameraController {
@@ -19,7 +21,12 @@ pub struct CameraController {
 
 #[derive(Component, Default)]
 pub struct Selector {
-    pub selected_voxel: Vec3,
+    /// World position of the selected voxel's center.
+    pub selected_voxel: DVec3,
+    /// Material placed by the building tools.
+    pub material: MaterialId,
+    /// Octree the tools act on: the one under the crosshair, or the last one that was.
+    pub target: Option<Entity>,
 }
 
 
@@ -35,12 +42,12 @@ impl Default for CameraController {
     }
 }
 
-pub fn setup(mut commands: Commands,){
+pub fn setup(mut commands: Commands, palette: Res<MaterialPalette>){
 
 
 
     commands.spawn((
-        Transform::from_xyz(0.0, 0.0, 10.0), // initial f32
+        DoubleTransform::from_xyz(0.0, 0.0, 10.0), // initial f64
         GlobalTransform::default(),
         Camera3d::default(),
         Projection::from(PerspectiveProjection{
@@ -49,7 +56,10 @@ pub fn setup(mut commands: Commands,){
         }),
         MainCamera,
         CameraController::default(),
-        Selector::default(),
+        Selector {
+            material: palette.find("brick").unwrap_or_default(),
+            ..default()
+        },
         ));
 
 
@@ -63,12 +73,12 @@ pub fn camera_controller_system(
     mut mouse_motion_events: EventReader<MouseMotion>,
     mut mouse_wheel_events: EventReader<MouseWheel>,
     mut windows: Query<&mut Window>,
-    // Here we query for BOTH DoubleTransform (f64) and Transform (f32).
-    // We'll update DoubleTransform for the "true" position
-    // and keep Transform in sync for rendering.a
-    mut query: Query<(&mut Transform, &mut CameraController)>,
+    // We update DoubleTransform (f64) for the "true" position;
+    // sync_double_transforms derives the Transform relative to the floating origin for rendering.
+    mut query: Query<(&mut DoubleTransform, &mut CameraController)>,
     mut selector: Query<(&mut Selector), With<CameraController>>,
-    mut octree_query: Query<&mut SparseVoxelOctree>,
+    mut octree_query: Query<(Entity, &mut SparseVoxelOctree, &DoubleTransform), Without<CameraController>>,
+    palette: Res<MaterialPalette>,
     mut app_exit_events: EventWriter<AppExit>,
 ) {
     let mut window = windows.single_mut();
@@ -92,7 +102,7 @@ pub fn camera_controller_system(
             let rot_yaw = Quat::from_axis_angle(Vec3::Y, yaw_radians);
             let rot_pitch = Quat::from_axis_angle(Vec3::X, -pitch_radians);
 
-            transform.rotation = rot_yaw * rot_pitch;
+            transform.rotation = (rot_yaw * rot_pitch).as_dquat();
         }
     }
 
@@ -111,41 +121,48 @@ pub fn camera_controller_system(
     // ====================
     // 3) Handle Keyboard Movement (WASD, Space, Shift)
     // ====================
-    let mut direction = Vec3::ZERO;
+    // Keys pressed with Control are shortcuts (Ctrl+Shift+Z, ...), not movement.
+    let control = keyboard_input.pressed(KeyCode::ControlLeft) || keyboard_input.pressed(KeyCode::ControlRight);
+    let mut direction = DVec3::ZERO;
+    let forward = transform.rotation * DVec3::NEG_Z;
+    let right = transform.rotation * DVec3::X;
+    let up = transform.rotation * DVec3::Y;
 
     // Forward/Back
     if keyboard_input.pressed(KeyCode::KeyW) {
-        direction += transform.forward().as_vec3();
+        direction += forward;
     }
     if keyboard_input.pressed(KeyCode::KeyS) {
-        direction -= transform.forward().as_vec3();
+        direction -= forward;
     }
 
     // Left/Right
     if keyboard_input.pressed(KeyCode::KeyA) {
-        direction -= transform.right().as_vec3();
+        direction -= right;
     }
     if keyboard_input.pressed(KeyCode::KeyD) {
-        direction += transform.right().as_vec3();
+        direction += right;
     }
 
     // Up/Down
     if keyboard_input.pressed(KeyCode::Space) {
-        direction += transform.up().as_vec3();
+        direction += up;
     }
     if keyboard_input.pressed(KeyCode::ShiftLeft) || keyboard_input.pressed(KeyCode::ShiftRight) {
-        direction -= transform.up().as_vec3();
+        direction -= up;
     }
 
     // Normalize direction if needed
-    if direction.length_squared() > 0.0 {
+    if control {
+        direction = DVec3::ZERO;
+    } else if direction.length_squared() > 0.0 {
         direction = direction.normalize();
     }
 
     // Apply movement in double-precision
     let delta_seconds = time.delta_secs_f64();
     let distance = controller.speed as f64 * delta_seconds;
-    transform.translation += direction * distance as f32;
+    transform.translation += direction * distance;
 
     
     
@@ -168,101 +185,97 @@ pub fn camera_controller_system(
 
 
     // =======================
-    // 5) Octree Keys
+    // 5) Targeting
     // =======================
-    if keyboard_input.just_pressed(KeyCode::F2){
-        for mut octree in octree_query.iter_mut() {
+    // The camera ray picks the closest hit over all octrees; that octree becomes the tool target.
+    // Hits are relative to the camera, the ray is cast in f64 world space.
+    let closest_hit = octree_query
+        .iter()
+        .filter_map(|(entity, octree, octree_transform)| {
+            octree.raycast_double(octree_transform, transform.translation, forward, None).map(|hit| (entity, hit))
+        })
+        .min_by(|(_, a), (_, b)| a.distance.total_cmp(&b.distance));
+    if let Some((entity, _)) = closest_hit {
+        selector.single_mut().target = Some(entity);
+    }
+    // Drop a target that was despawned.
+    let target = selector.single().target.filter(|&entity| octree_query.contains(entity));
+    selector.single_mut().target = target;
+
+    // =======================
+    // 6) Octree Keys
+    // =======================
+    if let Some((_, mut octree, _)) = target.and_then(|entity| octree_query.get_mut(entity).ok()) {
+        if keyboard_input.just_pressed(KeyCode::F2){
             octree.show_wireframe = !octree.show_wireframe;
         }
-    }
-    if keyboard_input.just_pressed(KeyCode::F3){
-        for mut octree in octree_query.iter_mut() {
+        if keyboard_input.just_pressed(KeyCode::F3){
             octree.show_world_grid = !octree.show_world_grid;
         }
-    }
-    if keyboard_input.just_pressed(KeyCode::F4){
-        for mut octree in octree_query.iter_mut() {
+        if keyboard_input.just_pressed(KeyCode::F4){
             octree.show_chunks = !octree.show_chunks;
         }
+        // Ctrl+Z undoes the last edit, Ctrl+Y (or Ctrl+Shift+Z) redoes it.
+        let shift = keyboard_input.pressed(KeyCode::ShiftLeft) || keyboard_input.pressed(KeyCode::ShiftRight);
+        if control && keyboard_input.just_pressed(KeyCode::KeyZ) && !shift && !octree.undo() {
+            info!("Nothing to undo");
+        }
+        if control && (keyboard_input.just_pressed(KeyCode::KeyY) || (shift && keyboard_input.just_pressed(KeyCode::KeyZ))) && !octree.redo() {
+            info!("Nothing to redo");
+        }
     }
     if keyboard_input.just_pressed(KeyCode::KeyQ) && window.cursor_options.visible == false{
-        for mut octree in octree_query.iter_mut() {
-            octree.insert(transform.translation, Voxel::new(Color::srgb(1.0, 0.0, 0.0)));
+        let material = selector.single().material;
+        if let Some((_, mut octree, octree_transform)) = target.and_then(|entity| octree_query.get_mut(entity).ok()) {
+            // Edits take octree-local positions.
+            let position = octree_transform.inverse_transform_point(transform.translation);
+            octree.insert_double(position, Voxel::new(material));
+        }
+    }
+
+    // Select the building material with the number keys (1 = first palette entry).
+    let material_keys = [
+        KeyCode::Digit1, KeyCode::Digit2, KeyCode::Digit3,
+        KeyCode::Digit4, KeyCode::Digit5, KeyCode::Digit6,
+        KeyCode::Digit7, KeyCode::Digit8, KeyCode::Digit9,
+    ];
+    for (index, key) in material_keys.iter().enumerate() {
+        if keyboard_input.just_pressed(*key) && index < palette.len() {
+            let material = MaterialId(index as u16);
+            selector.single_mut().material = material;
+            info!("Selected Material: {}", palette.resolve(material).name);
         }
     }
 
     // =======================
-    // 6) Building
+    // 7) Building
     // =======================
 
     if (mouse_button_input.just_pressed(MouseButton::Left) || mouse_button_input.just_pressed(MouseButton::Right)) && !window.cursor_options.visible {
 
         // Get the mouse position in normalized device coordinates (-1 to 1)
         if let Some(_) = window.cursor_position() {
-            // Set the ray direction to the camera's forward vector
-            let ray_origin = transform.translation;
-            let ray_direction = transform.forward().normalize();
-
-            let ray = Ray {
-                origin: ray_origin,
-                direction: ray_direction,
-            };
-
-
-
-            for mut octree in octree_query.iter_mut() {
-                if let Some((hit_x, hit_y, hit_z, depth,normal)) = octree.raycast(&ray) {
-                    
-
+            // Only the octree hit first is edited
+            if let Some((entity, hit)) = closest_hit {
+                if let Ok((_, mut octree, _)) = octree_query.get_mut(entity) {
                     if mouse_button_input.just_pressed(MouseButton::Right) {
-
                         if keyboard_input.pressed(KeyCode::ControlLeft) {
-                            let voxel_size = octree.get_spacing_at_depth(depth);
-                            let hit_position = Vec3::new(hit_x as f32, hit_y as f32, hit_z as f32);
-                            let epsilon = voxel_size * 0.1; // Adjust this value as needed (e.g., 0.1 times the voxel size)
-
-                            // Offset position by epsilon in the direction of the normal
-                            let offset_position = hit_position - (normal * Vec3::new(epsilon as f32, epsilon as f32, epsilon as f32));
-
-                            // Align the offset position to the center of the nearest voxel
-                            let new_voxel = octree.normalize_to_voxel_at_depth(
-                                offset_position,
-                                depth,
-                            );
-
-                            selector.single_mut().selected_voxel = new_voxel;
+                            selector.single_mut().selected_voxel = transform.translation + hit.center.as_dvec3();
                             info!("Selected Voxel: {:?}", selector.single().selected_voxel);
-
-
+                            info!("Selected Voxel Material: {}", palette.resolve(hit.voxel.material).name);
                         }
                         else{
-                            let voxel_size = octree.get_spacing_at_depth(depth);
-                            let hit_position = Vec3::new(hit_x as f32, hit_y as f32, hit_z as f32);
-                            let epsilon = voxel_size * 0.1; // Adjust this value as needed (e.g., 0.1 times the voxel size)
-
-                            // Offset position by epsilon in the direction of the normal
-                            let offset_position = hit_position - (normal * Vec3::new(epsilon as f32, epsilon as f32, epsilon as f32));
-
                             // Remove the voxel
-                            octree.remove(offset_position);
+                            octree.remove_key(hit.key);
                         }
-
-
                     }
                     else if mouse_button_input.just_pressed(MouseButton::Left) {
-
-                        let voxel_size = octree.get_spacing_at_depth(depth);
-                        let hit_position = Vec3::new(hit_x as f32, hit_y as f32, hit_z as f32);
-                        let epsilon = voxel_size * 0.1; // Adjust this value as needed (e.g., 0.1 times the voxel size)
-
-                        // Offset position by epsilon in the direction of the normal
-                        let offset_position = hit_position + (normal * Vec3::new(epsilon as f32, epsilon as f32, epsilon as f32));
-
-                        // Insert the new voxel
-                        octree.insert(
-                            offset_position,
-                            Voxel::new(Color::srgb(1.0, 0.0, 0.0)),
-                        );
+                        // Place the new voxel on the face that was hit. Keys are octree-local and may lie
+                        // outside the root; insert_double grows it, and f64 keeps far cells exact.
+                        let position = octree.key_center_double(hit.adjacent_key);
+                        if !octree.insert_double(position, Voxel::new(selector.single().material)) {
+                            warn!("Cannot place a voxel at {position:?}, the octree cannot grow that far");
+                        }
                     }
                 }
             }
@@ -271,7 +284,7 @@ pub fn camera_controller_system(
 
 
     // =======================
-    // 7) Exit on Escape
+    // 8) Exit on Escape
     // =======================
     if keyboard_input.pressed(KeyCode::Escape) {
         app_exit_events.send(Default::default());
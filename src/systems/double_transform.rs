@@ -0,0 +1,82 @@
+use bevy::math::{DQuat, DVec3};
+use bevy::prelude::*;
+
+/// Position, rotation and scale of a top-level entity in f64 world space.
+/// The entity's `Transform` is derived from it relative to `RenderOrigin` by the floating origin,
+/// so rendering only sees small f32 offsets however far from the world origin it is.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Reflect)]
+#[require(Transform)]
+pub struct DoubleTransform {
+    pub translation: DVec3,
+    pub rotation: DQuat,
+    pub scale: DVec3,
+}
+
+impl DoubleTransform {
+    pub const IDENTITY: Self = Self {
+        translation: DVec3::ZERO,
+        rotation: DQuat::IDENTITY,
+        scale: DVec3::ONE,
+    };
+
+    pub fn from_translation(translation: DVec3) -> Self {
+        Self {
+            translation,
+            ..Self::IDENTITY
+        }
+    }
+
+    pub fn from_xyz(x: f64, y: f64, z: f64) -> Self {
+        Self::from_translation(DVec3::new(x, y, z))
+    }
+
+    pub fn with_rotation(mut self, rotation: DQuat) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    pub fn with_scale(mut self, scale: DVec3) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// Maps a point of the entity's local space to world space.
+    pub fn transform_point(&self, point: DVec3) -> DVec3 {
+        self.rotation * (point * self.scale) + self.translation
+    }
+
+    /// Maps a world point into the entity's local space.
+    pub fn inverse_transform_point(&self, point: DVec3) -> DVec3 {
+        (self.rotation.inverse() * (point - self.translation)) / self.scale
+    }
+
+    /// The f32 `Transform` placing the entity relative to `origin`.
+    pub fn relative_to(&self, origin: DVec3) -> Transform {
+        Transform {
+            translation: (self.translation - origin).as_vec3(),
+            rotation: self.rotation.as_quat(),
+            scale: self.scale.as_vec3(),
+        }
+    }
+}
+
+impl Default for DoubleTransform {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn points_round_trip() {
+        let transform = DoubleTransform::from_xyz(4.0e6, -3.0, 2.5e6)
+            .with_rotation(DQuat::from_rotation_y(0.7))
+            .with_scale(DVec3::splat(2.0));
+        let point = DVec3::new(12.25, -0.5, 3.75);
+        let world = transform.transform_point(point);
+        assert!(transform.inverse_transform_point(world).distance(point) < 1e-9);
+    }
+}
@@ -2,7 +2,10 @@ use bevy::color::palettes::basic::*;
 use bevy::color::palettes::css::{BEIGE, MIDNIGHT_BLUE, ORANGE, ORANGE_RED, SEA_GREEN};
 use bevy::math::*;
 use bevy::prelude::*;
-use crate::systems::voxels::structure::{SparseVoxelOctree, Voxel};
+use crate::systems::double_transform::DoubleTransform;
+use crate::systems::voxels::csg::Shape;
+use crate::systems::voxels::material::{MaterialId, MaterialPalette};
+use crate::systems::voxels::structure::{SparseVoxelOctree, Voxel, AABB};
 /*pub fn setup(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
@@ -59,7 +62,7 @@ use crate::systems::voxels::structure::{SparseVoxelOctree, Voxel};
 */
 
 
-pub fn setup(mut commands: Commands,) {
+pub fn setup(mut commands: Commands, palette: Res<MaterialPalette>) {
 
 
     let unit_size = 1.0;
@@ -71,11 +74,14 @@ pub fn setup(mut commands: Commands,) {
     let mut octree = SparseVoxelOctree::new(octree_depth, octree_base_size as f32, false, false, false);
 
     
-    let color = Color::rgb(0.2, 0.8, 0.2);
-    /*generate_voxel_rect(&mut octree,color);*/
-    generate_voxel_sphere(&mut octree, 10, color);
-
-    /*generate_large_plane(&mut octree, 200, 200,color );*/
+    let material = palette.find("grass").unwrap_or_default();
+    /*generate_voxel_rect(&mut octree,material);*/
+    // The generated world is not an edit of the user, so it is not recorded for undo.
+    octree.history.enabled = false;
+    generate_voxel_sphere(&mut octree, 10, material);
+    octree.history.enabled = true;
+
+    /*generate_large_plane(&mut octree, 200, 200,material );*/
     
     
     /*let postion = octree.normalize_to_voxel_at_depth(Vec3::ZERO, 10);
@@ -86,14 +92,14 @@ pub fn setup(mut commands: Commands,) {
     
     commands.spawn(
         (
-            Transform::default(),
+            DoubleTransform::default(),
             octree
         )
     );
 
 
     commands.spawn((
-        Transform::default(),
+        DoubleTransform::default(),
         GlobalTransform::default(),
         PointLight {
             shadows_enabled: true,
@@ -106,121 +112,57 @@ pub fn setup(mut commands: Commands,) {
 
 
 
-/// Naïve function to generate a spherical planet in the voxel octree.
-/// - `planet_radius`: radius of the "planet" in your world-space units
-/// - `voxel_step`: how finely to sample the sphere in the x/y/z loops
+/// Generates a spherical planet in the voxel octree.
+/// - `planet_radius`: radius of the "planet" in voxels at max depth
 fn generate_voxel_sphere(
     octree: &mut SparseVoxelOctree,
     planet_radius: i32,
-    voxel_color: Color,
+    material: MaterialId,
 ) {
-    // For simplicity, we center the sphere around (0,0,0).
-    // We'll loop over a cubic region [-planet_radius, +planet_radius] in x, y, z
-    let min = -planet_radius;
-    let max = planet_radius;
-
     let step = octree.get_spacing_at_depth(octree.max_depth);
 
-    for ix in min..=max {
-        let x = ix;
-        for iy in min..=max {
-            let y = iy;
-            for iz in min..=max {
-                let z = iz;
-
-                // Check if within sphere of radius `planet_radius`
-                let dist2 = x * x + y * y + z * z;
-                if dist2 <= planet_radius * planet_radius {
-                    // Convert (x,y,z) to world space, stepping by `voxel_step`.
-                    let wx = x as f32 * step;
-                    let wy = y as f32 * step;
-                    let wz = z as f32 * step;
-                    let position = Vec3::new(wx, wy, wz);
-
-                    // Insert the voxel
-                    let voxel = Voxel {
-                        color: voxel_color,
-                    };
-                    octree.insert(position, voxel);
-                }
-            }
-        }
-    }
+    // Centered on the cell at (0,0,0), so the cell centers inside are the ones whose integer
+    // offsets satisfy x² + y² + z² <= radius².
+    let sphere = Shape::Sphere {
+        center: Vec3::splat(step * 0.5),
+        radius: planet_radius as f32 * step,
+    };
+    octree.union_shape(&sphere, Voxel::new(material));
 }
 
 
 
-/// Inserts a 16x256x16 "column" of voxels into the octree at (0,0,0) corner.
-/// If you want it offset or centered differently, just adjust the for-loop ranges or offsets.
+/// Fills a 16x256x16 "column" of voxels into the octree at (0,0,0) corner.
+/// If you want it offset or centered differently, just adjust the box corners.
 fn generate_voxel_rect(
     octree: &mut SparseVoxelOctree,
-    voxel_color: Color,
+    material: MaterialId,
 ) {
     // The dimensions of our rectangle: 16 x 256 x 16
-    let size_x = 16;
-    let size_y = 256;
-    let size_z = 16;
+    let size_x = 16.0;
+    let size_y = 256.0;
+    let size_z = 16.0;
 
     // We'll get the voxel spacing (size at the deepest level), same as in your sphere code.
     let step = octree.get_spacing_at_depth(octree.max_depth);
 
-    // Triple-nested loop for each voxel in [0..16, 0..256, 0..16]
-    for ix in 0..size_x {
-        let x = ix as f32;
-        for iy in 0..size_y {
-            let y = iy as f32;
-            for iz in 0..size_z {
-                let z = iz as f32;
-
-                // Convert (x,y,z) to world coordinates
-                let wx = x * step;
-                let wy = y * step;
-                let wz = z * step;
-
-                let position = Vec3::new(wx, wy, wz);
-
-                // Insert the voxel
-                let voxel = Voxel {
-                    color: voxel_color,
-                };
-                octree.insert(position, voxel);
-            }
-        }
-    }
+    // One box covering [0..16, 0..256, 0..16] voxels in world coordinates
+    let aabb = AABB::new(Vec3::ZERO, Vec3::new(size_x, size_y, size_z) * step);
+    octree.fill_aabb(&aabb, Voxel::new(material));
 }
 
 fn generate_large_plane(
     octree: &mut SparseVoxelOctree,
     width: usize,
     depth: usize,
-    color: Color,
+    material: MaterialId,
 ) {
     // We'll get the voxel spacing (size at the deepest level).
     let step = octree.get_spacing_at_depth(octree.max_depth);
 
-    // Double-nested loop for each voxel in [0..width, 0..depth],
-    // with y=0. 
-    for ix in 0..width {
-        let x = ix as f32;
-        for iz in 0..depth {
-            let z = iz as f32;
-            // y is always 0. 
-            let y = 0.0;
-
-            // Convert (x,0,z) to world coordinates
-            let wx = x * step;
-            let wy = y * step;
-            let wz = z * step;
-
-            let position = Vec3::new(wx, wy, wz);
-
-            // Insert the voxel
-            let voxel = Voxel {
-                color,
-            };
-            octree.insert(position, voxel);
-        }
-    }
+    // One voxel thick slab covering [0..width, 0..depth] with y=0.
+    let aabb = AABB::new(Vec3::ZERO, Vec3::new(width as f32, 1.0, depth as f32) * step);
+    octree.fill_aabb(&aabb, Voxel::new(material));
 }
 
 
@@ -0,0 +1,116 @@
+use bevy::math::DVec3;
+use bevy::prelude::*;
+use crate::helper::egui_dock::MainCamera;
+use crate::systems::double_transform::DoubleTransform;
+
+/// World position that is rendered at (0,0,0). Every `Transform` derived from a `DoubleTransform`,
+/// and with it every voxel mesh and gizmo, is relative to it.
+#[derive(Resource, Debug, Clone, Copy, Default, PartialEq, Reflect)]
+pub struct RenderOrigin(pub DVec3);
+
+impl RenderOrigin {
+    /// Converts an f64 world position to the f32 position it is rendered at, e.g. for gizmos.
+    pub fn render_position(&self, position: DVec3) -> Vec3 {
+        (position - self.0).as_vec3()
+    }
+
+    /// Converts a rendered position back to f64 world space.
+    pub fn world_position(&self, position: Vec3) -> DVec3 {
+        self.0 + position.as_dvec3()
+    }
+}
+
+/// How far `MainCamera` may move away from `RenderOrigin` before the world is re-centered on it.
+/// Within that distance f32 is precise enough; beyond it meshes and edits start to jitter.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Reflect)]
+pub struct FloatingOrigin {
+    pub recenter_distance: f64,
+}
+
+impl Default for FloatingOrigin {
+    fn default() -> Self {
+        Self {
+            recenter_distance: 512.0,
+        }
+    }
+}
+
+/// Moves `RenderOrigin` onto `MainCamera` once the camera is more than `recenter_distance` away.
+pub fn recenter_origin(
+    settings: Res<FloatingOrigin>,
+    mut origin: ResMut<RenderOrigin>,
+    camera_query: Query<&DoubleTransform, With<MainCamera>>,
+) {
+    let Ok(camera) = camera_query.get_single() else {
+        return;
+    };
+    if camera.translation.distance(origin.0) > settings.recenter_distance {
+        info!("Re-centering the world on {:?}", camera.translation);
+        origin.0 = camera.translation;
+    }
+}
+
+/// Rebuilds the `Transform` of entities whose `DoubleTransform` changed, and of all of them after
+/// the origin moved. Runs before transform propagation, so children such as meshes follow.
+pub fn sync_double_transforms(
+    origin: Res<RenderOrigin>,
+    mut query: Query<(Ref<DoubleTransform>, &mut Transform)>,
+) {
+    for (double_transform, mut transform) in query.iter_mut() {
+        if origin.is_changed() || double_transform.is_changed() {
+            *transform = double_transform.relative_to(origin.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.init_resource::<RenderOrigin>();
+        app.init_resource::<FloatingOrigin>();
+        app.add_systems(Update, (recenter_origin, sync_double_transforms).chain());
+        app
+    }
+
+    #[test]
+    fn recenters_when_the_camera_drifts() {
+        let mut app = app();
+        let camera = app.world_mut().spawn((MainCamera, DoubleTransform::from_xyz(0.0, 0.0, 10.0))).id();
+        let voxels = app.world_mut().spawn(DoubleTransform::from_xyz(2.0, 0.0, 0.0)).id();
+        app.update();
+        assert_eq!(app.world().resource::<RenderOrigin>().0, DVec3::ZERO);
+        assert_eq!(app.world().get::<Transform>(camera).unwrap().translation, Vec3::new(0.0, 0.0, 10.0));
+
+        // Small moves only move the camera.
+        app.world_mut().get_mut::<DoubleTransform>(camera).unwrap().translation.x = 300.0;
+        app.update();
+        assert_eq!(app.world().resource::<RenderOrigin>().0, DVec3::ZERO);
+        assert_eq!(app.world().get::<Transform>(camera).unwrap().translation, Vec3::new(300.0, 0.0, 10.0));
+        assert_eq!(app.world().get::<Transform>(voxels).unwrap().translation, Vec3::new(2.0, 0.0, 0.0));
+
+        // Past the threshold, the world shifts so the camera is back at the origin.
+        app.world_mut().get_mut::<DoubleTransform>(camera).unwrap().translation.x = 600.0;
+        app.update();
+        assert_eq!(app.world().resource::<RenderOrigin>().0, DVec3::new(600.0, 0.0, 10.0));
+        assert_eq!(app.world().get::<Transform>(camera).unwrap().translation, Vec3::ZERO);
+        assert_eq!(app.world().get::<Transform>(voxels).unwrap().translation, Vec3::new(-598.0, 0.0, -10.0));
+    }
+
+    #[test]
+    fn stays_precise_far_from_the_world_origin() {
+        let mut app = app();
+        // Thousands of kilometres out, where f32 world positions are off by metres.
+        let far = DVec3::new(5.0e6, 0.0, -7.0e6);
+        app.world_mut().spawn((MainCamera, DoubleTransform::from_translation(far)));
+        let voxels = app.world_mut().spawn(DoubleTransform::from_translation(far + DVec3::new(0.125, 1.0, -3.0))).id();
+        app.update();
+
+        let origin = *app.world().resource::<RenderOrigin>();
+        let rendered = app.world().get::<Transform>(voxels).unwrap().translation;
+        assert_eq!(rendered, Vec3::new(0.125, 1.0, -3.0));
+        assert_eq!(origin.world_position(rendered), far + DVec3::new(0.125, 1.0, -3.0));
+    }
+}
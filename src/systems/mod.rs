@@ -3,3 +3,5 @@ pub mod camera_system;
 pub mod ui_system;
 pub mod environment_system;
 pub mod voxels;
+pub mod double_transform;
+pub mod floating_origin_system;
\ No newline at end of file
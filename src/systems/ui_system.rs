@@ -1,6 +1,7 @@
 use bevy::asset::AssetServer;
 use bevy::prelude::*;
 use crate::systems::camera_system::CameraController;
+use crate::systems::double_transform::DoubleTransform;
 use crate::systems::voxels::structure::{SparseVoxelOctree};
 
 #[derive(Component)]
@@ -36,14 +37,13 @@ pub fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
 
 /// System that updates the UI text each frame with 
 ///  - speed
-///  - camera f32 position
 ///  - camera global f64 position
 ///  - current chunk coordinate
 pub fn update(
     // Query the camera controller so we can see its speed
     query_camera_controller: Query<&CameraController>,
-    // We also query for the camera's f32 `Transform` and the double `DoubleTransform`
-    camera_query: Query<(&Transform, &Camera)>,
+    // We also query for the camera's double `DoubleTransform`; its f32 `Transform` is relative to the floating origin
+    camera_query: Query<(&DoubleTransform, &Camera)>,
 
     // The UI text entity
     mut query_text: Query<&mut Text, With<SpeedDisplay>>,
@@ -56,7 +56,7 @@ pub fn update(
 
     // Format the string to show speed, positions, and chunk coords
     text.0 = format!(
-        "\n  Speed: {:.3}\n  Position(f32): ({:.2},{:.2},{:.2})",
+        "\n  Speed: {:.3}\n  Position(f64): ({:.2},{:.2},{:.2})",
         camera_controller.speed,
         transform.translation.x,
         transform.translation.y,
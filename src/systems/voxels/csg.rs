@@ -0,0 +1,400 @@
+use bevy::prelude::*;
+use crate::systems::voxels::events::VoxelChange;
+use crate::systems::voxels::region::{Containment, Region};
+use crate::systems::voxels::structure::{NodeId, NodePool, SparseVoxelOctree, VoxelData, AABB};
+
+/// Analytic brush shapes for CSG edits. All coordinates are in world space.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    Sphere { center: Vec3, radius: f32 },
+    Box(AABB),
+    /// Flat-capped cylinder around the segment `start..end`.
+    Cylinder { start: Vec3, end: Vec3, radius: f32 },
+    /// Cylinder with hemispherical caps around the segment `start..end`.
+    Capsule { start: Vec3, end: Vec3, radius: f32 },
+}
+
+/// Everything that is not inside the wrapped region. Used to express intersection as
+/// "clear everything outside the other operand".
+pub struct Complement<'a, R: Region>(pub &'a R);
+
+impl Shape {
+    /// Distance from `point` to the segment `start..end`, and the clamped segment parameter.
+    fn segment_distance(start: Vec3, end: Vec3, point: Vec3) -> (f32, f32) {
+        let axis = end - start;
+        let length_sq = axis.length_squared();
+        let t = if length_sq > 0.0 {
+            ((point - start).dot(axis) / length_sq).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        (point.distance(start + axis * t), t)
+    }
+
+    /// Conservative test: returns true only if no point of `cell` can be inside the shape.
+    fn excludes(&self, cell: &AABB) -> bool {
+        if !self.bounds().intersects(cell) {
+            return true;
+        }
+        match *self {
+            Shape::Sphere { center, radius } => {
+                // Exact: closest point of the box to the center.
+                let closest = center.clamp(cell.min, cell.max);
+                closest.distance_squared(center) >= radius * radius
+            }
+            Shape::Box(_) => false,
+            Shape::Cylinder { start, end, radius } | Shape::Capsule { start, end, radius } => {
+                // Bounding sphere of the cell against the distance to the axis segment.
+                let half_diagonal = cell.size().length() * 0.5;
+                let (distance, _) = Self::segment_distance(start, end, cell.center());
+                distance - half_diagonal >= radius
+            }
+        }
+    }
+
+    fn corners(cell: &AABB) -> [Vec3; 8] {
+        core::array::from_fn(|i| {
+            Vec3::new(
+                if (i & 1) != 0 { cell.max.x } else { cell.min.x },
+                if (i & 2) != 0 { cell.max.y } else { cell.min.y },
+                if (i & 4) != 0 { cell.max.z } else { cell.min.z },
+            )
+        })
+    }
+}
+
+impl Region for Shape {
+    fn bounds(&self) -> AABB {
+        match *self {
+            Shape::Sphere { center, radius } => {
+                AABB::new(center - Vec3::splat(radius), center + Vec3::splat(radius))
+            }
+            Shape::Box(aabb) => aabb,
+            Shape::Cylinder { start, end, radius } | Shape::Capsule { start, end, radius } => {
+                AABB::new(
+                    start.min(end) - Vec3::splat(radius),
+                    start.max(end) + Vec3::splat(radius),
+                )
+            }
+        }
+    }
+
+    fn classify(&self, cell: &AABB) -> Containment {
+        if let Shape::Box(aabb) = self {
+            return aabb.classify(cell);
+        }
+        if self.excludes(cell) {
+            return Containment::Outside;
+        }
+        // All shapes are convex, so a cell is inside if all of its corners are.
+        if Self::corners(cell).iter().all(|&corner| self.contains_point(corner)) {
+            Containment::Inside
+        } else {
+            Containment::Partial
+        }
+    }
+
+    fn contains_point(&self, point: Vec3) -> bool {
+        match *self {
+            Shape::Sphere { center, radius } => point.distance_squared(center) <= radius * radius,
+            Shape::Box(aabb) => aabb.contains_point(point),
+            Shape::Cylinder { start, end, radius } => {
+                let axis = end - start;
+                let length_sq = axis.length_squared();
+                let t = if length_sq > 0.0 { (point - start).dot(axis) / length_sq } else { 0.0 };
+                (0.0..=1.0).contains(&t) && point.distance(start + axis * t) <= radius
+            }
+            Shape::Capsule { start, end, radius } => {
+                Self::segment_distance(start, end, point).0 <= radius
+            }
+        }
+    }
+}
+
+impl<R: Region> Region for Complement<'_, R> {
+    fn bounds(&self) -> AABB {
+        AABB::new(Vec3::splat(f32::NEG_INFINITY), Vec3::splat(f32::INFINITY))
+    }
+
+    fn classify(&self, cell: &AABB) -> Containment {
+        match self.0.classify(cell) {
+            Containment::Outside => Containment::Inside,
+            Containment::Inside => Containment::Outside,
+            Containment::Partial => Containment::Partial,
+        }
+    }
+
+    fn contains_cell(&self, cell: &AABB) -> bool {
+        !self.0.contains_cell(cell)
+    }
+
+    fn contains_point(&self, point: Vec3) -> bool {
+        !self.0.contains_point(point)
+    }
+}
+
+/// Another octree used as an operand: its voxels are the inside of the region.
+impl<T: VoxelData> Region for SparseVoxelOctree<T> {
+    fn bounds(&self) -> AABB {
+        self.root_bounds()
+    }
+
+    fn classify(&self, cell: &AABB) -> Containment {
+        let root_bounds = self.root_bounds();
+        // Any part of the cell outside our root is empty space.
+        let (mut solid, mut empty) = (false, !root_bounds.contains_aabb(cell));
+        self.classify_recursive(NodePool::ROOT, root_bounds, cell, &mut solid, &mut empty);
+        match (solid, empty) {
+            (false, _) => Containment::Outside,
+            (true, false) => Containment::Inside,
+            (true, true) => Containment::Partial,
+        }
+    }
+
+    fn contains_point(&self, point: Vec3) -> bool {
+        self.contains(point.x, point.y, point.z) && self.get_voxel_at_world_coords(point).is_some()
+    }
+}
+
+impl<T: VoxelData> SparseVoxelOctree<T> {
+    /// Fills every cell inside `shape` with `voxel`, expanding the root to fit the shape.
+    /// Returns false, and changes nothing, if the root cannot grow to contain the shape.
+    pub fn union_shape(&mut self, shape: &Shape, voxel: T) -> bool {
+        if !self.ensure_contains(&shape.bounds()) {
+            return false;
+        }
+        self.set_region(shape, Some(voxel));
+        true
+    }
+
+    /// Carves `shape` out of the octree.
+    pub fn subtract_shape(&mut self, shape: &Shape) {
+        self.set_region(shape, None);
+    }
+
+    /// Keeps only the voxels inside `shape`.
+    pub fn intersect_shape(&mut self, shape: &Shape) {
+        self.set_region(&Complement(shape), None);
+    }
+
+    /// Copies every voxel of `other` into this octree, overwriting what was there.
+    /// Both octrees are assumed to share the same world origin.
+    /// Sends one `RegionChanged` event per leaf of `other`.
+    /// Returns false, and changes nothing, if the root cannot grow to contain `other`'s voxels.
+    pub fn union_octree(&mut self, other: &SparseVoxelOctree<T>) -> bool {
+        let Some(dirty_bounds) = other.iter().map(|entry| entry.bounds()).reduce(|a, b| {
+            AABB::new(a.min.min(b.min), a.max.max(b.max))
+        }) else {
+            return true;
+        };
+
+        if !self.ensure_contains(&dirty_bounds) {
+            return false;
+        }
+        self.record_edit(dirty_bounds);
+
+        let root_bounds = self.root_bounds();
+        for entry in other.iter() {
+            let bounds = entry.bounds();
+            let old = self.capture(bounds).voxels;
+            self.push_change(VoxelChange::Region { bounds, old, new: Some(*entry.voxel) });
+            self.set_region_recursive(NodePool::ROOT, root_bounds, 0, &bounds, Some(*entry.voxel));
+        }
+        self.check_edit("union_octree");
+        true
+    }
+
+    /// Removes every voxel that is occupied in `other`. The payload of `other` is ignored.
+    pub fn subtract_octree<U: VoxelData>(&mut self, other: &SparseVoxelOctree<U>) {
+        self.set_region(other, None);
+    }
+
+    /// Keeps only the voxels that are also occupied in `other`.
+    pub fn intersect_octree<U: VoxelData>(&mut self, other: &SparseVoxelOctree<U>) {
+        self.set_region(&Complement(other), None);
+    }
+
+    /// Records whether the part of `cell` overlapping node `id` contains solid and/or empty space.
+    fn classify_recursive(&self, id: NodeId, bounds: AABB, cell: &AABB, solid: &mut bool, empty: &mut bool) {
+        if (*solid && *empty) || !bounds.intersects(cell) {
+            return;
+        }
+        let node = self.nodes.get(id);
+        match node.children {
+            Some(first) => {
+                for i in 0..8 {
+                    let child_bounds = self.compute_child_bounds(&bounds, i);
+                    self.classify_recursive(first + i as NodeId, child_bounds, cell, solid, empty);
+                }
+            }
+            None if node.voxel.is_some() => *solid = true,
+            None => *empty = true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::systems::voxels::material::MaterialId;
+    use crate::systems::voxels::structure::Voxel;
+    use super::*;
+
+    fn voxel(material: u16) -> Voxel {
+        Voxel::new(MaterialId(material))
+    }
+
+    /// Octree with 1 m cells and a root spanning -8..8 m.
+    fn octree() -> SparseVoxelOctree {
+        SparseVoxelOctree::new(4, 16.0, false, false, false)
+    }
+
+    /// Every 1 m cell of the root, by the index of its min corner in metres.
+    fn all_cells() -> impl Iterator<Item = IVec3> {
+        (-8..8).flat_map(|x| (-8..8).flat_map(move |y| (-8..8).map(move |z| IVec3::new(x, y, z))))
+    }
+
+    fn cells(octree: &SparseVoxelOctree) -> HashMap<IVec3, Voxel> {
+        all_cells()
+            .filter_map(|cell| octree.get_voxel_at_world_coords(cell.as_vec3() + 0.5).map(|&voxel| (cell, voxel)))
+            .collect()
+    }
+
+    fn cube(min: f32, max: f32) -> AABB {
+        AABB::new(Vec3::splat(min), Vec3::splat(max))
+    }
+
+    #[test]
+    fn shapes_classify_cells() {
+        let sphere = Shape::Sphere { center: Vec3::ZERO, radius: 4.0 };
+        assert_eq!(sphere.classify(&cube(-1.0, 1.0)), Containment::Inside);
+        assert_eq!(sphere.classify(&AABB::new(Vec3::new(2.0, 0.0, 0.0), Vec3::new(4.0, 1.0, 1.0))), Containment::Partial);
+        assert_eq!(sphere.classify(&cube(3.0, 4.0)), Containment::Outside);
+
+        // Past the end of the segment, flat caps exclude what round caps still cover.
+        let (start, end) = (Vec3::new(0.0, -4.0, 0.0), Vec3::new(0.0, 4.0, 0.0));
+        let cylinder = Shape::Cylinder { start, end, radius: 2.0 };
+        let capsule = Shape::Capsule { start, end, radius: 2.0 };
+        let above = AABB::new(Vec3::new(0.0, 5.0, 0.0), Vec3::new(1.0, 6.0, 1.0));
+        for shape in [cylinder, capsule] {
+            assert_eq!(shape.classify(&cube(0.0, 1.0)), Containment::Inside);
+            assert_eq!(shape.classify(&above), Containment::Partial);
+            assert_eq!(shape.classify(&AABB::new(Vec3::new(3.0, 0.0, 0.0), Vec3::new(4.0, 1.0, 1.0))), Containment::Outside);
+            assert!(shape.contains_point(Vec3::new(0.0, 3.9, 1.9)));
+            assert!(!shape.contains_point(Vec3::new(1.5, 0.0, 1.5)));
+        }
+        assert!(!cylinder.contains_cell(&above));
+        assert!(capsule.contains_cell(&above));
+        assert!(!cylinder.contains_point(Vec3::new(0.0, -5.9, 0.0)));
+        assert!(capsule.contains_point(Vec3::new(0.0, -5.9, 0.0)));
+
+        let outside = Complement(&sphere);
+        assert_eq!(outside.classify(&cube(-1.0, 1.0)), Containment::Outside);
+        assert_eq!(outside.classify(&cube(3.0, 4.0)), Containment::Inside);
+        assert_eq!(outside.classify(&AABB::new(Vec3::new(2.0, 0.0, 0.0), Vec3::new(4.0, 1.0, 1.0))), Containment::Partial);
+        assert!(outside.contains_point(Vec3::splat(3.0)));
+        assert!(!outside.contains_cell(&cube(0.0, 1.0)));
+    }
+
+    #[test]
+    fn shapes_fill_cells_whose_center_is_inside() {
+        // Off-grid shapes, so most boundary cells are partial down to max depth.
+        let shapes = [
+            Shape::Sphere { center: Vec3::new(0.3, -0.2, 0.1), radius: 3.7 },
+            Shape::Cylinder { start: Vec3::new(-4.2, -1.0, 0.5), end: Vec3::new(3.9, 2.5, -1.0), radius: 1.6 },
+            Shape::Capsule { start: Vec3::new(-2.5, 0.2, -3.0), end: Vec3::new(2.0, -1.4, 3.3), radius: 2.1 },
+        ];
+        for shape in shapes {
+            let mut octree = octree();
+            assert!(octree.union_shape(&shape, voxel(1)));
+            let expected: Vec<IVec3> = all_cells().filter(|cell| shape.contains_point(cell.as_vec3() + 0.5)).collect();
+            let filled = cells(&octree);
+            assert!(!expected.is_empty());
+            assert_eq!(filled.len(), expected.len(), "{shape:?}");
+            assert!(expected.iter().all(|cell| filled.contains_key(cell)), "{shape:?}");
+            assert_eq!(octree.validate(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn box_shapes_match_box_regions() {
+        // The max face runs through the centers of the cells at x = 2.
+        let aabb = AABB::new(Vec3::ZERO, Vec3::new(2.5, 1.0, 1.0));
+        let mut shaped = octree();
+        shaped.union_shape(&Shape::Box(aabb), voxel(1));
+        let mut filled = octree();
+        filled.fill_aabb(&aabb, voxel(1));
+        assert_eq!(cells(&shaped).len(), 2);
+        assert_eq!(cells(&shaped), cells(&filled));
+
+        let mut octree = octree();
+        octree.fill_aabb(&cube(-4.0, 4.0), voxel(1));
+        assert_eq!(octree.count_in_region(&Shape::Box(aabb)), 2);
+        assert_eq!(octree.count_in_region(&aabb), 2);
+    }
+
+    #[test]
+    fn shape_operations() {
+        let mut octree = octree();
+        octree.union_shape(&Shape::Box(cube(-2.0, 2.0)), voxel(1));
+        assert_eq!(cells(&octree).len(), 64);
+
+        // The eight cells around the origin have their centers within one metre of it.
+        octree.subtract_shape(&Shape::Sphere { center: Vec3::ZERO, radius: 1.0 });
+        let remaining = cells(&octree);
+        assert_eq!(remaining.len(), 56);
+        assert!(!remaining.contains_key(&IVec3::new(-1, -1, -1)) && !remaining.contains_key(&IVec3::ZERO));
+        assert_eq!(remaining.get(&IVec3::new(-2, 0, 1)), Some(&voxel(1)));
+
+        octree.intersect_shape(&Shape::Box(cube(0.0, 2.0)));
+        let remaining = cells(&octree);
+        assert_eq!(remaining.len(), 7);
+        assert!(remaining.keys().all(|cell| cell.cmpge(IVec3::ZERO).all() && *cell != IVec3::ZERO));
+    }
+
+    /// A 4 m cube of material 1 around the origin, and a 4 m cube of material 2 overlapping
+    /// one of its octants.
+    fn operands() -> (SparseVoxelOctree, SparseVoxelOctree) {
+        let mut a = octree();
+        a.fill_aabb(&cube(-2.0, 2.0), voxel(1));
+        let mut b = octree();
+        b.fill_aabb(&cube(0.0, 4.0), voxel(2));
+        (a, b)
+    }
+
+    #[test]
+    fn octree_operations() {
+        let (mut union, b) = operands();
+        assert!(union.union_octree(&b));
+        let cells_of_union = cells(&union);
+        assert_eq!(cells_of_union.len(), 64 + 64 - 8);
+        assert_eq!(cells_of_union.get(&IVec3::new(1, 1, 1)), Some(&voxel(2)));
+        assert_eq!(cells_of_union.get(&IVec3::new(-1, 1, 1)), Some(&voxel(1)));
+        assert_eq!(cells_of_union.get(&IVec3::new(3, 3, 3)), Some(&voxel(2)));
+
+        let (mut difference, b) = operands();
+        difference.subtract_octree(&b);
+        let cells_of_difference = cells(&difference);
+        assert_eq!(cells_of_difference.len(), 56);
+        assert!(!cells_of_difference.contains_key(&IVec3::new(1, 0, 0)));
+        assert!(cells_of_difference.contains_key(&IVec3::new(-1, 0, 0)));
+
+        let (mut intersection, b) = operands();
+        intersection.intersect_octree(&b);
+        let cells_of_intersection = cells(&intersection);
+        assert_eq!(cells_of_intersection.len(), 8);
+        // The payload of the other operand is ignored.
+        assert!(cells_of_intersection.iter().all(|(cell, &voxel)| cell.cmpge(IVec3::ZERO).all() && voxel == self::voxel(1)));
+    }
+
+    #[test]
+    fn octree_operands_classify_cells() {
+        let mut other = octree();
+        other.fill_aabb(&cube(0.0, 2.0), voxel(1));
+        assert_eq!(other.classify(&cube(0.0, 2.0)), Containment::Inside);
+        assert_eq!(other.classify(&cube(1.0, 3.0)), Containment::Partial);
+        assert_eq!(other.classify(&cube(-2.0, 0.0)), Containment::Outside);
+        // Space outside the operand's root is empty.
+        assert_eq!(other.classify(&AABB::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(2.0, 2.0, 12.0))), Containment::Partial);
+    }
+}
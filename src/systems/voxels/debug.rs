@@ -7,22 +7,23 @@ use bevy::render::mesh::{Indices, PrimitiveTopology};
 use bevy::render::render_asset::RenderAssetUsages;
 use bevy_egui::egui::emath::Numeric;
 use crate::systems::camera_system::Selector;
-use crate::systems::voxels::structure::{OctreeNode, SparseVoxelOctree};
+use crate::systems::voxels::material::MaterialPalette;
+use crate::systems::voxels::structure::{NodeId, NodePool, SparseVoxelOctree};
 
 /// Visualize each node of the octree as a scaled cuboid, **center-based**.
-/// `octree_tf.translation` is the world-space center of the root bounding box.
+/// Nodes are computed in octree space and drawn through the octree's `GlobalTransform`.
 pub fn visualize_octree_system(
     mut gizmos: Gizmos,
-    octree_query: Query<(&SparseVoxelOctree, &Transform)>,
+    octree_query: Query<(&SparseVoxelOctree, &GlobalTransform)>,
+    palette: Res<MaterialPalette>,
 ) {
-    for (octree, octree_tf) in octree_query.iter() {
+    for (octree, octree_tf) in octree_query.iter().filter(|(octree, _)| octree.show_wireframe) {
         // The root node covers [-size/2..+size/2], so half_size is:
         let half_size = octree.size * 0.5;
 
         // Draw a translucent cuboid for the root
         gizmos.cuboid(
-            Transform::from_translation(octree_tf.translation)
-                .with_scale(Vec3::splat(octree.size)),
+            octree_tf.mul_transform(Transform::from_scale(Vec3::splat(octree.size))),
             Color::rgba(1.0, 1.0, 0.0, 0.15),
         );
 
@@ -30,8 +31,11 @@ pub fn visualize_octree_system(
         // Start from depth=0. The node at depth=0 has bounding side = octree.size.
         visualize_recursive_center(
             &mut gizmos,
-            &octree.root,
-            octree_tf.translation, // center of root in world
+            octree,
+            &palette,
+            octree_tf,
+            NodePool::ROOT,
+            Vec3::ZERO, // center of root in octree space
             octree.size,
             0,
             octree.max_depth,
@@ -45,7 +49,10 @@ pub fn visualize_octree_system(
 /// i=1 => (+x,-y,-z), i=2 => (-x,+y,-z), etc.
 fn visualize_recursive_center(
     gizmos: &mut Gizmos,
-    node: &OctreeNode,
+    octree: &SparseVoxelOctree,
+    palette: &MaterialPalette,
+    octree_tf: &GlobalTransform,
+    id: NodeId,
     parent_center: Vec3,
     parent_size: f32,
     depth: u32,
@@ -54,12 +61,13 @@ fn visualize_recursive_center(
     if depth >= max_depth {
         return;
     }
-    if let Some(children) = &node.children {
+    let node = octree.nodes.get(id);
+    if let Some(first) = node.children {
         // Each child is half the parent’s size
         let child_size = parent_size * 0.5;
         let half = child_size * 0.5;
 
-        for (i, child) in children.iter().enumerate() {
+        for i in 0..8 {
             // For i in [0..8], bits: x=1, y=2, z=4
             let offset_x = if (i & 1) != 0 { half } else { -half };
             let offset_y = if (i & 2) != 0 { half } else { -half };
@@ -69,14 +77,17 @@ fn visualize_recursive_center(
 
             // Draw the child bounding box
             gizmos.cuboid(
-                Transform::from_translation(child_center).with_scale(Vec3::splat(child_size)),
+                octree_tf.mul_transform(Transform::from_translation(child_center).with_scale(Vec3::splat(child_size))),
                 Color::rgba(0.5, 1.0, 0.5, 0.15), // greenish
             );
 
             // Recurse
             visualize_recursive_center(
                 gizmos,
-                child,
+                octree,
+                palette,
+                octree_tf,
+                first + i,
                 child_center,
                 child_size,
                 depth + 1,
@@ -84,20 +95,17 @@ fn visualize_recursive_center(
             );
         }
     } else {
-        // If node.is_leaf && node.voxel.is_some(), draw a smaller marker
-        if node.is_leaf {
-            if let Some(voxel) = node.voxel {
-                // We'll choose a size that's a fraction of the parent's size.
-                // For example, 25% of the parent bounding box dimension.
-                let leaf_size = parent_size * 0.25;
-
-                // Draw a small cuboid at the same center as the parent node.
-                gizmos.cuboid(
-                    Transform::from_translation(parent_center)
-                        .with_scale(Vec3::splat(leaf_size)),
-                    voxel.color,
-                );
-            }
+        // If the leaf holds a voxel, draw a smaller marker
+        if let Some(voxel) = node.voxel {
+            // We'll choose a size that's a fraction of the parent's size.
+            // For example, 25% of the parent bounding box dimension.
+            let leaf_size = parent_size * 0.25;
+
+            // Draw a small cuboid at the same center as the parent node.
+            gizmos.cuboid(
+                octree_tf.mul_transform(Transform::from_translation(parent_center).with_scale(Vec3::splat(leaf_size))),
+                palette.color(voxel.material),
+            );
         }
     }
 }
@@ -112,18 +120,17 @@ fn selction_visualizer(mut gizmos: Gizmos,
 }
 
 
+/// Draws the max-depth grid on the bottom face of each octree. The octree's `GlobalTransform`
+/// is relative to the floating origin, so the lines stay precise far from the world origin.
 #[allow(dead_code)]
 pub fn draw_grid(
     mut gizmos: Gizmos,
-    camera_query: Query<&Transform, With<Camera>>,
-    octree_query: Query<(&SparseVoxelOctree, &Transform)>,
+    octree_query: Query<(&SparseVoxelOctree, &GlobalTransform)>,
 ) {
-    let camera_tf = camera_query.single();
-    let camera_pos = camera_tf.translation;
-
-    for (octree, octree_tf) in octree_query.iter() {
+    for (octree, octree_tf) in octree_query.iter().filter(|(octree, _)| octree.show_world_grid) {
         let half_size = octree.size * 0.5;
-        let root_center = octree_tf.translation;
+        // The grid is laid out in octree space and moved into the world by the octree's transform.
+        let root_center = Vec3::ZERO;
 
         // Voxel spacing at max depth
         let spacing = octree.get_spacing_at_depth(octree.max_depth);
@@ -145,9 +152,8 @@ pub fn draw_grid(
             let p1 = Vec3::new(x, min_corner.y, z1);
             let p2 = Vec3::new(x, min_corner.y, z2);
 
-            // offset by -camera_pos for stable Gizmos in large coords
-            let p1_f32 = p1 - camera_pos;
-            let p2_f32 = p2 - camera_pos;
+            let p1_f32 = octree_tf.transform_point(p1);
+            let p2_f32 = octree_tf.transform_point(p2);
             gizmos.line(p1_f32, p2_f32, Color::WHITE);
 
             // 2) line along X
@@ -155,8 +161,8 @@ pub fn draw_grid(
             let x1 = min_corner.x;
             let x2 = min_corner.x + (grid_count as f32 * spacing);
 
-            let p3 = Vec3::new(x1, min_corner.y, z) - camera_pos;
-            let p4 = Vec3::new(x2, min_corner.y, z) - camera_pos;
+            let p3 = octree_tf.transform_point(Vec3::new(x1, min_corner.y, z));
+            let p4 = octree_tf.transform_point(Vec3::new(x2, min_corner.y, z));
             gizmos.line(p3, p4, Color::WHITE);
         }
     }
@@ -0,0 +1,204 @@
+use std::sync::Arc;
+use bevy::prelude::*;
+use crate::systems::voxels::structure::{SparseVoxelOctree, Voxel, VoxelData, VoxelKey, AABB};
+
+/// An edit waiting in `SparseVoxelOctree::changes` until `emit_voxel_events` turns it into an event.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VoxelChange<T: VoxelData = Voxel> {
+    Inserted { key: VoxelKey, bounds: AABB, old: Option<T>, new: T },
+    Removed { key: VoxelKey, bounds: AABB, old: T },
+    Region { bounds: AABB, old: Arc<[(AABB, T)]>, new: Option<T> },
+}
+
+/// A voxel was written to one cell. `old` is what the cell held before, if anything.
+/// `key` refers to the root at the time of the edit; `bounds` is the cell in world space,
+/// which stays valid when the root is resized later in the frame.
+#[derive(Event, Debug, Clone, Copy, PartialEq)]
+pub struct VoxelInserted<T: VoxelData = Voxel> {
+    pub entity: Entity,
+    pub key: VoxelKey,
+    pub bounds: AABB,
+    pub old: Option<T>,
+    pub new: T,
+}
+
+/// The voxel `old` was removed from one cell. See `VoxelInserted` for `key` and `bounds`.
+#[derive(Event, Debug, Clone, Copy, PartialEq)]
+pub struct VoxelRemoved<T: VoxelData = Voxel> {
+    pub entity: Entity,
+    pub key: VoxelKey,
+    pub bounds: AABB,
+    pub old: T,
+}
+
+/// A region edit set the cells it covers inside `bounds` to `new` (`None` means cleared).
+/// Cells of `bounds` outside the edited shape keep their value, so read the octree for details.
+#[derive(Event, Debug, Clone, PartialEq)]
+pub struct RegionChanged<T: VoxelData = Voxel> {
+    pub entity: Entity,
+    pub bounds: AABB,
+    /// The filled leaves overlapping `bounds` (widened to whole cells) before the edit, as
+    /// world-space cells with their voxel. Merged leaves are listed whole and may reach outside
+    /// `bounds`. Shared with the undo record of the edit.
+    pub old: Arc<[(AABB, T)]>,
+    pub new: Option<T>,
+}
+
+impl<T: VoxelData> VoxelChange<T> {
+    /// World-space box of the cells the change touched.
+    pub fn bounds(&self) -> AABB {
+        match self {
+            VoxelChange::Inserted { bounds, .. }
+            | VoxelChange::Removed { bounds, .. }
+            | VoxelChange::Region { bounds, .. } => *bounds,
+        }
+    }
+}
+
+impl<T: VoxelData> SparseVoxelOctree<T> {
+    /// Queues a change notification. Drained every frame by `emit_voxel_events`.
+    pub(crate) fn push_change(&mut self, change: VoxelChange<T>) {
+        self.changes.push(change);
+    }
+
+    /// Takes the queued changes, for use outside the Bevy app.
+    pub fn drain_changes(&mut self) -> Vec<VoxelChange<T>> {
+        std::mem::take(&mut self.changes)
+    }
+}
+
+/// Sends the queued changes of every octree as events, tagged with the octree's entity.
+/// Each consumer reads them with its own `EventReader`, so meshing, physics or networking
+/// can all react to the same edits.
+pub fn emit_voxel_events<T: VoxelData>(
+    mut octrees: Query<(Entity, &mut SparseVoxelOctree<T>)>,
+    mut inserted: EventWriter<VoxelInserted<T>>,
+    mut removed: EventWriter<VoxelRemoved<T>>,
+    mut region_changed: EventWriter<RegionChanged<T>>,
+) {
+    for (entity, mut octree) in octrees.iter_mut() {
+        // Only take mutable access when there is something to send.
+        if octree.changes.is_empty() {
+            continue;
+        }
+        for change in octree.drain_changes() {
+            match change {
+                VoxelChange::Inserted { key, bounds, old, new } => {
+                    inserted.send(VoxelInserted { entity, key, bounds, old, new });
+                }
+                VoxelChange::Removed { key, bounds, old } => {
+                    removed.send(VoxelRemoved { entity, key, bounds, old });
+                }
+                VoxelChange::Region { bounds, old, new } => {
+                    region_changed.send(RegionChanged { entity, bounds, old, new });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::event::Events;
+    use crate::systems::voxels::material::MaterialId;
+    use super::*;
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.add_event::<VoxelInserted>();
+        app.add_event::<VoxelRemoved>();
+        app.add_event::<RegionChanged>();
+        app.add_systems(Update, emit_voxel_events::<Voxel>);
+        app
+    }
+
+    fn read<E: Event + Clone>(app: &App) -> Vec<E> {
+        let events = app.world().resource::<Events<E>>();
+        events.get_cursor().read(events).cloned().collect()
+    }
+
+    #[test]
+    fn edits_become_events() {
+        let mut app = app();
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        let position = Vec3::new(0.5, 0.5, 0.5);
+        octree.insert(position, Voxel::new(MaterialId(1)));
+        octree.insert(position, Voxel::new(MaterialId(2)));
+        octree.remove(position);
+        octree.remove(position);
+        octree.clear_aabb(&AABB::new(Vec3::ZERO, Vec3::splat(2.0)));
+        let entity = app.world_mut().spawn(octree).id();
+
+        app.update();
+        let key = app.world().get::<SparseVoxelOctree>(entity).unwrap().key_at(position, 4);
+        let bounds = AABB::new(Vec3::ZERO, Vec3::ONE);
+        assert_eq!(read::<VoxelInserted>(&app), vec![
+            VoxelInserted { entity, key, bounds, old: None, new: Voxel::new(MaterialId(1)) },
+            VoxelInserted { entity, key, bounds, old: Some(Voxel::new(MaterialId(1))), new: Voxel::new(MaterialId(2)) },
+        ]);
+        // Removing an empty cell is not an edit.
+        assert_eq!(read::<VoxelRemoved>(&app), vec![VoxelRemoved { entity, key, bounds, old: Voxel::new(MaterialId(2)) }]);
+        assert_eq!(read::<RegionChanged>(&app), vec![RegionChanged {
+            entity,
+            bounds: AABB::new(Vec3::ZERO, Vec3::splat(2.0)),
+            old: Arc::default(),
+            new: None,
+        }]);
+        assert!(app.world().get::<SparseVoxelOctree>(entity).unwrap().changes.is_empty());
+    }
+
+    #[test]
+    fn events_name_their_octree() {
+        let mut app = app();
+        let mut first = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        first.insert(Vec3::splat(0.5), Voxel::new(MaterialId(1)));
+        let mut second = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        second.fill_aabb(&AABB::new(Vec3::ZERO, Vec3::splat(4.0)), Voxel::new(MaterialId(2)));
+        let first = app.world_mut().spawn(first).id();
+        let second = app.world_mut().spawn(second).id();
+
+        app.update();
+        assert_eq!(read::<VoxelInserted>(&app).iter().map(|event| event.entity).collect::<Vec<_>>(), vec![first]);
+        assert_eq!(read::<RegionChanged>(&app).iter().map(|event| event.entity).collect::<Vec<_>>(), vec![second]);
+
+        // Nothing is sent twice.
+        app.update();
+        app.update();
+        assert!(read::<VoxelInserted>(&app).is_empty());
+        assert!(read::<RegionChanged>(&app).is_empty());
+    }
+
+    #[test]
+    fn region_events_carry_the_replaced_voxels() {
+        let mut app = app();
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        octree.fill_aabb(&AABB::new(Vec3::ZERO, Vec3::splat(2.0)), Voxel::new(MaterialId(1)));
+        octree.insert(Vec3::new(2.5, 0.5, 0.5), Voxel::new(MaterialId(3)));
+        octree.drain_changes();
+        octree.fill_aabb(&AABB::new(Vec3::new(1.0, 0.0, 0.0), Vec3::new(3.0, 1.0, 1.0)), Voxel::new(MaterialId(2)));
+        let mut other = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        other.insert(Vec3::new(2.5, 0.5, 0.5), Voxel::new(MaterialId(4)));
+        octree.union_octree(&other);
+        app.world_mut().spawn(octree);
+
+        app.update();
+        let events = read::<RegionChanged>(&app);
+        assert_eq!(events.len(), 2);
+        // The merged 2x2x2 leaf is reported whole.
+        let old = &events[0].old;
+        assert_eq!(old.len(), 2);
+        assert!(old.contains(&(AABB::new(Vec3::ZERO, Vec3::splat(2.0)), Voxel::new(MaterialId(1)))));
+        assert!(old.contains(&(AABB::new(Vec3::new(2.0, 0.0, 0.0), Vec3::new(3.0, 1.0, 1.0)), Voxel::new(MaterialId(3)))));
+        assert_eq!(events[0].new, Some(Voxel::new(MaterialId(2))));
+        assert_eq!(*events[1].old, [(AABB::new(Vec3::new(2.0, 0.0, 0.0), Vec3::new(3.0, 1.0, 1.0)), Voxel::new(MaterialId(2)))]);
+
+        // Without history the replaced voxels are still reported.
+        let mut octree = app.world_mut().query::<&mut SparseVoxelOctree>().single_mut(app.world_mut());
+        octree.history.enabled = false;
+        octree.clear_aabb(&AABB::new(Vec3::new(2.0, 0.0, 0.0), Vec3::new(3.0, 1.0, 1.0)));
+        app.update();
+        let events = read::<RegionChanged>(&app);
+        assert_eq!(*events.last().unwrap().old, [(AABB::new(Vec3::new(2.0, 0.0, 0.0), Vec3::new(3.0, 1.0, 1.0)), Voxel::new(MaterialId(4)))]);
+    }
+}
+
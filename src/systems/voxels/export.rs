@@ -0,0 +1,395 @@
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, VertexAttributeValues};
+use crate::systems::voxels::material::{MaterialId, MaterialPalette};
+use crate::systems::voxels::rendering::generate_meshes;
+use crate::systems::voxels::structure::{SparseVoxelOctree, AABB};
+
+// glTF binary container (all numbers little-endian).
+const GLB_MAGIC: u32 = 0x4654_6C67;
+const GLB_VERSION: u32 = 2;
+const GLB_CHUNK_JSON: u32 = 0x4E4F_534A;
+const GLB_CHUNK_BIN: u32 = 0x004E_4942;
+
+// glTF enums.
+const COMPONENT_FLOAT: u32 = 5126;
+const COMPONENT_UNSIGNED_INT: u32 = 5125;
+const TARGET_ARRAY_BUFFER: u32 = 34962;
+const TARGET_ELEMENT_ARRAY_BUFFER: u32 = 34963;
+
+/// Mesh of one material, as produced by `generate_meshes`.
+struct Surface {
+    material: MaterialId,
+    positions: Vec<[f32; 3]>,
+    normals: Vec<[f32; 3]>,
+    uvs: Vec<[f32; 2]>,
+    indices: Vec<u32>,
+}
+
+impl Surface {
+    fn triangles(&self) -> impl Iterator<Item = [u32; 3]> + '_ {
+        self.indices.chunks_exact(3).map(|corners| [corners[0], corners[1], corners[2]])
+    }
+}
+
+impl SparseVoxelOctree {
+    /// Writes the voxel surface, or the closed surface of `region`, as a Wavefront OBJ file.
+    /// Each material becomes a group, and vertices carry its sRGB color as `v x y z r g b`.
+    /// Wrap files in a `BufWriter`, the file is written line by line.
+    pub fn export_obj(&self, palette: &MaterialPalette, region: Option<&AABB>, writer: &mut impl Write) -> io::Result<()> {
+        writeln!(writer, "# voxel-engine export")?;
+        let mut first_vertex = 1;
+        for surface in self.surfaces(region) {
+            let material = palette.resolve(surface.material);
+            let color = material.color.to_srgba();
+            writeln!(writer, "g {}", material.name.replace(char::is_whitespace, "_"))?;
+            for position in &surface.positions {
+                let [x, y, z] = position;
+                writeln!(writer, "v {x} {y} {z} {} {} {}", color.red, color.green, color.blue)?;
+            }
+            for [u, v] in &surface.uvs {
+                writeln!(writer, "vt {u} {}", 1.0 - v)?;
+            }
+            for [x, y, z] in &surface.normals {
+                writeln!(writer, "vn {x} {y} {z}")?;
+            }
+            for triangle in surface.triangles() {
+                let [a, b, c] = triangle.map(|index| index + first_vertex);
+                writeln!(writer, "f {a}/{a}/{a} {b}/{b}/{b} {c}/{c}/{c}")?;
+            }
+            first_vertex += surface.positions.len() as u32;
+        }
+        Ok(())
+    }
+
+    /// Writes the voxel surface, or the closed surface of `region`, as a binary STL file.
+    /// STL has no materials, so all materials end up in one solid.
+    pub fn export_stl(&self, region: Option<&AABB>, writer: &mut impl Write) -> io::Result<()> {
+        let surfaces = self.surfaces(region);
+        let triangle_count: usize = surfaces.iter().map(|surface| surface.indices.len() / 3).sum();
+        let triangle_count = u32::try_from(triangle_count)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many triangles for STL"))?;
+
+        let mut header = [0; 80];
+        let title = b"voxel-engine export";
+        header[..title.len()].copy_from_slice(title);
+        writer.write_all(&header)?;
+        writer.write_all(&triangle_count.to_le_bytes())?;
+
+        let mut record = Vec::with_capacity(50);
+        for surface in &surfaces {
+            for triangle in surface.triangles() {
+                // Faces are flat, so any corner normal is the face normal.
+                record.clear();
+                for value in surface.normals[triangle[0] as usize] {
+                    record.extend_from_slice(&value.to_le_bytes());
+                }
+                for index in triangle {
+                    for value in surface.positions[index as usize] {
+                        record.extend_from_slice(&value.to_le_bytes());
+                    }
+                }
+                // Attribute byte count, unused.
+                record.extend_from_slice(&0u16.to_le_bytes());
+                writer.write_all(&record)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the voxel surface, or the closed surface of `region`, as a binary glTF (`.glb`) file.
+    /// The scene has one node with one mesh; every material is a primitive with its own PBR material.
+    pub fn export_gltf(&self, palette: &MaterialPalette, region: Option<&AABB>, writer: &mut impl Write) -> io::Result<()> {
+        let surfaces = self.surfaces(region);
+
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut buffer_views = Vec::new();
+        let mut accessors = Vec::new();
+        let mut primitives = Vec::new();
+        let mut materials = Vec::new();
+
+        // Appends a buffer view and an accessor over it, returning the accessor index.
+        let mut add_accessor = |bytes: &[u8], target: u32, component: u32, count: usize, kind: &str, bounds: &str| {
+            buffer_views.push(format!(
+                r#"{{"buffer":0,"byteOffset":{},"byteLength":{},"target":{target}}}"#,
+                buffer.len(),
+                bytes.len()
+            ));
+            buffer.extend_from_slice(bytes);
+            accessors.push(format!(
+                r#"{{"bufferView":{},"componentType":{component},"count":{count},"type":"{kind}"{bounds}}}"#,
+                buffer_views.len() - 1
+            ));
+            accessors.len() - 1
+        };
+
+        for surface in &surfaces {
+            let (min, max) = surface.positions.iter().fold(
+                (Vec3::INFINITY, Vec3::NEG_INFINITY),
+                |(min, max), &position| (min.min(position.into()), max.max(position.into())),
+            );
+            let bounds = format!(
+                r#","min":[{},{},{}],"max":[{},{},{}]"#,
+                min.x, min.y, min.z, max.x, max.y, max.z
+            );
+            let count = surface.positions.len();
+            let position = add_accessor(
+                bytemuck::cast_slice(&surface.positions),
+                TARGET_ARRAY_BUFFER,
+                COMPONENT_FLOAT,
+                count,
+                "VEC3",
+                &bounds,
+            );
+            let normal = add_accessor(bytemuck::cast_slice(&surface.normals), TARGET_ARRAY_BUFFER, COMPONENT_FLOAT, count, "VEC3", "");
+            let uv = add_accessor(bytemuck::cast_slice(&surface.uvs), TARGET_ARRAY_BUFFER, COMPONENT_FLOAT, count, "VEC2", "");
+            let indices = add_accessor(
+                bytemuck::cast_slice(&surface.indices),
+                TARGET_ELEMENT_ARRAY_BUFFER,
+                COMPONENT_UNSIGNED_INT,
+                surface.indices.len(),
+                "SCALAR",
+                "",
+            );
+            primitives.push(format!(
+                r#"{{"attributes":{{"POSITION":{position},"NORMAL":{normal},"TEXCOORD_0":{uv}}},"indices":{indices},"material":{}}}"#,
+                materials.len()
+            ));
+
+            let material = palette.resolve(surface.material);
+            let color = material.color.to_linear();
+            let emissive = material.emissive.to_linear();
+            let alpha_mode = if material.transparency > 0.0 { "BLEND" } else { "OPAQUE" };
+            materials.push(format!(
+                r#"{{"name":"{}","pbrMetallicRoughness":{{"baseColorFactor":[{},{},{},{}],"metallicFactor":{},"roughnessFactor":{}}},"emissiveFactor":[{},{},{}],"alphaMode":"{alpha_mode}"}}"#,
+                escape_json(&material.name),
+                color.red,
+                color.green,
+                color.blue,
+                1.0 - material.transparency,
+                material.metallic,
+                material.roughness,
+                emissive.red,
+                emissive.green,
+                emissive.blue,
+            ));
+        }
+
+        // Empty arrays are not allowed, so an empty export is a scene without nodes.
+        let mut json = String::from(r#"{"asset":{"version":"2.0","generator":"voxel-engine"},"scene":0"#);
+        if primitives.is_empty() {
+            json.push_str(r#","scenes":[{}]}"#);
+        } else {
+            let _ = write!(
+                json,
+                r#","scenes":[{{"nodes":[0]}}],"nodes":[{{"mesh":0}}],"meshes":[{{"primitives":[{}]}}],"materials":[{}],"accessors":[{}],"bufferViews":[{}],"buffers":[{{"byteLength":{}}}]}}"#,
+                primitives.join(","),
+                materials.join(","),
+                accessors.join(","),
+                buffer_views.join(","),
+                buffer.len()
+            );
+        }
+
+        // Chunks are padded to four bytes: JSON with spaces, binary data with zeros.
+        let mut json = json.into_bytes();
+        json.resize(json.len().next_multiple_of(4), b' ');
+        buffer.resize(buffer.len().next_multiple_of(4), 0);
+        let chunk_len = |bytes: &[u8]| bytes.len() as u32;
+        let mut total = 12 + 8 + chunk_len(&json);
+        if !buffer.is_empty() {
+            total += 8 + chunk_len(&buffer);
+        }
+
+        writer.write_all(&GLB_MAGIC.to_le_bytes())?;
+        writer.write_all(&GLB_VERSION.to_le_bytes())?;
+        writer.write_all(&total.to_le_bytes())?;
+        writer.write_all(&chunk_len(&json).to_le_bytes())?;
+        writer.write_all(&GLB_CHUNK_JSON.to_le_bytes())?;
+        writer.write_all(&json)?;
+        if !buffer.is_empty() {
+            writer.write_all(&chunk_len(&buffer).to_le_bytes())?;
+            writer.write_all(&GLB_CHUNK_BIN.to_le_bytes())?;
+            writer.write_all(&buffer)?;
+        }
+        Ok(())
+    }
+
+    /// Meshes the octree with the renderer's mesher, sorted by material so exports are stable.
+    fn surfaces(&self, region: Option<&AABB>) -> Vec<Surface> {
+        let mut surfaces: Vec<Surface> = generate_meshes(self, region)
+            .into_iter()
+            .map(|(material, mesh)| {
+                let float3 = |attribute| match mesh.attribute(attribute) {
+                    Some(VertexAttributeValues::Float32x3(values)) => values.clone(),
+                    _ => Vec::new(),
+                };
+                Surface {
+                    material,
+                    positions: float3(Mesh::ATTRIBUTE_POSITION),
+                    normals: float3(Mesh::ATTRIBUTE_NORMAL),
+                    uvs: match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+                        Some(VertexAttributeValues::Float32x2(values)) => values.clone(),
+                        _ => Vec::new(),
+                    },
+                    indices: match mesh.indices() {
+                        Some(Indices::U32(indices)) => indices.clone(),
+                        Some(indices) => indices.iter().map(|index| index as u32).collect(),
+                        None => Vec::new(),
+                    },
+                }
+            })
+            .collect();
+        surfaces.sort_unstable_by_key(|surface| surface.material);
+        surfaces
+    }
+}
+
+fn escape_json(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            character if character.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", character as u32);
+            }
+            character => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::systems::voxels::material::VoxelMaterial;
+    use crate::systems::voxels::structure::Voxel;
+    use super::*;
+
+    fn two_voxels() -> (SparseVoxelOctree, MaterialPalette) {
+        let mut palette = MaterialPalette::default();
+        let red = palette.add(VoxelMaterial::new("red brick", Color::srgb(1.0, 0.0, 0.0))).unwrap();
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        octree.insert(Vec3::new(0.5, 0.5, 0.5), Voxel::new(red));
+        octree.insert(Vec3::new(1.5, 0.5, 0.5), Voxel::new(MaterialId(0)));
+        (octree, palette)
+    }
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn meshes_exposed_faces() {
+        let (octree, _) = two_voxels();
+        let surfaces = octree.surfaces(None);
+        assert_eq!(surfaces.len(), 2);
+        // Each voxel hides the face towards the other.
+        for surface in &surfaces {
+            assert_eq!(surface.positions.len(), 5 * 4);
+            assert_eq!(surface.indices.len(), 5 * 6);
+        }
+    }
+
+    #[test]
+    fn region_exports_are_closed() {
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        octree.fill_aabb(&AABB::new(Vec3::ZERO, Vec3::splat(4.0)), Voxel::new(MaterialId(1)));
+        let region = AABB::new(Vec3::ZERO, Vec3::splat(2.0));
+
+        // Only the 2x2x2 corner: four faces on each of its six sides.
+        let surfaces = octree.surfaces(Some(&region));
+        let faces: usize = surfaces.iter().map(|surface| surface.indices.len() / 6).sum();
+        assert_eq!(faces, 6 * 4);
+    }
+
+    #[test]
+    fn region_exports_only_visit_the_region() {
+        // One merged leaf of 2^20 cells per axis.
+        let mut octree = SparseVoxelOctree::new(24, 16_777_216.0, false, false, false);
+        octree.fill_aabb(&AABB::new(Vec3::ZERO, Vec3::splat(1_048_576.0)), Voxel::new(MaterialId(1)));
+        assert_eq!(octree.iter().count(), 1);
+
+        let region = AABB::new(Vec3::splat(1.0), Vec3::splat(3.0));
+        let surfaces = octree.surfaces(Some(&region));
+        let faces: usize = surfaces.iter().map(|surface| surface.indices.len() / 6).sum();
+        assert_eq!(faces, 6 * 4);
+    }
+
+    #[test]
+    fn writes_obj() {
+        let (octree, palette) = two_voxels();
+        let mut bytes = Vec::new();
+        octree.export_obj(&palette, None, &mut bytes).unwrap();
+        let text = String::from_utf8(bytes).unwrap();
+
+        let count = |prefix: &str| text.lines().filter(|line| line.starts_with(prefix)).count();
+        assert_eq!(count("v "), 40);
+        assert_eq!(count("vt "), 40);
+        assert_eq!(count("vn "), 40);
+        assert_eq!(count("f "), 20);
+        assert!(text.contains("g red_brick"));
+        assert!(text.lines().any(|line| line.starts_with("v ") && line.ends_with(" 1 0 0")));
+        // Indices of the second group continue after the first one's vertices.
+        assert!(text.lines().filter(|line| line.starts_with("f ")).any(|line| line.contains(" 40/40/40")));
+
+        // The voxelizer reads it back as the same closed box.
+        let mesh = crate::systems::voxels::voxelize::TriangleMesh::parse_obj(&text).unwrap();
+        assert_eq!(mesh.triangles.len(), 20);
+        assert_eq!(mesh.bounds(), Some(AABB::new(Vec3::ZERO, Vec3::new(2.0, 1.0, 1.0))));
+    }
+
+    #[test]
+    fn writes_binary_stl() {
+        let (octree, _) = two_voxels();
+        let mut bytes = Vec::new();
+        octree.export_stl(None, &mut bytes).unwrap();
+        assert_eq!(read_u32(&bytes, 80), 20);
+        assert_eq!(bytes.len(), 84 + 20 * 50);
+
+        // Every normal points away from the solid.
+        for record in bytes[84..].chunks_exact(50) {
+            let values: Vec<f32> = record[..48].chunks_exact(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect();
+            let normal = Vec3::new(values[0], values[1], values[2]);
+            let center = (Vec3::from_slice(&values[3..6]) + Vec3::from_slice(&values[6..9]) + Vec3::from_slice(&values[9..12])) / 3.0;
+            assert!(normal.dot(center - Vec3::new(1.0, 0.5, 0.5)) > 0.0);
+        }
+    }
+
+    #[test]
+    fn writes_glb() {
+        let (octree, palette) = two_voxels();
+        let mut bytes = Vec::new();
+        octree.export_gltf(&palette, None, &mut bytes).unwrap();
+
+        assert_eq!(read_u32(&bytes, 0), GLB_MAGIC);
+        assert_eq!(read_u32(&bytes, 4), GLB_VERSION);
+        assert_eq!(read_u32(&bytes, 8) as usize, bytes.len());
+        let json_len = read_u32(&bytes, 12) as usize;
+        assert_eq!(read_u32(&bytes, 16), GLB_CHUNK_JSON);
+        let json = std::str::from_utf8(&bytes[20..20 + json_len]).unwrap();
+        let bin_len = read_u32(&bytes, 20 + json_len) as usize;
+        assert_eq!(read_u32(&bytes, 24 + json_len), GLB_CHUNK_BIN);
+        assert_eq!(28 + json_len + bin_len, bytes.len());
+        assert_eq!(json_len % 4, 0);
+
+        // Two primitives of 20 vertices: positions, normals, uvs and 30 indices each.
+        let per_surface = 20 * (12 + 12 + 8) + 30 * 4;
+        assert!(json.contains(&format!(r#""buffers":[{{"byteLength":{}}}]"#, 2 * per_surface)));
+        assert_eq!(json.matches(r#""POSITION""#).count(), 2);
+        assert!(json.contains(r#""name":"red brick""#));
+        assert!(json.contains(r#""min":[0,0,0],"max":[1,1,1]"#));
+
+        let empty = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        let mut bytes = Vec::new();
+        empty.export_gltf(&palette, None, &mut bytes).unwrap();
+        assert_eq!(read_u32(&bytes, 8) as usize, bytes.len());
+        assert!(!String::from_utf8_lossy(&bytes).contains("buffers"));
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(escape_json("a \"b\" \\ c\n"), "a \\\"b\\\" \\\\ c\\u000a");
+    }
+}
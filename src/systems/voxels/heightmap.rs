@@ -0,0 +1,253 @@
+use std::io;
+use std::path::Path;
+use bevy::image::{CompressedImageFormats, ImageSampler, ImageType};
+use bevy::prelude::*;
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::render::render_resource::TextureFormat;
+use crate::systems::voxels::material::MaterialId;
+use crate::systems::voxels::structure::{SparseVoxelOctree, Voxel, AABB};
+
+/// Grid of normalized heights in `[0, 1]`, one per pixel, row by row.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heightmap {
+    pub width: u32,
+    pub depth: u32,
+    pub heights: Vec<f32>,
+}
+
+/// How a heightmap is turned into voxel columns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeightmapSettings {
+    /// World position of the bottom corner of pixel (0, 0).
+    pub offset: Vec3,
+    /// Column height added for a white pixel.
+    pub vertical_scale: f32,
+    /// Column height of a black pixel, so that every pixel gets ground.
+    pub base_height: f32,
+    pub material: MaterialId,
+    /// Optional `(height, material)` bands, sorted by height. Voxels at or above a band's height
+    /// (measured from `offset.y`) use its material; voxels below the first band use `material`.
+    pub ramp: Vec<(f32, MaterialId)>,
+}
+
+impl Heightmap {
+    /// Loads a PNG or JPEG heightmap; the format is taken from the file extension.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or_default();
+        Self::from_bytes(&std::fs::read(path)?, extension)
+    }
+
+    /// Decodes an encoded image, e.g. `from_bytes(&bytes, "png")`.
+    pub fn from_bytes(bytes: &[u8], extension: &str) -> io::Result<Self> {
+        let image = Image::from_buffer(
+            bytes,
+            ImageType::Extension(extension),
+            CompressedImageFormats::NONE,
+            false,
+            ImageSampler::Default,
+            RenderAssetUsages::MAIN_WORLD,
+        )
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        Self::from_image(&image)
+    }
+
+    /// Reads the heights of a decoded image. Grayscale images use their only channel,
+    /// color images the average of red, green and blue.
+    pub fn from_image(image: &Image) -> io::Result<Self> {
+        let single_channel = matches!(
+            image.texture_descriptor.format,
+            TextureFormat::R8Unorm
+                | TextureFormat::R16Unorm
+                | TextureFormat::R32Float
+                | TextureFormat::Rg8Unorm
+                | TextureFormat::Rg16Unorm
+        );
+        let (width, depth) = (image.width(), image.height());
+        let mut heights = Vec::with_capacity((width * depth) as usize);
+        for z in 0..depth {
+            for x in 0..width {
+                let color = image
+                    .get_color_at(x, z)
+                    .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?
+                    .to_linear();
+                let height = if single_channel {
+                    color.red
+                } else {
+                    (color.red + color.green + color.blue) / 3.0
+                };
+                heights.push(height.clamp(0.0, 1.0));
+            }
+        }
+        Ok(Self { width, depth, heights })
+    }
+
+    pub fn height(&self, x: u32, z: u32) -> f32 {
+        self.heights[(z * self.width + x) as usize]
+    }
+}
+
+impl HeightmapSettings {
+    /// Columns of `material`, one voxel high for black and `vertical_scale` higher for white.
+    pub fn new(material: MaterialId, vertical_scale: f32) -> Self {
+        Self {
+            offset: Vec3::ZERO,
+            vertical_scale,
+            base_height: 0.0,
+            material,
+            ramp: Vec::new(),
+        }
+    }
+
+    pub fn with_offset(mut self, offset: Vec3) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn with_base_height(mut self, base_height: f32) -> Self {
+        self.base_height = base_height;
+        self
+    }
+
+    pub fn with_ramp(mut self, mut ramp: Vec<(f32, MaterialId)>) -> Self {
+        ramp.sort_by(|a, b| a.0.total_cmp(&b.0));
+        self.ramp = ramp;
+        self
+    }
+}
+
+impl SparseVoxelOctree {
+    /// Builds terrain from a heightmap: one column per pixel, one voxel at max depth wide.
+    /// Column heights are `base_height + height * vertical_scale`, rounded to whole voxels and
+    /// at least one voxel. Each column (or each ramp band of it) is filled with a single `fill_aabb`.
+    /// `settings.offset` should lie on the voxel grid. Returns false, and changes nothing, if the
+    /// root cannot grow to contain the terrain.
+    pub fn import_heightmap(&mut self, heightmap: &Heightmap, settings: &HeightmapSettings) -> bool {
+        let step = self.get_spacing_at_depth(self.max_depth);
+        if heightmap.heights.is_empty() {
+            return true;
+        }
+
+        // Grow the root once up front instead of column by column.
+        let highest = heightmap.heights.iter().copied().fold(0.0, f32::max);
+        let top = (settings.base_height + highest * settings.vertical_scale).max(step);
+        let extent = Vec3::new(heightmap.width as f32 * step, top + step, heightmap.depth as f32 * step);
+        let bounds = AABB::new(settings.offset, settings.offset + extent);
+        // Expanding deepens the tree, so the voxel size stays `step`.
+        if !self.ensure_contains(&bounds) {
+            return false;
+        }
+
+        // One undo step for the whole terrain.
+        self.begin_group();
+        for z in 0..heightmap.depth {
+            for x in 0..heightmap.width {
+                let height = settings.base_height + heightmap.height(x, z) * settings.vertical_scale;
+                let cells = (height / step).round().max(1.0);
+                let column_top = cells * step;
+
+                // Split the column at the ramp heights; each piece gets the material of the band below it.
+                let min = settings.offset + Vec3::new(x as f32 * step, 0.0, z as f32 * step);
+                let mut bottom = 0.0;
+                let mut material = settings.material;
+                for &(band_height, band_material) in &settings.ramp {
+                    let band_start = ((band_height / step).round() * step).clamp(bottom, column_top);
+                    self.fill_column(min, bottom, band_start, step, material);
+                    bottom = band_start;
+                    material = band_material;
+                }
+                self.fill_column(min, bottom, column_top, step, material);
+            }
+        }
+        self.end_group();
+        true
+    }
+
+    /// Fills the part `bottom..top` (relative heights) of the column whose bottom corner is `min`.
+    fn fill_column(&mut self, min: Vec3, bottom: f32, top: f32, step: f32, material: MaterialId) {
+        if top > bottom {
+            let aabb = AABB::new(min + Vec3::Y * bottom, min + Vec3::new(step, top, step));
+            self.fill_aabb(&aabb, Voxel::new(material));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::render::render_resource::{Extent3d, TextureDimension};
+    use super::*;
+
+    const SMALL_GRAY: &[u8] = include_bytes!("../../../assets/heightmaps/small_gray.png");
+
+    fn column_height(octree: &SparseVoxelOctree, x: f32, z: f32) -> usize {
+        (0..64)
+            .take_while(|&y| octree.get_voxel_at_world_coords(Vec3::new(x, y as f32 + 0.5, z)).is_some())
+            .count()
+    }
+
+    #[test]
+    fn decodes_grayscale_png() {
+        let heightmap = Heightmap::from_bytes(SMALL_GRAY, "png").unwrap();
+        assert_eq!((heightmap.width, heightmap.depth), (4, 3));
+        assert_eq!(heightmap.height(0, 0), 0.0);
+        assert!((heightmap.height(1, 0) - 0.2).abs() < 1e-3);
+        assert_eq!(heightmap.height(3, 0), 1.0);
+        assert_eq!(heightmap.height(0, 1), 1.0);
+        assert!((heightmap.height(2, 2) - 0.4).abs() < 1e-3);
+    }
+
+    #[test]
+    fn reads_color_images_as_luminance() {
+        let image = Image::new(
+            Extent3d { width: 2, height: 1, depth_or_array_layers: 1 },
+            TextureDimension::D2,
+            vec![255, 0, 0, 255, 255, 255, 255, 255],
+            TextureFormat::Rgba8Unorm,
+            RenderAssetUsages::MAIN_WORLD,
+        );
+        let heightmap = Heightmap::from_image(&image).unwrap();
+        assert!((heightmap.height(0, 0) - 1.0 / 3.0).abs() < 1e-3);
+        assert_eq!(heightmap.height(1, 0), 1.0);
+        assert!(Heightmap::from_bytes(b"not an image", "png").is_err());
+    }
+
+    #[test]
+    fn builds_columns() {
+        let heightmap = Heightmap::from_bytes(SMALL_GRAY, "png").unwrap();
+        let mut octree = SparseVoxelOctree::new(5, 32.0, false, false, false);
+        let settings = HeightmapSettings::new(MaterialId(1), 10.0).with_base_height(2.0).with_offset(Vec3::new(-2.0, 0.0, -1.0));
+        octree.import_heightmap(&heightmap, &settings);
+
+        // Heights: base 2 + value * 10, rounded to whole voxels.
+        assert_eq!(column_height(&octree, -1.5, -0.5), 2);
+        assert_eq!(column_height(&octree, -0.5, -0.5), 4);
+        assert_eq!(column_height(&octree, 1.5, -0.5), 12);
+        assert_eq!(column_height(&octree, 0.5, 1.5), 6);
+        assert!(octree.get_voxel_at_world_coords(Vec3::new(-1.5, -0.5, -0.5)).is_none());
+        assert!(octree.get_voxel_at_world_coords(Vec3::new(2.5, 0.5, -0.5)).is_none());
+
+        // The whole import is one undo step.
+        assert_eq!(octree.history.undo_steps(), 1);
+        octree.undo();
+        assert_eq!(octree.iter().count(), 0);
+    }
+
+    #[test]
+    fn applies_color_ramp_and_expands_root() {
+        let heightmap = Heightmap { width: 1, depth: 1, heights: vec![1.0] };
+        let mut octree = SparseVoxelOctree::new(3, 8.0, false, false, false);
+        let settings = HeightmapSettings::new(MaterialId(2), 20.0)
+            .with_ramp(vec![(12.0, MaterialId(4)), (3.0, MaterialId(3))]);
+        octree.import_heightmap(&heightmap, &settings);
+
+        assert!(octree.max_depth > 3);
+        assert_eq!(column_height(&octree, 0.5, 0.5), 20);
+        let material = |y: f32| octree.get_voxel_at_world_coords(Vec3::new(0.5, y, 0.5)).unwrap().material;
+        assert_eq!(material(0.5), MaterialId(2));
+        assert_eq!(material(2.5), MaterialId(2));
+        assert_eq!(material(3.5), MaterialId(3));
+        assert_eq!(material(11.5), MaterialId(3));
+        assert_eq!(material(12.5), MaterialId(4));
+        assert_eq!(material(19.5), MaterialId(4));
+    }
+}
@@ -2,10 +2,10 @@ use bevy::color::Color;
 use bevy::math::DVec3;
 use bevy::prelude::Vec3;
 use bevy_egui::egui::Key::D;
-use crate::systems::voxels::structure::{OctreeNode, Ray, SparseVoxelOctree, Voxel, AABB};
+use crate::systems::voxels::structure::{NodeId, Ray, SparseVoxelOctree, VoxelData, VoxelKey, AABB};
 
 
-impl SparseVoxelOctree {
+impl<T: VoxelData> SparseVoxelOctree<T> {
     pub fn ray_intersects_aabb(&self,ray: &Ray, aabb: &AABB) -> bool {
         let inv_dir = 1.0 / ray.direction;
         let t1 = (aabb.min - ray.origin) * inv_dir;
@@ -27,20 +27,66 @@ impl SparseVoxelOctree {
         self.size / (2_u32.pow(effective)) as f32
     }
 
+    /// Returns the number of max-depth cells covered by a leaf at the given depth.
+    /// At `MAX_DEPTH` a root leaf covers 2^90 cells, hence the `u128`.
+    pub fn cells_in_leaf(&self, depth: u32) -> u128 {
+        1 << (3 * self.max_depth.saturating_sub(depth))
+    }
+
 
     /// Center-based: [-size/2..+size/2]. Shift +half_size => [0..size], floor, shift back.
     pub fn normalize_to_voxel_at_depth(&self, position: Vec3, depth: u32) -> Vec3 {
+        self.key_to_normalized(self.key_at(position, depth))
+    }
+
+    /// Returns the integer cell containing the world position at the given depth.
+    /// The key may be out of range if the position lies outside the root bounds.
+    pub fn key_at(&self, position: Vec3, depth: u32) -> VoxelKey {
+        self.key_at_double(position.as_dvec3(), depth)
+    }
+
+    /// Like `key_at`, for an f64 position. The cell is computed in f64, so it stays exact
+    /// far from the octree's origin.
+    pub fn key_at_double(&self, position: DVec3, depth: u32) -> VoxelKey {
         // Convert world coordinate to normalized [0,1] space.
-        let half_size = self.size * 0.5;
-        // Shift to [0, self.size]
-        let shifted = (position + Vec3::splat(half_size)) / self.size;
+        let size = self.size as f64;
+        let shifted = (position + DVec3::splat(size * 0.5)) / size;
         // Determine the number of voxels along an edge at the given depth.
-        let voxel_count = 2_u32.pow(depth) as f32;
-        // Get the voxel index (as a float) and then compute the center in normalized space.
-        let voxel_index = (shifted * voxel_count).floor();
-        let voxel_center = (voxel_index + Vec3::splat(0.5)) / voxel_count;
-        voxel_center
+        let voxel_count = (1_u64 << depth) as f64;
+        VoxelKey::new((shifted * voxel_count).floor().as_ivec3(), depth)
     }
+
+    /// Returns the normalized [0,1] center of the cell addressed by `key`.
+    pub fn key_to_normalized(&self, key: VoxelKey) -> Vec3 {
+        let voxel_count = key.cells_per_axis() as f32;
+        (key.position.as_vec3() + Vec3::splat(0.5)) / voxel_count
+    }
+
+    /// Returns the world-space center of the cell addressed by `key`.
+    pub fn key_center(&self, key: VoxelKey) -> Vec3 {
+        self.denormalize_voxel_center(self.key_to_normalized(key))
+    }
+
+    /// Like `key_center`, in f64.
+    pub fn key_center_double(&self, key: VoxelKey) -> DVec3 {
+        let size = self.size as f64;
+        let cell_size = size / key.cells_per_axis() as f64;
+        (key.position.as_dvec3() + DVec3::splat(0.5)) * cell_size - DVec3::splat(size * 0.5)
+    }
+
+    /// Returns the world-space bounds of the cell addressed by `key`.
+    pub fn key_bounds(&self, key: VoxelKey) -> AABB {
+        let cell_size = self.size / key.cells_per_axis() as f32;
+        let min = key.position.as_vec3() * cell_size - Vec3::splat(self.size * 0.5);
+        AABB::new(min, min + Vec3::splat(cell_size))
+    }
+
+    /// Returns the world-space bounds of the root node, [-size/2..+size/2] on every axis.
+    pub fn root_bounds(&self) -> AABB {
+        let half_size = self.size / 2.0;
+        AABB::new(Vec3::splat(-half_size), Vec3::splat(half_size))
+    }
+
     pub fn denormalize_voxel_center(&self, voxel_center: Vec3) -> Vec3 {
         let half_size = self.size * 0.5;
         // Convert the normalized voxel center back to world space.
@@ -118,10 +164,9 @@ impl SparseVoxelOctree {
             && (z >= -half_size - eps && z < half_size + eps)
     }
 
-    /// Retrieve a voxel at world coordinates by normalizing and looking up.
-    pub fn get_voxel_at_world_coords(&self, position: Vec3) -> Option<&Voxel> {
-        let aligned = self.normalize_to_voxel_at_depth(position, self.max_depth);
-        self.get_voxel_at(aligned.x, aligned.y, aligned.z)
+    /// Retrieve a voxel at world coordinates by converting to a key and looking up.
+    pub fn get_voxel_at_world_coords(&self, position: Vec3) -> Option<&T> {
+        self.get_voxel_at_key(self.key_at(position, self.max_depth))
     }
 
     pub fn local_to_world(&self, local_pos: Vec3) -> Vec3 {
@@ -136,55 +181,16 @@ impl SparseVoxelOctree {
 
 
 
-    /// Helper function to recursively traverse the octree to a specific depth.
-    fn get_node_at_depth(
-        node: &OctreeNode,
-        x: f32,
-        y: f32,
-        z: f32,
-        depth: u32,
-    ) -> Option<&OctreeNode> {
-        if depth == 0 {
-            return Some(node); // We've reached the desired depth
-        }
-
-        if let Some(ref children) = node.children {
-            // Determine which child to traverse into
-            let epsilon = 1e-6;
-            let index = ((x >= 0.5 - epsilon) as usize)
-                + ((y >= 0.5 - epsilon) as usize * 2)
-                + ((z >= 0.5 - epsilon) as usize * 4);
-
-            let adjust_coord = |coord: f32| {
-                if coord >= 0.5 - epsilon {
-                    (coord - 0.5) * 2.0
-                } else {
-                    coord * 2.0
-                }
-            };
-
-            // Recurse into the correct child
-            Self::get_node_at_depth(
-                &children[index],
-                adjust_coord(x),
-                adjust_coord(y),
-                adjust_coord(z),
-                depth - 1,
-            )
-        } else {
-            None // Node has no children at this depth
-        }
-    }
-
-    pub fn has_volume(&self, node: &OctreeNode) -> bool {
+    pub fn has_volume(&self, id: NodeId) -> bool {
+        let node = self.nodes.get(id);
         // Check if this node is a leaf with a voxel
-        if node.is_leaf && node.voxel.is_some() {
+        if node.voxel.is_some() {
             return true;
         }
 
         // If the node has children, recursively check them
-        if let Some(children) = &node.children {
-            for child in children.iter() {
+        if let Some(first) = node.children {
+            for child in first..first + 8 {
                 if self.has_volume(child) {
                     return true; // If any child has a voxel, the chunk has volume
                 }
@@ -0,0 +1,398 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use crate::systems::voxels::structure::{SparseVoxelOctree, VoxelData, AABB};
+
+/// Number of undo steps kept by default.
+pub const DEFAULT_HISTORY_DEPTH: usize = 100;
+
+/// The filled cells inside `bounds` at some point in time, in world space so that
+/// root expansion or shrinking in between does not invalidate them. The cells are shared
+/// with the `RegionChanged` event of the same edit.
+#[derive(Debug, Clone)]
+pub struct EditRecord<T: VoxelData> {
+    pub bounds: AABB,
+    pub voxels: Arc<[(AABB, T)]>,
+}
+
+/// Undo and redo stacks of an octree. Every edit made through the octree API is recorded with
+/// the previous contents of the cells it touches; edits between `begin_group` and `end_group`
+/// form a single step.
+#[derive(Debug, Clone)]
+pub struct EditHistory<T: VoxelData> {
+    /// Oldest step first. Each step holds its records in the order they were made.
+    undo: VecDeque<Vec<EditRecord<T>>>,
+    redo: Vec<Vec<EditRecord<T>>>,
+    /// Records of the group being built, if any.
+    group: Option<Vec<EditRecord<T>>>,
+    group_depth: u32,
+    /// Maximum number of undo steps; older steps are dropped. See `set_max_steps`.
+    pub max_steps: usize,
+    /// When false, edits are applied without being recorded.
+    pub enabled: bool,
+}
+
+impl<T: VoxelData> EditHistory<T> {
+    pub fn new(max_steps: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            group: None,
+            group_depth: 0,
+            max_steps,
+            enabled: true,
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    pub fn undo_steps(&self) -> usize {
+        self.undo.len()
+    }
+
+    pub fn redo_steps(&self) -> usize {
+        self.redo.len()
+    }
+
+    /// Changes the history depth, dropping the oldest steps if there are more.
+    pub fn set_max_steps(&mut self, max_steps: usize) {
+        self.max_steps = max_steps;
+        self.trim();
+    }
+
+    /// Estimated heap usage of the recorded steps.
+    pub fn heap_bytes(&self) -> usize {
+        let step_bytes = |step: &Vec<EditRecord<T>>| {
+            step.capacity() * std::mem::size_of::<EditRecord<T>>()
+                + step.iter().map(|record| record.voxels.len() * std::mem::size_of::<(AABB, T)>()).sum::<usize>()
+        };
+        (self.undo.capacity() + self.redo.capacity()) * std::mem::size_of::<Vec<EditRecord<T>>>()
+            + self.undo.iter().chain(&self.redo).chain(&self.group).map(step_bytes).sum::<usize>()
+    }
+
+    /// Drops every step, including the records of a group that is still open.
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.group = None;
+        self.group_depth = 0;
+    }
+
+    fn push_step(&mut self, step: Vec<EditRecord<T>>) {
+        if step.is_empty() {
+            return;
+        }
+        self.undo.push_back(step);
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        while self.undo.len() > self.max_steps {
+            self.undo.pop_front();
+        }
+    }
+}
+
+impl<T: VoxelData> Default for EditHistory<T> {
+    fn default() -> Self {
+        Self::new(DEFAULT_HISTORY_DEPTH)
+    }
+}
+
+impl<T: VoxelData> SparseVoxelOctree<T> {
+    /// Starts an undo step that collects every edit until the matching `end_group`.
+    /// Groups can be nested; only the outermost one creates a step.
+    pub fn begin_group(&mut self) {
+        self.history.group_depth += 1;
+        if self.history.group_depth == 1 {
+            self.history.group = Some(Vec::new());
+        }
+    }
+
+    pub fn end_group(&mut self) {
+        if self.history.group_depth == 0 {
+            return;
+        }
+        self.history.group_depth -= 1;
+        if self.history.group_depth == 0 {
+            if let Some(step) = self.history.group.take() {
+                self.history.push_step(step);
+            }
+        }
+    }
+
+    /// Runs `edit` as a single undo step.
+    pub fn edit_group<R>(&mut self, edit: impl FnOnce(&mut Self) -> R) -> R {
+        self.begin_group();
+        let result = edit(self);
+        self.end_group();
+        result
+    }
+
+    /// Reverts the last undo step. Returns false if there was nothing to undo.
+    pub fn undo(&mut self) -> bool {
+        let Some(step) = self.history.undo.pop_back() else {
+            return false;
+        };
+        let inverse = self.apply_step(step);
+        self.history.redo.push(inverse);
+        true
+    }
+
+    /// Re-applies the last undone step. Returns false if there was nothing to redo.
+    pub fn redo(&mut self) -> bool {
+        let Some(step) = self.history.redo.pop() else {
+            return false;
+        };
+        let inverse = self.apply_step(step);
+        self.history.push_step(inverse);
+        true
+    }
+
+    /// Called by every editing method before it changes the cells overlapping `bounds`.
+    /// Starting a new edit drops the redo stack.
+    pub(crate) fn record_edit(&mut self, bounds: AABB) {
+        if self.history.enabled {
+            let record = self.capture_edit(bounds);
+            self.push_record(record);
+        }
+    }
+
+    /// The cells an edit of `bounds` can change. The bounds are widened to whole cells at
+    /// max depth, so undoing also clears cells the edit only partly covered.
+    pub(crate) fn capture_edit(&self, bounds: AABB) -> EditRecord<T> {
+        let cell = self.get_spacing_at_depth(self.max_depth);
+        self.capture(AABB::new((bounds.min / cell).floor() * cell, (bounds.max / cell).ceil() * cell))
+    }
+
+    /// Adds a record taken by `capture_edit` to the history, unless it is disabled.
+    pub(crate) fn push_record(&mut self, record: EditRecord<T>) {
+        if !self.history.enabled {
+            return;
+        }
+        self.history.redo.clear();
+        match &mut self.history.group {
+            Some(group) => group.push(record),
+            None => self.history.push_step(vec![record]),
+        }
+    }
+
+    /// Restores the records of `step` newest first and returns the step that reverts it.
+    fn apply_step(&mut self, step: Vec<EditRecord<T>>) -> Vec<EditRecord<T>> {
+        let enabled = std::mem::replace(&mut self.history.enabled, false);
+        let inverse = step
+            .into_iter()
+            .rev()
+            .map(|record| {
+                let current = self.capture(record.bounds);
+                // Clearing first also removes voxels added outside the root the record was taken in.
+                self.clear_aabb(&record.bounds);
+                for (cell, voxel) in record.voxels.iter() {
+                    self.fill_aabb(cell, *voxel);
+                }
+                current
+            })
+            .collect();
+        self.history.enabled = enabled;
+        inverse
+    }
+
+    /// The filled leaves overlapping `bounds`. Merged leaves are stored whole, which is fine
+    /// because an edit leaves the parts outside `bounds` unchanged.
+    pub(crate) fn capture(&self, bounds: AABB) -> EditRecord<T> {
+        EditRecord {
+            bounds,
+            voxels: self.iter_in_aabb(&bounds).map(|entry| (entry.bounds(), *entry.voxel)).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+    use crate::systems::voxels::csg::Shape;
+    use crate::systems::voxels::material::MaterialId;
+    use crate::systems::voxels::structure::{SparseVoxelOctree, Voxel, AABB};
+
+    fn voxel(material: u16) -> Voxel {
+        Voxel::new(MaterialId(material))
+    }
+
+    /// Every filled cell at max depth, so differently merged trees compare equal.
+    fn cells(octree: &SparseVoxelOctree) -> Vec<(IVec3, Voxel)> {
+        let cell = octree.get_spacing_at_depth(octree.max_depth);
+        let mut cells = Vec::new();
+        for entry in octree.iter() {
+            let bounds = entry.bounds();
+            let min = (bounds.min / cell).round().as_ivec3();
+            let max = (bounds.max / cell).round().as_ivec3();
+            for x in min.x..max.x {
+                for y in min.y..max.y {
+                    for z in min.z..max.z {
+                        cells.push((IVec3::new(x, y, z), *entry.voxel));
+                    }
+                }
+            }
+        }
+        cells.sort_by_key(|&(position, _)| position.to_array());
+        cells
+    }
+
+    #[test]
+    fn undoes_and_redoes_single_edits() {
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        octree.insert(Vec3::new(0.5, 0.5, 0.5), voxel(1));
+        octree.insert(Vec3::new(1.5, 0.5, 0.5), voxel(2));
+        let two = cells(&octree);
+        octree.remove(Vec3::new(0.5, 0.5, 0.5));
+        assert_eq!(octree.history.undo_steps(), 3);
+
+        assert!(octree.undo());
+        assert_eq!(cells(&octree), two);
+        assert!(octree.undo());
+        assert!(octree.undo());
+        assert!(cells(&octree).is_empty());
+        assert!(!octree.undo());
+
+        assert!(octree.redo());
+        assert!(octree.redo());
+        assert_eq!(cells(&octree), two);
+        assert_eq!(octree.history.redo_steps(), 1);
+
+        // A new edit drops what could still be redone.
+        octree.insert(Vec3::new(2.5, 0.5, 0.5), voxel(3));
+        assert!(!octree.history.can_redo());
+    }
+
+    #[test]
+    fn overwritten_voxels_come_back() {
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        octree.fill_aabb(&AABB::new(Vec3::splat(-4.0), Vec3::splat(4.0)), voxel(1));
+        let before = cells(&octree);
+        octree.insert(Vec3::new(0.5, 0.5, 0.5), voxel(2));
+        octree.subtract_shape(&Shape::Sphere { center: Vec3::new(3.0, 3.0, 3.0), radius: 2.5 });
+
+        octree.undo();
+        octree.undo();
+        assert_eq!(cells(&octree), before);
+        // The merged fill is restored as a single leaf.
+        assert_eq!(octree.iter().count(), 8);
+    }
+
+    #[test]
+    fn groups_form_one_step() {
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        octree.edit_group(|octree| {
+            for x in 0..5 {
+                octree.insert(Vec3::new(x as f32 + 0.5, 0.5, 0.5), voxel(1));
+            }
+            // Nested groups join the outer one.
+            octree.edit_group(|octree| octree.remove(Vec3::new(2.5, 0.5, 0.5)));
+        });
+        assert_eq!(octree.history.undo_steps(), 1);
+        assert_eq!(cells(&octree).len(), 4);
+
+        octree.undo();
+        assert!(cells(&octree).is_empty());
+        octree.redo();
+        assert_eq!(cells(&octree).len(), 4);
+        assert!(octree.get_voxel_at_world_coords(Vec3::new(2.5, 0.5, 0.5)).is_none());
+    }
+
+    #[test]
+    fn clearing_drops_an_open_group() {
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        octree.begin_group();
+        octree.insert(Vec3::new(0.5, 0.5, 0.5), voxel(1));
+        octree.history.clear();
+        octree.end_group();
+        assert_eq!(octree.history.undo_steps(), 0);
+
+        // Later edits are recorded on their own again.
+        octree.insert(Vec3::new(1.5, 0.5, 0.5), voxel(1));
+        assert_eq!(octree.history.undo_steps(), 1);
+        octree.undo();
+        assert_eq!(cells(&octree).len(), 1);
+    }
+
+    #[test]
+    fn undo_survives_root_resizing() {
+        let mut octree = SparseVoxelOctree::new(3, 8.0, false, false, false);
+        octree.insert(Vec3::new(0.5, 0.5, 0.5), voxel(1));
+        octree.insert(Vec3::new(40.5, 0.5, 0.5), voxel(2));
+        assert!(octree.max_depth > 3);
+
+        octree.undo();
+        assert_eq!(octree.max_depth, 3);
+        assert_eq!(cells(&octree).len(), 1);
+
+        octree.redo();
+        assert_eq!(octree.get_voxel_at_world_coords(Vec3::new(40.5, 0.5, 0.5)), Some(&voxel(2)));
+        octree.undo();
+        octree.undo();
+        assert!(cells(&octree).is_empty());
+    }
+
+    #[test]
+    fn depth_is_limited() {
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        octree.history.set_max_steps(3);
+        for x in 0..5 {
+            octree.insert(Vec3::new(x as f32 + 0.5, 0.5, 0.5), voxel(1));
+        }
+        assert_eq!(octree.history.undo_steps(), 3);
+        while octree.undo() {}
+        assert_eq!(cells(&octree).len(), 2);
+
+        octree.history.enabled = false;
+        octree.insert(Vec3::new(-0.5, 0.5, 0.5), voxel(1));
+        assert_eq!(octree.history.undo_steps(), 0);
+    }
+
+    #[test]
+    fn random_edits_unwind() {
+        let mut state = 0x2545_f491_u32;
+        let mut random = move |range: i32| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state % range as u32) as i32
+        };
+
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        let mut snapshots = vec![cells(&octree)];
+        for _ in 0..60 {
+            let position = Vec3::new(random(24) as f32 - 12.0, random(16) as f32 - 8.0, random(16) as f32 - 8.0) + 0.5;
+            let material = random(3) as u16;
+            match random(5) {
+                0 | 1 => {
+                    octree.insert(position, voxel(material));
+                }
+                2 => octree.remove(position),
+                3 => {
+                    octree.fill_aabb(&AABB::new(position - 2.0, position + Vec3::new(3.0, 1.0, 2.0)), voxel(material));
+                }
+                _ => octree.subtract_shape(&Shape::Sphere { center: position, radius: 2.5 }),
+            }
+            // Removing empty space is not an undo step.
+            if octree.history.undo_steps() == snapshots.len() {
+                snapshots.push(cells(&octree));
+            }
+        }
+
+        for expected in snapshots.iter().rev().skip(1) {
+            assert!(octree.undo());
+            assert_eq!(&cells(&octree), expected);
+        }
+        assert!(!octree.undo());
+        for expected in snapshots.iter().skip(1) {
+            assert!(octree.redo());
+            assert_eq!(&cells(&octree), expected);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use bevy::prelude::*;
+use crate::systems::voxels::structure::{NodeId, NodePool, SparseVoxelOctree, Voxel, VoxelData, VoxelKey, AABB};
+
+/// A filled leaf yielded by the octree iterators.
+#[derive(Debug, Clone, Copy)]
+pub struct VoxelEntry<'a, T: VoxelData = Voxel> {
+    /// World-space center of the voxel's cell.
+    pub position: Vec3,
+    pub voxel: &'a T,
+    pub key: VoxelKey,
+    pub depth: u32,
+    /// Edge length of the cell in world units.
+    pub size: f32,
+}
+
+impl<T: VoxelData> VoxelEntry<'_, T> {
+    /// World-space bounds of the voxel's cell.
+    pub fn bounds(&self) -> AABB {
+        let half = Vec3::splat(self.size * 0.5);
+        AABB::new(self.position - half, self.position + half)
+    }
+}
+
+/// Depth-first iterator over the filled leaves of an octree.
+/// Only keeps a stack of pending nodes, so it never materializes the full voxel list.
+pub struct VoxelIter<'a, T: VoxelData = Voxel> {
+    octree: &'a SparseVoxelOctree<T>,
+    stack: Vec<(NodeId, VoxelKey)>,
+    region: Option<AABB>,
+    depth: Option<u32>,
+}
+
+impl<'a, T: VoxelData> VoxelIter<'a, T> {
+    fn new(octree: &'a SparseVoxelOctree<T>, region: Option<AABB>, depth: Option<u32>) -> Self {
+        let mut stack = Vec::with_capacity(8 * octree.max_depth as usize + 1);
+        stack.push((NodePool::ROOT, VoxelKey::default()));
+        Self {
+            octree,
+            stack,
+            region,
+            depth,
+        }
+    }
+}
+
+impl<'a, T: VoxelData> Iterator for VoxelIter<'a, T> {
+    type Item = VoxelEntry<'a, T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((id, key)) = self.stack.pop() {
+            let bounds = self.octree.key_bounds(key);
+            // Skip subtrees that do not overlap the query box.
+            if self.region.is_some_and(|region| !region.intersects(&bounds)) {
+                continue;
+            }
+
+            let node = self.octree.nodes.get(id);
+            if let Some(first) = node.children {
+                // Nothing below the requested depth can match.
+                if self.depth.is_some_and(|depth| key.depth >= depth) {
+                    continue;
+                }
+                // Push in reverse so children come out in index order.
+                for i in (0..8).rev() {
+                    self.stack.push((first + i as NodeId, key.child(i)));
+                }
+                continue;
+            }
+
+            if let Some(voxel) = &node.voxel {
+                if self.depth.is_some_and(|depth| key.depth != depth) {
+                    continue;
+                }
+                return Some(VoxelEntry {
+                    position: bounds.center(),
+                    voxel,
+                    key,
+                    depth: key.depth,
+                    size: bounds.size().x,
+                });
+            }
+        }
+        None
+    }
+}
+
+impl<T: VoxelData> SparseVoxelOctree<T> {
+    /// Iterates over every filled leaf.
+    pub fn iter(&self) -> VoxelIter<'_, T> {
+        VoxelIter::new(self, None, None)
+    }
+
+    /// Iterates over the filled leaves whose cell overlaps `aabb`, skipping subtrees outside it.
+    pub fn iter_in_aabb(&self, aabb: &AABB) -> VoxelIter<'_, T> {
+        VoxelIter::new(self, Some(*aabb), None)
+    }
+
+    /// Iterates over the filled leaves stored exactly at `depth`.
+    pub fn iter_at_depth(&self, depth: u32) -> VoxelIter<'_, T> {
+        VoxelIter::new(self, None, Some(depth))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::systems::voxels::material::MaterialId;
+    use super::*;
+
+    /// Two single voxels and a merged 2x2x2 block in an octree with 1 m cells.
+    fn octree() -> SparseVoxelOctree {
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        octree.insert(Vec3::new(0.5, 0.5, 0.5), Voxel::new(MaterialId(1)));
+        octree.fill_aabb(&AABB::new(Vec3::splat(-4.0), Vec3::splat(-2.0)), Voxel::new(MaterialId(2)));
+        octree.insert(Vec3::new(5.5, -6.5, 2.5), Voxel::new(MaterialId(3)));
+        octree
+    }
+
+    fn materials<'a>(entries: impl Iterator<Item = VoxelEntry<'a>>) -> Vec<u16> {
+        let mut materials: Vec<u16> = entries.map(|entry| entry.voxel.material.0).collect();
+        materials.sort_unstable();
+        materials
+    }
+
+    #[test]
+    fn iterates_filled_leaves() {
+        let octree = octree();
+        let entries: Vec<_> = octree.iter().collect();
+        assert_eq!(entries.len(), 3);
+        let merged = entries.iter().find(|entry| entry.voxel.material == MaterialId(2)).unwrap();
+        assert_eq!((merged.depth, merged.size, merged.position), (3, 2.0, Vec3::splat(-3.0)));
+        assert_eq!(merged.bounds(), AABB::new(Vec3::splat(-4.0), Vec3::splat(-2.0)));
+        assert_eq!(merged.key, octree.key_at(Vec3::splat(-3.0), 3));
+        let single = entries.iter().find(|entry| entry.voxel.material == MaterialId(3)).unwrap();
+        assert_eq!((single.depth, single.size, single.position), (4, 1.0, Vec3::new(5.5, -6.5, 2.5)));
+    }
+
+    #[test]
+    fn filters_by_region() {
+        let octree = octree();
+        assert_eq!(materials(octree.iter_in_aabb(&AABB::new(Vec3::ZERO, Vec3::ONE))), vec![1]);
+        // Leaves partly inside the box are yielded whole.
+        let entries: Vec<_> = octree.iter_in_aabb(&AABB::new(Vec3::splat(-2.5), Vec3::splat(0.5))).collect();
+        assert_eq!(materials(entries.iter().copied()), vec![1, 2]);
+        assert!(entries.iter().any(|entry| entry.bounds() == AABB::new(Vec3::splat(-4.0), Vec3::splat(-2.0))));
+        // Touching a leaf is not overlapping it.
+        assert!(octree.iter_in_aabb(&AABB::new(Vec3::splat(-2.0), Vec3::ZERO)).next().is_none());
+        assert_eq!(materials(octree.iter_in_aabb(&octree.root_bounds())), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn filters_by_depth() {
+        let octree = octree();
+        assert_eq!(materials(octree.iter_at_depth(4)), vec![1, 3]);
+        assert_eq!(materials(octree.iter_at_depth(3)), vec![2]);
+        assert!(octree.iter_at_depth(2).next().is_none());
+        assert!(octree.iter_at_depth(5).next().is_none());
+    }
+}
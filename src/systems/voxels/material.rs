@@ -0,0 +1,173 @@
+use bevy::prelude::*;
+
+/// Index of a material in the `MaterialPalette`. This is what voxels store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Reflect)]
+pub struct MaterialId(pub u16);
+
+/// Definition of a voxel material.
+#[derive(Debug, Clone, Reflect)]
+pub struct VoxelMaterial {
+    pub name: String,
+    pub color: Color,
+    pub roughness: f32,
+    pub metallic: f32,
+    pub emissive: Color,
+    /// 0.0 is fully opaque, 1.0 fully transparent.
+    pub transparency: f32,
+}
+
+/// Registry of all voxel materials. Voxels refer to entries by `MaterialId`.
+/// Entry 0 is the fallback used for ids that are not registered.
+#[derive(Resource, Debug, Clone, Reflect)]
+#[reflect(Resource)]
+pub struct MaterialPalette {
+    pub materials: Vec<VoxelMaterial>,
+}
+
+impl VoxelMaterial {
+    /// Creates an opaque, rough, non-emissive material with the given color.
+    pub fn new(name: impl Into<String>, color: Color) -> Self {
+        Self {
+            name: name.into(),
+            color,
+            roughness: 0.9,
+            metallic: 0.0,
+            emissive: Color::BLACK,
+            transparency: 0.0,
+        }
+    }
+
+    pub fn with_roughness(mut self, roughness: f32) -> Self {
+        self.roughness = roughness;
+        self
+    }
+
+    pub fn with_metallic(mut self, metallic: f32) -> Self {
+        self.metallic = metallic;
+        self
+    }
+
+    pub fn with_emissive(mut self, emissive: Color) -> Self {
+        self.emissive = emissive;
+        self
+    }
+
+    pub fn with_transparency(mut self, transparency: f32) -> Self {
+        self.transparency = transparency;
+        self
+    }
+
+    /// Builds the PBR material used to render voxels of this material.
+    pub fn to_standard_material(&self) -> StandardMaterial {
+        let transparent = self.transparency > 0.0;
+        StandardMaterial {
+            base_color: self.color.with_alpha(1.0 - self.transparency),
+            perceptual_roughness: self.roughness,
+            metallic: self.metallic,
+            emissive: LinearRgba::from(self.emissive),
+            alpha_mode: if transparent { AlphaMode::Blend } else { AlphaMode::Opaque },
+            ..Default::default()
+        }
+    }
+}
+
+impl MaterialPalette {
+    /// Creates a palette containing only the fallback material.
+    pub fn new() -> Self {
+        Self {
+            materials: vec![VoxelMaterial::new("default", Color::srgb(0.8, 0.7, 0.6))],
+        }
+    }
+
+    /// Registers a material and returns its id, or `None` if the palette already holds the
+    /// 65536 materials a `MaterialId` can address.
+    pub fn add(&mut self, material: VoxelMaterial) -> Option<MaterialId> {
+        let id = MaterialId(u16::try_from(self.materials.len()).ok()?);
+        self.materials.push(material);
+        Some(id)
+    }
+
+    pub fn get(&self, id: MaterialId) -> Option<&VoxelMaterial> {
+        self.materials.get(id.0 as usize)
+    }
+
+    /// Returns the material for `id`, falling back to entry 0 for unknown ids.
+    pub fn resolve(&self, id: MaterialId) -> &VoxelMaterial {
+        self.get(id).unwrap_or(&self.materials[0])
+    }
+
+    /// Looks up a material by name.
+    pub fn find(&self, name: &str) -> Option<MaterialId> {
+        self.materials
+            .iter()
+            .position(|material| material.name == name)
+            .map(|index| MaterialId(index as u16))
+    }
+
+    pub fn color(&self, id: MaterialId) -> Color {
+        self.resolve(id).color
+    }
+
+    pub fn len(&self) -> usize {
+        self.materials.len()
+    }
+}
+
+impl Default for MaterialPalette {
+    fn default() -> Self {
+        let mut palette = Self::new();
+        palette.add(VoxelMaterial::new("grass", Color::srgb(0.2, 0.8, 0.2)));
+        palette.add(VoxelMaterial::new("dirt", Color::srgb(0.45, 0.3, 0.15)));
+        palette.add(VoxelMaterial::new("stone", Color::srgb(0.5, 0.5, 0.5)).with_roughness(0.7));
+        palette.add(VoxelMaterial::new("brick", Color::srgb(1.0, 0.0, 0.0)));
+        palette.add(
+            VoxelMaterial::new("glass", Color::srgb(0.7, 0.85, 1.0))
+                .with_roughness(0.05)
+                .with_transparency(0.6),
+        );
+        palette.add(VoxelMaterial::new("metal", Color::srgb(0.8, 0.8, 0.85)).with_roughness(0.3).with_metallic(1.0));
+        palette.add(
+            VoxelMaterial::new("lamp", Color::srgb(1.0, 0.9, 0.6))
+                .with_emissive(Color::srgb(4.0, 3.6, 2.4)),
+        );
+        palette
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn added_materials_are_found_by_name() {
+        let mut palette = MaterialPalette::new();
+        let red = palette.add(VoxelMaterial::new("red", Color::srgb(1.0, 0.0, 0.0))).unwrap();
+        let blue = palette.add(VoxelMaterial::new("blue", Color::srgb(0.0, 0.0, 1.0))).unwrap();
+        assert_eq!((red, blue), (MaterialId(1), MaterialId(2)));
+        assert_eq!(palette.find("blue"), Some(blue));
+        assert_eq!(palette.find("default"), Some(MaterialId(0)));
+        assert_eq!(palette.find("green"), None);
+        assert_eq!(palette.len(), 3);
+    }
+
+    #[test]
+    fn unknown_ids_resolve_to_the_default_material() {
+        let mut palette = MaterialPalette::new();
+        let red = palette.add(VoxelMaterial::new("red", Color::srgb(1.0, 0.0, 0.0))).unwrap();
+        assert_eq!(palette.resolve(red).name, "red");
+        assert!(palette.get(MaterialId(7)).is_none());
+        assert_eq!(palette.resolve(MaterialId(7)).name, "default");
+        assert_eq!(palette.color(MaterialId(7)), palette.color(MaterialId(0)));
+    }
+
+    #[test]
+    fn a_full_palette_rejects_new_materials() {
+        let mut palette = MaterialPalette::new();
+        for i in 1..=u16::MAX {
+            assert_eq!(palette.add(VoxelMaterial::new(format!("material {i}"), Color::WHITE)), Some(MaterialId(i)));
+        }
+        assert_eq!(palette.add(VoxelMaterial::new("one too many", Color::WHITE)), None);
+        assert_eq!(palette.len(), 1 << 16);
+        assert_eq!(palette.find("one too many"), None);
+    }
+}
@@ -2,4 +2,18 @@ pub mod debug;
 pub mod helper;
 pub mod octree;
 pub mod structure;
-pub mod rendering;
\ No newline at end of file
+pub mod rendering;
+pub mod region;
+pub mod csg;
+pub mod iter;
+pub mod material;
+pub mod query;
+pub mod serialization;
+pub mod vox;
+pub mod heightmap;
+pub mod voxelize;
+pub mod export;
+pub mod history;
+pub mod events;
+pub mod stats;
+pub mod validate;
\ No newline at end of file
@@ -5,284 +5,224 @@ use bevy::math::{DQuat, DVec3};
 use bevy::prelude::*;
 use bevy::render::mesh::{Indices, PrimitiveTopology, VertexAttributeValues};
 use bevy::render::render_asset::RenderAssetUsages;
-use crate::systems::voxels::structure::{DirtyVoxel, OctreeNode, Ray, SparseVoxelOctree, Voxel, AABB, NEIGHBOR_OFFSETS};
+use crate::systems::double_transform::DoubleTransform;
+use crate::systems::voxels::events::VoxelChange;
+use crate::systems::voxels::history::EditHistory;
+use crate::systems::voxels::stats::OctreeStats;
+use crate::systems::voxels::structure::{NodeId, NodePool, OctreeNode, Ray, RaycastHit, SparseVoxelOctree, VoxelData, VoxelKey, AABB, MAX_DEPTH, NEIGHBOR_OFFSETS};
 
-impl SparseVoxelOctree {
+impl<T: VoxelData> SparseVoxelOctree<T> {
     /// Creates a new octree with the specified max depth, size, and wireframe visibility.
+    /// Panics if `max_depth` exceeds `MAX_DEPTH`.
     pub fn new(max_depth: u32, size: f32, show_wireframe: bool, show_world_grid: bool, show_chunks: bool) -> Self {
+        assert!(max_depth <= MAX_DEPTH, "octree depth {max_depth} exceeds {MAX_DEPTH}");
         Self {
-            root: OctreeNode::new(),
+            nodes: NodePool::new(),
             max_depth,
+            base_depth: max_depth,
             size,
             show_wireframe,
             show_world_grid,
             show_chunks,
-            dirty: Vec::new(),
+            validate_edits: false,
+            changes: Vec::new(),
+            history: EditHistory::default(),
+            stats: OctreeStats::default(),
         }
     }
-    pub fn insert(&mut self, position: Vec3, voxel: Voxel) {
-        // Align to the center of the voxel at max_depth
-        let mut aligned = self.normalize_to_voxel_at_depth(position, self.max_depth);
-        let mut world_center = self.denormalize_voxel_center(aligned);
-
-        // Expand as needed using the denormalized position.
-        while !self.contains(world_center.x, world_center.y, world_center.z) {
-            self.expand_root(world_center.x, world_center.y, world_center.z);
-            // Recompute aligned and world_center after expansion.
-            aligned = self.normalize_to_voxel_at_depth(position, self.max_depth);
-            world_center = self.denormalize_voxel_center(aligned);
-        }
+    /// Inserts a voxel at the given position, growing the root until it covers the position.
+    /// Returns false, and changes nothing, if the position is not finite or lies beyond what the
+    /// root can grow to cover at `MAX_DEPTH`.
+    pub fn insert(&mut self, position: Vec3, voxel: T) -> bool {
+        self.insert_double(position.as_dvec3(), voxel)
+    }
 
-        let dirty_voxel = DirtyVoxel{
-            position: aligned,
-        };
-        self.dirty.push(dirty_voxel);
+    /// Like `insert`, for an f64 position in octree space. Use it for positions coming from
+    /// `DoubleTransform::inverse_transform_point`, so far-away cells are hit exactly.
+    pub fn insert_double(&mut self, position: DVec3, voxel: T) -> bool {
+        if !position.is_finite() {
+            return false;
+        }
+        // Align to the voxel cell at max_depth
+        let mut key = self.key_at_double(position, self.max_depth);
 
+        // Expand until the cell lies inside the root bounds.
+        while !key.is_valid() {
+            if !self.expand_root() {
+                return false;
+            }
+            // Recompute the key after expansion.
+            key = self.key_at_double(position, self.max_depth);
+        }
 
-        Self::insert_recursive(&mut self.root, aligned, voxel, self.max_depth);
+        self.insert_key(key, voxel);
+        true
     }
 
-    fn insert_recursive(node: &mut OctreeNode, position: Vec3, voxel: Voxel, depth: u32) {
-        if depth == 0 {
-            node.voxel = Some(voxel);
-            node.is_leaf = true;
+    /// Inserts a voxel at the given integer cell. Keys outside the root bounds are ignored.
+    pub fn insert_key(&mut self, key: VoxelKey, voxel: T) {
+        if !key.is_valid() {
             return;
         }
-        let epsilon = 1e-6;
-        // Determine octant index by comparing with 0.5
-        let index = ((position.x >= 0.5 - epsilon) as usize)
-            + ((position.y >= 0.5 - epsilon) as usize * 2)
-            + ((position.z >= 0.5 - epsilon) as usize * 4);
-
-        // If there are no children, create them.
-        if node.children.is_none() {
-            node.children = Some(Box::new(core::array::from_fn(|_| OctreeNode::new())));
-            node.is_leaf = false;
+
+        let bounds = self.key_bounds(key);
+        self.record_edit(bounds);
+        let old = self.get_voxel_at_key(key).copied();
+        self.push_change(VoxelChange::Inserted { key, bounds, old, new: voxel });
+
+        // Walk down the key's path, splitting leaves that are in the way.
+        let mut path = Vec::with_capacity(key.depth as usize);
+        let mut id = NodePool::ROOT;
+        for level in 0..key.depth {
+            path.push(id);
+            let first = self.nodes.split(id);
+            id = first + key.child_index(level) as NodeId;
         }
-        if let Some(ref mut children) = node.children {
-            // Adjust coordinate into the child’s [0, 1] range.
-            let adjust_coord = |coord: f32| {
-                if coord >= 0.5 - epsilon {
-                    (coord - 0.5) * 2.0
-                } else {
-                    coord * 2.0
-                }
-            };
-            let child_pos = Vec3::new(
-                adjust_coord(position.x),
-                adjust_coord(position.y),
-                adjust_coord(position.z),
-            );
-            Self::insert_recursive(&mut children[index], child_pos, voxel, depth - 1);
+
+        // The voxel now covers this whole cell.
+        self.nodes.collapse(id);
+        self.nodes.get_mut(id).voxel = Some(voxel);
+
+        // Merge parents whose children all hold the same voxel.
+        for &parent in path.iter().rev() {
+            if !self.nodes.try_merge(parent) {
+                break;
+            }
         }
+        self.check_edit("insert_key");
     }
 
     pub fn remove(&mut self, position: Vec3) {
-        let aligned = self.normalize_to_voxel_at_depth(position, self.max_depth);
-
-        let dirty_voxel = DirtyVoxel{
-            position: aligned,
-        };
-        self.dirty.push(dirty_voxel);
+        self.remove_double(position.as_dvec3());
+    }
 
-        Self::remove_recursive(&mut self.root, aligned.x, aligned.y, aligned.z, self.max_depth);
+    /// Like `remove`, for an f64 position in octree space.
+    pub fn remove_double(&mut self, position: DVec3) {
+        let key = self.key_at_double(position, self.max_depth);
+        self.remove_key(key);
     }
 
-    fn remove_recursive(
-        node: &mut OctreeNode,
-        x: f32,
-        y: f32,
-        z: f32,
-        depth: u32,
-    ) -> bool {
-        if depth == 0 {
-            if node.voxel.is_some() {
-                node.voxel = None;
-                node.is_leaf = false;
-                return true;
-            } else {
-                return false;
-            }
-        }
+    /// Like `get_voxel_at_world_coords`, for an f64 position in octree space.
+    pub fn get_voxel_at_double(&self, position: DVec3) -> Option<&T> {
+        self.get_voxel_at_key(self.key_at_double(position, self.max_depth))
+    }
 
-        if node.children.is_none() {
-            return false;
-        }
-        let epsilon = 1e-6;
-        let index = ((x >= 0.5 - epsilon) as usize)
-            + ((y >= 0.5 - epsilon) as usize * 2)
-            + ((z >= 0.5 - epsilon) as usize * 4);
-
-        let adjust_coord = |coord: f32| {
-            if coord >= 0.5 - epsilon {
-                (coord - 0.5) * 2.0
-            } else {
-                coord * 2.0
-            }
+    /// Removes the voxel at the given integer cell and prunes empty branches.
+    pub fn remove_key(&mut self, key: VoxelKey) {
+        // Removing empty space changes nothing, so there is no undo step or event.
+        let Some(&old) = self.get_voxel_at_key(key) else {
+            return;
         };
 
-        let child = &mut node.children.as_mut().unwrap()[index];
-        let should_prune_child = Self::remove_recursive(
-            child,
-            adjust_coord(x),
-            adjust_coord(y),
-            adjust_coord(z),
-            depth - 1,
-        );
+        let bounds = self.key_bounds(key);
+        self.record_edit(bounds);
+        self.push_change(VoxelChange::Removed { key, bounds, old });
 
-        if should_prune_child {
-            // remove the child node
-            node.children.as_mut().unwrap()[index] = OctreeNode::new();
+        // Walk down the key's path. Stop early on empty space, split voxels covering a larger cell.
+        let mut path = Vec::with_capacity(key.depth as usize);
+        let mut id = NodePool::ROOT;
+        for level in 0..key.depth {
+            if self.nodes.get(id).is_empty() {
+                return;
+            }
+            path.push(id);
+            let first = self.nodes.split(id);
+            id = first + key.child_index(level) as NodeId;
         }
 
-        // Check if all children are empty
-        let all_children_empty = node
-            .children
-            .as_ref()
-            .unwrap()
-            .iter()
-            .all(|child| child.is_empty());
-
-        if all_children_empty {
-            node.children = None;
-            node.is_leaf = true;
-            return node.voxel.is_none();
+        self.nodes.collapse(id);
+        self.nodes.get_mut(id).voxel = None;
+
+        // Prune branches whose children all became empty.
+        for &parent in path.iter().rev() {
+            if !self.nodes.try_merge(parent) {
+                break;
+            }
         }
-        false
+        while self.shrink_root() {}
+        self.check_edit("remove_key");
     }
 
 
-    fn expand_root(&mut self, _x: f32, _y: f32, _z: f32) {
+    /// Doubles the root around the origin. Returns false, leaving the tree as it is, once
+    /// `max_depth` has reached `MAX_DEPTH`.
+    pub(crate) fn expand_root(&mut self) -> bool {
+        if self.max_depth >= MAX_DEPTH {
+            warn!("Root cannot expand beyond depth {MAX_DEPTH}");
+            return false;
+        }
         info!("Root expanding ...");
-        // Save the old root and its size.
-        let old_root = std::mem::replace(&mut self.root, OctreeNode::new());
-        let old_size = self.size;
-
-        // Update the octree's size and depth.
+        // The root stays centered on the origin, so the old tree becomes the inner half
+        // of the new one. Each voxel keeps its cell size, which is one level deeper now.
+        self.nodes.grow();
         self.size *= 2.0;
         self.max_depth += 1;
-
-        // Reinsert each voxel from the old tree.
-        let voxels = Self::collect_voxels_from_node(&old_root, old_size);
-        for (world_pos, voxel, _depth) in voxels {
-            self.insert(world_pos, voxel);
-        }
-    }
-
-    /// Helper: Collect all voxels from a given octree node recursively.
-    /// The coordinate system here assumes the node covers [–old_size/2, +old_size/2] in each axis.
-    fn collect_voxels_from_node(node: &OctreeNode, old_size: f32) -> Vec<(Vec3, Voxel, u32)> {
-        let mut voxels = Vec::new();
-        Self::collect_voxels_recursive(node, -old_size / 2.0, -old_size / 2.0, -old_size / 2.0, old_size, 0, &mut voxels);
-        voxels
+        true
     }
 
-    fn collect_voxels_recursive(
-        node: &OctreeNode,
-        x: f32,
-        y: f32,
-        z: f32,
-        size: f32,
-        depth: u32,
-        out: &mut Vec<(Vec3, Voxel, u32)>,
-    ) {
-        if node.is_leaf {
-            if let Some(voxel) = node.voxel {
-                // Compute the center of this node's region.
-                let center = Vec3::new(x + size / 2.0, y + size / 2.0, z + size / 2.0);
-                out.push((center, voxel, depth));
-            }
+    /// Expands the root until it contains `bounds`. Returns false, leaving the root as it is,
+    /// if `bounds` is not finite or would not fit even at `MAX_DEPTH`.
+    pub(crate) fn ensure_contains(&mut self, bounds: &AABB) -> bool {
+        if !(bounds.min.is_finite() && bounds.max.is_finite()) {
+            return false;
+        }
+        let reach = self.size * 0.5 * (1_u32 << (MAX_DEPTH - self.max_depth)) as f32;
+        if bounds.min.min_element() < -reach || bounds.max.max_element() > reach {
+            return false;
         }
-        if let Some(children) = &node.children {
-            let half = size / 2.0;
-            for (i, child) in children.iter().enumerate() {
-                let offset_x = if (i & 1) != 0 { half } else { 0.0 };
-                let offset_y = if (i & 2) != 0 { half } else { 0.0 };
-                let offset_z = if (i & 4) != 0 { half } else { 0.0 };
-                Self::collect_voxels_recursive(child, x + offset_x, y + offset_y, z + offset_z, half, depth + 1, out);
+        while !self.root_bounds().contains_aabb(bounds) {
+            if !self.expand_root() {
+                return false;
             }
         }
+        true
     }
 
-
-
-    pub fn traverse(&self) -> Vec<(Vec3, Color, u32)> {
-        let mut voxels = Vec::new();
-        // Start at the normalized center (0.5, 0.5, 0.5) rather than (0,0,0)
-        Self::traverse_recursive(
-            &self.root,
-            Vec3::splat(0.5), // normalized center of the root cell
-            1.0,              // full normalized cell size
-            0,
-            &mut voxels,
-            self,
-        );
-        voxels
+    /// Halves the root if all voxels fit in its inner half, undoing an earlier `expand_root`.
+    /// Never shrinks below the depth the octree was created with. Returns true if the root shrank.
+    pub fn shrink_root(&mut self) -> bool {
+        if self.max_depth <= self.base_depth || !self.nodes.shrink() {
+            return false;
+        }
+        info!("Root shrinking ...");
+        self.size *= 0.5;
+        self.max_depth -= 1;
+        true
     }
 
-    fn traverse_recursive(
-        node: &OctreeNode,
-        local_center: Vec3,
-        size: f32,
-        depth: u32,
-        out: &mut Vec<(Vec3, Color, u32)>,
-        octree: &SparseVoxelOctree,
-    ) {
-        // If a leaf contains a voxel, record its world-space center
-        if node.is_leaf {
-            if let Some(voxel) = node.voxel {
-                out.push((octree.denormalize_voxel_center(local_center), voxel.color, depth));
-            }
-        }
 
-        // If the node has children, subdivide the cell into 8 subcells.
-        if let Some(ref children) = node.children {
-            let offset = size / 4.0;  // child center offset from parent center
-            let new_size = size / 2.0;  // each child cell's size in normalized space
-            for (i, child) in children.iter().enumerate() {
-                // Compute each axis' offset: use +offset if the bit is set, else -offset.
-                let dx = if (i & 1) != 0 { offset } else { -offset };
-                let dy = if (i & 2) != 0 { offset } else { -offset };
-                let dz = if (i & 4) != 0 { offset } else { -offset };
-                let child_center = local_center + Vec3::new(dx, dy, dz);
-
-                Self::traverse_recursive(child, child_center, new_size, depth + 1, out, octree);
-            }
-        }
+
+    pub fn traverse(&self) -> Vec<(Vec3, T, u32)> {
+        self.iter()
+            .map(|entry| (entry.position, *entry.voxel, entry.depth))
+            .collect()
     }
 
 
 
-    /// Retrieve a voxel from the octree if it exists (x,y,z in [-0.5..+0.5] range).
-    pub fn get_voxel_at(&self, x: f32, y: f32, z: f32) -> Option<&Voxel> {
-        Self::get_voxel_recursive(&self.root, x, y, z)
+    /// Retrieve a voxel from the octree if it exists (x,y,z in normalized [0..1] range).
+    pub fn get_voxel_at(&self, x: f32, y: f32, z: f32) -> Option<&T> {
+        let cells = (1_u32 << self.max_depth) as f32;
+        let position = (Vec3::new(x, y, z) * cells).floor().as_ivec3();
+        self.get_voxel_at_key(VoxelKey::new(position, self.max_depth))
     }
 
-    fn get_voxel_recursive(node: &OctreeNode, x: f32, y: f32, z: f32) -> Option<&Voxel> {
-        if node.is_leaf {
-            return node.voxel.as_ref();
+    /// Retrieve the voxel covering the given integer cell, if any.
+    /// A voxel stored at a shallower depth covers all cells below it.
+    pub fn get_voxel_at_key(&self, key: VoxelKey) -> Option<&T> {
+        if !key.is_valid() {
+            return None;
         }
-        if let Some(children) = &node.children {
-            let epsilon = 1e-6;
-            let index = ((x >= 0.5 - epsilon) as usize)
-                + ((y >= 0.5 - epsilon) as usize * 2)
-                + ((z >= 0.5 - epsilon) as usize * 4);
-            let adjust_coord = |coord: f32| {
-                if coord >= 0.5 - epsilon {
-                    (coord - 0.5) * 2.0
-                } else {
-                    coord * 2.0
-                }
-            };
-            Self::get_voxel_recursive(
-                &children[index],
-                adjust_coord(x),
-                adjust_coord(y),
-                adjust_coord(z),
-            )
-        } else {
-            None
+
+        let mut id = NodePool::ROOT;
+        for level in 0..key.depth {
+            match self.nodes.child(id, key.child_index(level)) {
+                Some(child) => id = child,
+                // A leaf above the key's depth covers the whole cell.
+                None => break,
+            }
         }
+        self.nodes.get(id).voxel.as_ref()
     }
 
     /// Checks if there is a neighbor voxel at the specified direction from the given world coordinates at the specified depth.
@@ -295,99 +235,454 @@ impl SparseVoxelOctree {
         offset_z: i32,
         depth: u32,
     ) -> bool {
-        let aligned = self.normalize_to_voxel_at_depth(position, depth);
-        let voxel_count = 2_u32.pow(depth) as f32;
-        // Normalized voxel size is 1/voxel_count
-        let norm_voxel_size = 1.0 / voxel_count;
-
-        let neighbor = Vec3::new(
-            aligned.x + (offset_x as f32) * norm_voxel_size,
-            aligned.y + (offset_y as f32) * norm_voxel_size,
-            aligned.z + (offset_z as f32) * norm_voxel_size,
-        );
+        let key = self.key_at(position, depth);
+        self.has_neighbor_key(key, IVec3::new(offset_x, offset_y, offset_z))
+    }
 
-        // Convert the normalized neighbor coordinate back to world space
-        let half_size = self.size * 0.5;
-        let neighbor_world = neighbor * self.size - Vec3::splat(half_size);
+    /// Checks if the cell `offset` cells away from `key` (at the same depth) holds a voxel.
+    pub fn has_neighbor_key(&self, key: VoxelKey, offset: IVec3) -> bool {
+        let neighbor = key.offset(offset);
+        neighbor.is_valid() && self.get_voxel_at_key(neighbor).is_some()
+    }
 
-        if !self.contains(neighbor_world.x, neighbor_world.y, neighbor_world.z) {
-            return false;
+
+    /// Casts a ray and returns the first voxel it hits, ignoring hits farther than `max_distance`.
+    /// Children are visited front to back, so the walk stops at the first filled leaf.
+    pub fn raycast(&self, ray: &Ray, max_distance: Option<f32>) -> Option<RaycastHit<T>> {
+        let direction = ray.direction.normalize_or_zero();
+        if direction == Vec3::ZERO {
+            return None;
         }
+        let ray = Ray { origin: ray.origin, direction };
+        let max_distance = max_distance.unwrap_or(f32::INFINITY);
+
+        // Mirror child indices along the axes the ray travels backwards on,
+        // so visiting `i ^ mirror` for i in 0..8 is front-to-back order.
+        let mirror = (direction.x < 0.0) as usize
+            | ((direction.y < 0.0) as usize) << 1
+            | ((direction.z < 0.0) as usize) << 2;
+
+        let root_bounds = self.root_bounds();
+        let (id, key, t_enter, axis) =
+            self.raycast_recursive(NodePool::ROOT, VoxelKey::default(), &ray, &root_bounds, mirror, max_distance)?;
+
+        let distance = t_enter.max(0.0);
+        let point = ray.origin + direction * distance;
+        let normal = match axis {
+            Some(axis) if t_enter > 0.0 => {
+                let mut normal = Vec3::ZERO;
+                normal[axis] = -direction[axis].signum();
+                normal
+            }
+            _ => Vec3::ZERO,
+        };
 
-        self.get_voxel_at_world_coords(neighbor_world).is_some()
+        // Resolve the max-depth cell inside the (possibly merged) leaf that holds the hit point.
+        let shift = self.max_depth - key.depth;
+        let first_cell = key.position << shift;
+        let last_cell = first_cell + IVec3::splat((1 << shift) - 1);
+        let hit_key = VoxelKey::new(
+            self.key_at(point, self.max_depth).position.clamp(first_cell, last_cell),
+            self.max_depth,
+        );
+
+        Some(RaycastHit {
+            point,
+            distance,
+            normal,
+            key: hit_key,
+            center: self.key_center(hit_key),
+            adjacent_key: hit_key.offset(normal.as_ivec3()),
+            voxel: self.nodes.get(id).voxel?,
+        })
     }
 
+    /// Like `raycast`, but for a world-space ray against an octree placed by `transform`.
+    /// The ray is moved into octree space, and the hit's point, center, normal and distance are
+    /// mapped back to world space. `max_distance` is in world units.
+    pub fn raycast_world(&self, transform: &GlobalTransform, ray: &Ray, max_distance: Option<f32>) -> Option<RaycastHit<T>> {
+        let direction = ray.direction.normalize_or_zero();
+        let to_local = transform.affine().inverse();
+        let local_direction = to_local.transform_vector3(direction);
+        // Local units per world unit along the ray.
+        let scale = local_direction.length();
+        let local_ray = Ray {
+            origin: to_local.transform_point3(ray.origin),
+            direction: local_direction,
+        };
+
+        let hit = self.raycast(&local_ray, max_distance.map(|distance| distance * scale))?;
+        // Normals transform with the inverse transpose, which handles non-uniform scale.
+        let normal_matrix = Mat3::from(to_local.matrix3).transpose();
+        Some(RaycastHit {
+            point: transform.transform_point(hit.point),
+            distance: hit.distance / scale,
+            normal: (normal_matrix * hit.normal).normalize_or_zero(),
+            center: transform.transform_point(hit.center),
+            ..hit
+        })
+    }
 
-    /// Performs a raycast against the octree and returns the first intersected voxel.
-    pub fn raycast(&self, ray: &Ray) -> Option<(f32, f32, f32, u32, Vec3)> {
-        // Start from the root node
-        let half_size = self.size / 2.0;
-        let root_bounds = AABB {
-            min: Vec3::new(-half_size as f32, -half_size as f32, -half_size as f32),
-            max: Vec3::new(half_size as f32, half_size as f32, half_size as f32),
+    /// Casts an f64 world-space ray from `origin` against an octree placed by `transform`.
+    /// The octree is moved next to the origin before the f32 cast, so picking stays exact far
+    /// from the world origin. Positions of the hit are relative to `origin`; the keys are exact.
+    pub fn raycast_double(
+        &self,
+        transform: &DoubleTransform,
+        origin: DVec3,
+        direction: DVec3,
+        max_distance: Option<f64>,
+    ) -> Option<RaycastHit<T>> {
+        let relative = GlobalTransform::from(transform.relative_to(origin));
+        let ray = Ray {
+            origin: Vec3::ZERO,
+            direction: direction.as_vec3(),
         };
-        self.raycast_recursive(
-            &self.root,
-            ray,
-            &root_bounds,
-            0,
-        )
+        self.raycast_world(&relative, &ray, max_distance.map(|distance| distance as f32))
     }
 
+    /// Returns the first filled leaf along the ray inside node `id`,
+    /// with its key, entry distance and the axis of the entry face.
     fn raycast_recursive(
         &self,
-        node: &OctreeNode,
+        id: NodeId,
+        key: VoxelKey,
         ray: &Ray,
         bounds: &AABB,
-        depth: u32,
-    ) -> Option<(f32, f32, f32, u32, Vec3)> {
-        // Check if the ray intersects this node's bounding box
-        if let Some((t_enter, _, normal)) = self.ray_intersects_aabb_with_normal(ray, bounds) {
-            // If this is a leaf node and contains a voxel, return it
-            if node.is_leaf && node.voxel.is_some() {
-                // Compute the exact hit position
-                let hit_position = ray.origin + ray.direction * t_enter;
-
-                // Return the hit position along with depth and normal
-                return Some((
-                    hit_position.x as f32,
-                    hit_position.y as f32,
-                    hit_position.z as f32,
-                    depth,
-                    normal,
-                ));
-            }
+        mirror: usize,
+        max_distance: f32,
+    ) -> Option<(NodeId, VoxelKey, f32, Option<usize>)> {
+        let (t_enter, t_exit, axis) = Self::ray_slab(ray, bounds)?;
+        if t_exit < 0.0 || t_enter > max_distance {
+            return None;
+        }
 
-            // If the node has children, traverse them
-            if let Some(ref children) = node.children {
-                // For each child, compute its bounding box and recurse
-                let mut hits = Vec::new();
-                for (i, child) in children.iter().enumerate() {
-                    let child_bounds = self.compute_child_bounds(bounds, i);
-                    if let Some(hit) = self.raycast_recursive(child, ray, &child_bounds, depth + 1) {
-                        hits.push(hit);
-                    }
-                }
-                // Return the closest hit, if any
-                if !hits.is_empty() {
-                    hits.sort_by(|a, b| {
-                        let dist_a = ((a.0 as f32 - ray.origin.x).powi(2)
-                            + (a.1 as f32 - ray.origin.y).powi(2)
-                            + (a.2 as f32 - ray.origin.z).powi(2))
-                            .sqrt();
-                        let dist_b = ((b.0 as f32 - ray.origin.x).powi(2)
-                            + (b.1 as f32 - ray.origin.y).powi(2)
-                            + (b.2 as f32 - ray.origin.z).powi(2))
-                            .sqrt();
-                        dist_a.partial_cmp(&dist_b).unwrap()
-                    });
-                    return Some(hits[0]);
+        let node = self.nodes.get(id);
+        match node.children {
+            Some(first) => (0..8).map(|i| i ^ mirror).find_map(|i| {
+                let child_bounds = self.compute_child_bounds(bounds, i);
+                self.raycast_recursive(first + i as NodeId, key.child(i), ray, &child_bounds, mirror, max_distance)
+            }),
+            None if node.voxel.is_some() => Some((id, key, t_enter, axis)),
+            None => None,
+        }
+    }
+
+    /// Slab test returning the entry and exit distances and the axis of the entry face.
+    /// Axes the ray runs parallel to only reject the box if the origin lies outside its slab.
+    fn ray_slab(ray: &Ray, bounds: &AABB) -> Option<(f32, f32, Option<usize>)> {
+        let (mut t_enter, mut t_exit, mut axis) = (f32::NEG_INFINITY, f32::INFINITY, None);
+        for i in 0..3 {
+            let (origin, direction) = (ray.origin[i], ray.direction[i]);
+            if direction == 0.0 {
+                if origin < bounds.min[i] || origin > bounds.max[i] {
+                    return None;
                 }
+                continue;
             }
+            let t1 = (bounds.min[i] - origin) / direction;
+            let t2 = (bounds.max[i] - origin) / direction;
+            let (near, far) = if t1 <= t2 { (t1, t2) } else { (t2, t1) };
+            if near > t_enter {
+                t_enter = near;
+                axis = Some(i);
+            }
+            t_exit = t_exit.min(far);
         }
-
-        None
+        (t_enter <= t_exit).then_some((t_enter, t_exit, axis))
     }
     
 }
 
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+    use crate::systems::voxels::material::MaterialId;
+    use bevy::math::{DQuat, DVec3};
+    use crate::systems::double_transform::DoubleTransform;
+    use crate::systems::voxels::csg::Shape;
+    use crate::systems::voxels::structure::{NodePool, Ray, SparseVoxelOctree, Voxel, AABB, MAX_DEPTH};
+
+    #[test]
+    fn raycast_world_follows_the_transform() {
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        octree.insert(Vec3::new(2.5, 0.5, 0.5), Voxel::new(MaterialId(1)));
+        let transform = GlobalTransform::from(
+            Transform::from_xyz(10.0, -3.0, 4.0)
+                .with_rotation(Quat::from_rotation_y(std::f32::consts::FRAC_PI_2))
+                .with_scale(Vec3::splat(2.0)),
+        );
+
+        // The same ray in octree space and in world space hits the same cell.
+        let local_ray = Ray { origin: Vec3::new(-4.0, 0.5, 0.5), direction: Vec3::X };
+        let world_ray = Ray {
+            origin: transform.transform_point(local_ray.origin),
+            direction: transform.affine().transform_vector3(local_ray.direction),
+        };
+        let local = octree.raycast(&local_ray, None).unwrap();
+        let world = octree.raycast_world(&transform, &world_ray, None).unwrap();
+
+        assert_eq!(world.key, local.key);
+        assert_eq!(world.adjacent_key, local.adjacent_key);
+        assert!(world.point.distance(transform.transform_point(local.point)) < 1e-4);
+        assert!(world.center.distance(transform.transform_point(local.center)) < 1e-4);
+        assert!((world.distance - local.distance * 2.0).abs() < 1e-4);
+        // Local -X turns into world +Z under the quarter turn around Y.
+        assert!(world.normal.distance(Vec3::Z) < 1e-4);
+
+        // The distance limit is in world units.
+        assert!(octree.raycast_world(&transform, &world_ray, Some(11.0)).is_none());
+        assert!(octree.raycast_world(&transform, &world_ray, Some(13.0)).is_some());
+    }
+
+    #[test]
+    fn double_positions_stay_exact_far_away() {
+        // Ten thousand kilometres out, where f32 positions are a metre apart.
+        let far = DVec3::new(1.0e7, 250.0, -1.0e7);
+        let transform = DoubleTransform::from_translation(far).with_rotation(DQuat::from_rotation_y(0.3));
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+
+        let target = transform.transform_point(DVec3::new(2.5, 0.5, 0.5));
+        octree.insert_double(transform.inverse_transform_point(target), Voxel::new(MaterialId(1)));
+        assert_eq!(octree.get_voxel_at_world_coords(Vec3::new(2.5, 0.5, 0.5)), Some(&Voxel::new(MaterialId(1))));
+
+        // The camera looks at the voxel from ten metres away in f64 world space.
+        let camera = transform.transform_point(DVec3::new(-7.5, 0.5, 0.5));
+        let direction = (target - camera).normalize();
+        let hit = octree.raycast_double(&transform, camera, direction, None).unwrap();
+        assert_eq!(hit.key, octree.key_at(Vec3::new(2.5, 0.5, 0.5), octree.max_depth));
+        assert!((hit.distance - 9.5).abs() < 1e-4);
+        // Hit positions are relative to the camera.
+        let center = camera + hit.center.as_dvec3();
+        assert!(center.distance(transform.transform_point(octree.key_center_double(hit.key))) < 1e-4);
+
+        octree.remove_double(transform.inverse_transform_point(camera + hit.center.as_dvec3()));
+        assert!(octree.get_voxel_at_double(DVec3::new(2.5, 0.5, 0.5)).is_none());
+    }
+
+    #[test]
+    fn keys_are_computed_in_double() {
+        // A 2^22 m wide octree with metre cells. In f32, shifting by half the root size would
+        // round 2_000_000.9 up into the next cell.
+        let octree: SparseVoxelOctree = SparseVoxelOctree::new(22, 4_194_304.0, false, false, false);
+        let a = octree.key_at_double(DVec3::new(2_000_000.25, 0.0, 0.0), 22);
+        let b = octree.key_at_double(DVec3::new(2_000_000.9, 0.0, 0.0), 22);
+        let c = octree.key_at_double(DVec3::new(2_000_001.25, 0.0, 0.0), 22);
+        assert_eq!(a, b);
+        assert_eq!(c.position.x, a.position.x + 1);
+        assert_eq!(octree.key_center_double(a).x, 2_000_000.5);
+    }
+
+    #[test]
+    fn expansion_stops_at_max_depth() {
+        let mut octree = SparseVoxelOctree::new(28, 2.0, false, false, false);
+        let voxel = Voxel::new(MaterialId(1));
+        // Two doublings are allowed, a third would pass MAX_DEPTH.
+        assert!(octree.insert_double(DVec3::new(3.5, 0.0, 0.0), voxel));
+        assert_eq!(octree.max_depth, MAX_DEPTH);
+        assert!(!octree.insert_double(DVec3::new(1.0e9, 0.0, 0.0), voxel));
+        assert!(!octree.insert_double(DVec3::new(f64::NAN, 0.0, 0.0), voxel));
+        assert!(!octree.insert(Vec3::new(0.0, f32::INFINITY, 0.0), voxel));
+        assert_eq!((octree.max_depth, octree.size), (MAX_DEPTH, 8.0));
+        assert_eq!(octree.iter().count(), 1);
+    }
+
+    #[test]
+    fn equal_children_merge_into_their_parent() {
+        let mut octree = SparseVoxelOctree::new(3, 8.0, false, false, false);
+        let stone = Voxel::new(MaterialId(1));
+        let cells = |min: i32, max: i32| {
+            (min..max).flat_map(move |x| (min..max).flat_map(move |y| (min..max).map(move |z| Vec3::new(x as f32, y as f32, z as f32) + 0.5)))
+        };
+
+        // Seven of eight cells stay separate, the eighth merges them into one leaf a level up.
+        for position in cells(0, 2).take(7) {
+            octree.insert(position, stone);
+        }
+        assert_eq!(octree.iter().count(), 7);
+        octree.insert(Vec3::new(1.5, 1.5, 1.5), stone);
+        assert_eq!(octree.traverse(), vec![(Vec3::new(1.0, 1.0, 1.0), stone, 2)]);
+
+        // A different voxel splits the merged leaf again and keeps it from merging.
+        octree.insert(Vec3::new(0.5, 0.5, 0.5), Voxel::new(MaterialId(2)));
+        assert_eq!(octree.iter().count(), 8);
+        octree.insert(Vec3::new(0.5, 0.5, 0.5), stone);
+        assert_eq!(octree.iter().count(), 1);
+
+        // Merges cascade upwards once the whole octant is filled.
+        for position in cells(0, 4) {
+            octree.insert(position, stone);
+        }
+        assert_eq!(octree.traverse(), vec![(Vec3::new(2.0, 2.0, 2.0), stone, 1)]);
+        assert_eq!(octree.nodes.len(), 9);
+    }
+
+    #[test]
+    fn region_edits_reject_unreachable_bounds() {
+        let mut octree = SparseVoxelOctree::new(26, 8.0, false, false, false);
+        let voxel = Voxel::new(MaterialId(1));
+        let nan = AABB::new(Vec3::new(f32::NAN, 0.0, 0.0), Vec3::ONE);
+        let infinite = AABB::new(Vec3::ZERO, Vec3::new(1.0, f32::INFINITY, 1.0));
+        // The root can double four more times, to 128 m.
+        let too_far = AABB::new(Vec3::ZERO, Vec3::new(1.0, 1.0, 65.0));
+        for bounds in [nan, infinite, too_far] {
+            assert!(!octree.fill_aabb(&bounds, voxel));
+            assert!(!octree.union_shape(&Shape::Box(bounds), voxel));
+        }
+        assert_eq!((octree.max_depth, octree.size), (26, 8.0));
+        assert_eq!(octree.iter().count(), 0);
+
+        assert!(octree.fill_aabb(&AABB::new(Vec3::new(0.0, 0.0, 63.0), Vec3::new(1.0, 1.0, 64.0)), voxel));
+        assert_eq!((octree.max_depth, octree.size), (MAX_DEPTH, 128.0));
+    }
+
+    #[test]
+    fn root_shrinks_back_after_removing_everything() {
+        let mut octree = SparseVoxelOctree::new(3, 8.0, false, false, false);
+        let voxel = Voxel::new(MaterialId(1));
+        octree.insert(Vec3::new(0.5, 0.5, 0.5), voxel);
+        // Two doublings: 8 m -> 32 m.
+        octree.insert(Vec3::new(-13.5, 9.5, 2.5), voxel);
+        assert_eq!((octree.max_depth, octree.size), (5, 32.0));
+
+        octree.remove(Vec3::new(-13.5, 9.5, 2.5));
+        octree.remove(Vec3::new(0.5, 0.5, 0.5));
+        assert_eq!((octree.max_depth, octree.size), (octree.base_depth, 8.0));
+        assert_eq!(octree.nodes.len(), 1);
+        assert!(octree.nodes.get(NodePool::ROOT).is_empty());
+    }
+
+    #[test]
+    fn voxels_keep_their_position_across_grow_and_shrink() {
+        let mut octree = SparseVoxelOctree::new(3, 8.0, false, false, false);
+        let placed: Vec<(Vec3, Voxel)> = [(-3.5, -3.5, -3.5), (3.5, 3.5, 3.5), (0.5, -1.5, 2.5), (-0.5, 0.5, -0.5)]
+            .into_iter()
+            .enumerate()
+            .map(|(i, (x, y, z))| (Vec3::new(x, y, z), Voxel::new(MaterialId(i as u16 + 1))))
+            .collect();
+        for &(position, voxel) in &placed {
+            octree.insert(position, voxel);
+        }
+        // A filled octant merges into one leaf, which is moved whole.
+        octree.fill_aabb(&AABB::new(Vec3::new(0.0, -4.0, -4.0), Vec3::new(4.0, 0.0, 0.0)), Voxel::new(MaterialId(9)));
+        let before = octree.traverse();
+
+        let far = Vec3::new(30.5, -20.5, 0.5);
+        octree.insert(far, Voxel::new(MaterialId(7)));
+        assert_eq!(octree.max_depth, 6);
+        for &(position, voxel) in &placed {
+            assert_eq!(octree.get_voxel_at_world_coords(position), Some(&voxel));
+        }
+        assert_eq!(octree.get_voxel_at_world_coords(Vec3::new(3.5, -0.5, -3.5)), Some(&Voxel::new(MaterialId(9))));
+
+        octree.remove(far);
+        assert_eq!(octree.max_depth, 3);
+        assert_eq!(octree.traverse(), before);
+    }
+
+    #[test]
+    fn shrink_is_refused_while_outer_cells_are_filled() {
+        let mut octree = SparseVoxelOctree::new(3, 8.0, false, false, false);
+        let voxel = Voxel::new(MaterialId(1));
+        octree.insert(Vec3::new(0.5, 0.5, 0.5), voxel);
+        octree.insert(Vec3::new(7.5, 0.5, 0.5), voxel);
+        assert_eq!(octree.max_depth, 4);
+
+        // Cells outside the inner half, here the one at 7.5 m, keep the root from shrinking.
+        let nodes = octree.nodes.len();
+        assert!(!octree.shrink_root());
+        assert_eq!((octree.max_depth, octree.size, octree.nodes.len()), (4, 16.0, nodes));
+        octree.insert(Vec3::new(-7.5, -7.5, -7.5), voxel);
+        octree.remove(Vec3::new(7.5, 0.5, 0.5));
+        assert_eq!(octree.max_depth, 4);
+
+        octree.remove(Vec3::new(-7.5, -7.5, -7.5));
+        assert_eq!(octree.max_depth, 3);
+        assert_eq!(octree.get_voxel_at_world_coords(Vec3::new(0.5, 0.5, 0.5)), Some(&voxel));
+    }
+
+    #[test]
+    fn raycast_returns_the_nearest_voxel() {
+        let mut octree = SparseVoxelOctree::new(3, 8.0, false, false, false);
+        for (x, material) in [(2.5, 1), (-3.5, 2), (-0.5, 3)] {
+            octree.insert(Vec3::new(x, 0.5, 0.5), Voxel::new(MaterialId(material)));
+        }
+
+        let hit = octree.raycast(&Ray { origin: Vec3::new(-10.0, 0.5, 0.5), direction: Vec3::X }, None).unwrap();
+        assert_eq!(hit.voxel, Voxel::new(MaterialId(2)));
+        assert_eq!((hit.distance, hit.point), (6.0, Vec3::new(-4.0, 0.5, 0.5)));
+        assert_eq!(hit.key, octree.key_at(Vec3::new(-3.5, 0.5, 0.5), 3));
+
+        // Unnormalized directions are fine, and the far side is hit first from the other end.
+        let hit = octree.raycast(&Ray { origin: Vec3::new(10.0, 0.5, 0.5), direction: Vec3::new(-3.0, 0.0, 0.0) }, None).unwrap();
+        assert_eq!((hit.voxel, hit.distance), (Voxel::new(MaterialId(1)), 7.0));
+
+        // Rays passing between voxels miss.
+        assert!(octree.raycast(&Ray { origin: Vec3::new(-10.0, 1.5, 0.5), direction: Vec3::X }, None).is_none());
+        assert!(octree.raycast(&Ray { origin: Vec3::new(-10.0, 0.5, 0.5), direction: Vec3::ZERO }, None).is_none());
+    }
+
+    #[test]
+    fn raycast_stops_at_max_distance() {
+        let mut octree = SparseVoxelOctree::new(3, 8.0, false, false, false);
+        octree.insert(Vec3::new(2.5, 0.5, 0.5), Voxel::new(MaterialId(1)));
+        let ray = Ray { origin: Vec3::new(-10.0, 0.5, 0.5), direction: Vec3::X };
+        assert!(octree.raycast(&ray, Some(11.5)).is_none());
+        assert_eq!(octree.raycast(&ray, Some(12.5)).map(|hit| hit.distance), Some(12.0));
+    }
+
+    #[test]
+    fn raycast_reports_the_hit_face() {
+        let mut octree = SparseVoxelOctree::new(3, 8.0, false, false, false);
+        // A merged 2x2x2 leaf; hits resolve to the max-depth cell behind the face.
+        octree.fill_aabb(&AABB::new(Vec3::ZERO, Vec3::splat(2.0)), Voxel::new(MaterialId(1)));
+        let target = Vec3::new(1.5, 0.5, 1.5);
+
+        for normal in [Vec3::X, Vec3::NEG_X, Vec3::Y, Vec3::NEG_Y, Vec3::Z, Vec3::NEG_Z] {
+            // Aim at the cell through the face pointing along `normal`.
+            let face_cell = Vec3::ONE + normal * 0.5 + (target - Vec3::ONE) * (Vec3::ONE - normal.abs());
+            let ray = Ray { origin: face_cell + normal * 2.0, direction: -normal };
+            let hit = octree.raycast(&ray, None).unwrap();
+            assert_eq!(hit.normal, normal);
+            assert_eq!(hit.key, octree.key_at(face_cell, 3), "{normal}");
+            assert_eq!(hit.adjacent_key, hit.key.offset(normal.as_ivec3()));
+            assert_eq!(octree.key_center(hit.adjacent_key), octree.key_center(hit.key) + normal);
+            assert_eq!(hit.distance, 1.5);
+        }
+    }
+
+    #[test]
+    fn raycast_from_inside_the_root() {
+        let mut octree = SparseVoxelOctree::new(3, 8.0, false, false, false);
+        octree.insert(Vec3::new(0.5, 0.5, 0.5), Voxel::new(MaterialId(1)));
+
+        // Starting in empty space inside the root behaves like any other ray.
+        let hit = octree.raycast(&Ray { origin: Vec3::new(-1.5, 0.5, 0.5), direction: Vec3::X }, None).unwrap();
+        assert_eq!((hit.distance, hit.normal), (1.5, Vec3::NEG_X));
+
+        // Starting inside a voxel hits it at distance zero, without a face.
+        let origin = Vec3::new(0.25, 0.5, 0.75);
+        let hit = octree.raycast(&Ray { origin, direction: Vec3::new(1.0, 1.0, 0.0) }, None).unwrap();
+        assert_eq!((hit.distance, hit.point, hit.normal), (0.0, origin, Vec3::ZERO));
+        assert_eq!(hit.adjacent_key, hit.key);
+
+        // Voxels behind the origin are not hit.
+        assert!(octree.raycast(&Ray { origin: Vec3::new(1.5, 0.5, 0.5), direction: Vec3::X }, None).is_none());
+    }
+
+    #[test]
+    fn voxels_placed_on_the_root_face_grow_the_root() {
+        // What a left click does: place a voxel in front of the hit face, here outside the root.
+        let mut octree = SparseVoxelOctree::new(3, 8.0, false, false, false);
+        octree.insert(Vec3::new(3.5, 0.5, 0.5), Voxel::new(MaterialId(1)));
+        let hit = octree.raycast(&Ray { origin: Vec3::new(10.0, 0.5, 0.5), direction: Vec3::NEG_X }, None).unwrap();
+        assert!(!hit.adjacent_key.is_valid());
+
+        assert!(octree.insert_double(octree.key_center_double(hit.adjacent_key), Voxel::new(MaterialId(2))));
+        assert_eq!(octree.max_depth, 4);
+        assert_eq!(octree.get_voxel_at_world_coords(Vec3::new(4.5, 0.5, 0.5)), Some(&Voxel::new(MaterialId(2))));
+        assert_eq!(octree.get_voxel_at_world_coords(Vec3::new(3.5, 0.5, 0.5)), Some(&Voxel::new(MaterialId(1))));
+    }
+}
+
@@ -0,0 +1,310 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use bevy::prelude::*;
+use crate::systems::voxels::csg::Shape;
+use crate::systems::voxels::iter::VoxelEntry;
+use crate::systems::voxels::region::{Containment, Region};
+use crate::systems::voxels::structure::{NodeId, NodePool, SparseVoxelOctree, VoxelData, VoxelKey, AABB};
+
+/// Node waiting in the nearest-voxel search, ordered so the closest cell is popped first.
+struct Candidate {
+    distance_sq: f32,
+    id: NodeId,
+    key: VoxelKey,
+    bounds: AABB,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.distance_sq == other.distance_sq
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Reversed: BinaryHeap is a max-heap.
+        other.distance_sq.total_cmp(&self.distance_sq)
+    }
+}
+
+impl<T: VoxelData> SparseVoxelOctree<T> {
+    /// Returns the filled leaves whose cell overlaps `aabb`.
+    pub fn query_aabb(&self, aabb: &AABB) -> Vec<VoxelEntry<'_, T>> {
+        self.query_region(aabb)
+    }
+
+    /// Returns the filled leaves whose cell overlaps the sphere.
+    pub fn query_sphere(&self, center: Vec3, radius: f32) -> Vec<VoxelEntry<'_, T>> {
+        self.query_region(&Shape::Sphere { center, radius })
+    }
+
+    /// Returns the filled leaves whose cell overlaps `region`. Subtrees outside it are skipped.
+    /// Merged leaves are returned as a whole, even if the region only covers part of them.
+    pub fn query_region(&self, region: &impl Region) -> Vec<VoxelEntry<'_, T>> {
+        let mut entries = Vec::new();
+        self.query_recursive(NodePool::ROOT, VoxelKey::default(), self.root_bounds(), region, &mut entries);
+        entries
+    }
+
+    /// Finds the filled leaf closest to `point`, searching no farther than `max_distance`.
+    /// Returns the leaf and its distance; the distance is zero if `point` lies inside it.
+    pub fn nearest_voxel(&self, point: Vec3, max_distance: f32) -> Option<(VoxelEntry<'_, T>, f32)> {
+        let max_distance_sq = max_distance * max_distance;
+        let distance_sq = |bounds: &AABB| point.clamp(bounds.min, bounds.max).distance_squared(point);
+
+        let root_bounds = self.root_bounds();
+        let mut heap = BinaryHeap::new();
+        heap.push(Candidate {
+            distance_sq: distance_sq(&root_bounds),
+            id: NodePool::ROOT,
+            key: VoxelKey::default(),
+            bounds: root_bounds,
+        });
+
+        // Best-first: cells come out in order of their distance, so the first filled leaf wins.
+        while let Some(candidate) = heap.pop() {
+            if candidate.distance_sq > max_distance_sq {
+                break;
+            }
+            let node = self.nodes.get(candidate.id);
+            match node.children {
+                Some(first) => {
+                    for i in 0..8 {
+                        let bounds = self.compute_child_bounds(&candidate.bounds, i);
+                        let child = first + i as NodeId;
+                        let child_node = self.nodes.get(child);
+                        if child_node.is_empty() {
+                            continue;
+                        }
+                        heap.push(Candidate {
+                            distance_sq: distance_sq(&bounds),
+                            id: child,
+                            key: candidate.key.child(i),
+                            bounds,
+                        });
+                    }
+                }
+                None => {
+                    if let Some(entry) = self.entry(candidate.id, candidate.key, &candidate.bounds) {
+                        return Some((entry, candidate.distance_sq.sqrt()));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Counts the filled cells at max depth inside `region`.
+    /// Partially covered cells count if the region contains them (by default: their center),
+    /// matching the cells `set_region` would touch.
+    pub fn count_in_region(&self, region: &impl Region) -> u128 {
+        self.count_recursive(NodePool::ROOT, self.root_bounds(), 0, region)
+    }
+
+    fn query_recursive<'a>(
+        &'a self,
+        id: NodeId,
+        key: VoxelKey,
+        bounds: AABB,
+        region: &impl Region,
+        entries: &mut Vec<VoxelEntry<'a, T>>,
+    ) {
+        let node = self.nodes.get(id);
+        if node.is_empty() || region.classify(&bounds) == Containment::Outside {
+            return;
+        }
+        match node.children {
+            Some(first) => {
+                for i in 0..8 {
+                    let child_bounds = self.compute_child_bounds(&bounds, i);
+                    self.query_recursive(first + i as NodeId, key.child(i), child_bounds, region, entries);
+                }
+            }
+            None => entries.extend(self.entry(id, key, &bounds)),
+        }
+    }
+
+    fn count_recursive(&self, id: NodeId, bounds: AABB, depth: u32, region: &impl Region) -> u128 {
+        let node = self.nodes.get(id);
+        if node.is_empty() {
+            return 0;
+        }
+        match region.classify(&bounds) {
+            Containment::Outside => 0,
+            Containment::Inside if node.is_leaf() => self.cells_in_leaf(depth),
+            _ => match node.children {
+                Some(first) => (0..8)
+                    .map(|i| {
+                        let child_bounds = self.compute_child_bounds(&bounds, i);
+                        self.count_recursive(first + i as NodeId, child_bounds, depth + 1, region)
+                    })
+                    .sum(),
+                // A partially covered leaf is counted cell by cell.
+                None => self.count_leaf_cells(bounds, depth, region),
+            },
+        }
+    }
+
+    /// Counts the cells at max depth of a filled leaf that belong to `region`.
+    fn count_leaf_cells(&self, bounds: AABB, depth: u32, region: &impl Region) -> u128 {
+        if depth >= self.max_depth {
+            return region.contains_cell(&bounds) as u128;
+        }
+        (0..8)
+            .map(|i| {
+                let child_bounds = self.compute_child_bounds(&bounds, i);
+                match region.classify(&child_bounds) {
+                    Containment::Outside => 0,
+                    Containment::Inside => self.cells_in_leaf(depth + 1),
+                    Containment::Partial => self.count_leaf_cells(child_bounds, depth + 1, region),
+                }
+            })
+            .sum()
+    }
+
+    /// Collects the keys at max depth under `key` of the cells that belong to `region`.
+    /// Only children overlapping the region are visited, so a small region inside a large
+    /// merged leaf costs as much as the cells it covers.
+    pub fn region_cell_keys(&self, key: VoxelKey, region: &impl Region, keys: &mut Vec<VoxelKey>) {
+        let bounds = self.key_bounds(key);
+        if key.depth >= self.max_depth {
+            if region.contains_cell(&bounds) {
+                keys.push(key);
+            }
+        } else if region.classify(&bounds) != Containment::Outside {
+            for i in 0..8 {
+                self.region_cell_keys(key.child(i), region, keys);
+            }
+        }
+    }
+
+    /// Builds the iterator entry for a filled leaf.
+    fn entry(&self, id: NodeId, key: VoxelKey, bounds: &AABB) -> Option<VoxelEntry<'_, T>> {
+        let voxel = self.nodes.get(id).voxel.as_ref()?;
+        Some(VoxelEntry {
+            position: bounds.center(),
+            voxel,
+            key,
+            depth: key.depth,
+            size: bounds.size().x,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::systems::voxels::material::MaterialId;
+    use crate::systems::voxels::structure::{Voxel, MAX_DEPTH};
+    use super::*;
+
+    fn voxel(material: u16) -> Voxel {
+        Voxel::new(MaterialId(material))
+    }
+
+    /// Three single voxels and a merged 4 m leaf in an octree with 1 m cells.
+    fn octree() -> SparseVoxelOctree {
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        octree.insert(Vec3::new(0.5, 0.5, 0.5), voxel(1));
+        octree.insert(Vec3::new(3.5, 0.5, 0.5), voxel(2));
+        octree.fill_aabb(&AABB::new(Vec3::splat(-8.0), Vec3::splat(-4.0)), voxel(3));
+        octree.insert(Vec3::new(6.5, 6.5, 6.5), voxel(4));
+        octree
+    }
+
+    fn materials(entries: &[VoxelEntry<'_>]) -> Vec<u16> {
+        let mut materials: Vec<u16> = entries.iter().map(|entry| entry.voxel.material.0).collect();
+        materials.sort_unstable();
+        materials
+    }
+
+    #[test]
+    fn queries_return_overlapping_leaves() {
+        let octree = octree();
+        assert_eq!(materials(&octree.query_aabb(&AABB::new(Vec3::ZERO, Vec3::ONE))), vec![1]);
+        assert_eq!(materials(&octree.query_aabb(&AABB::new(Vec3::new(0.5, 0.0, 0.0), Vec3::new(3.5, 1.0, 1.0)))), vec![1, 2]);
+        assert!(octree.query_aabb(&AABB::new(Vec3::new(1.0, 0.0, 0.0), Vec3::new(3.0, 1.0, 1.0))).is_empty());
+
+        // The merged leaf is returned whole.
+        let entries = octree.query_aabb(&AABB::new(Vec3::splat(-4.5), Vec3::splat(-3.0)));
+        assert_eq!(materials(&entries), vec![3]);
+        assert_eq!(entries[0].bounds(), AABB::new(Vec3::splat(-8.0), Vec3::splat(-4.0)));
+        assert_eq!(entries[0].depth, 2);
+
+        assert_eq!(materials(&octree.query_sphere(Vec3::new(2.0, 0.5, 0.5), 1.2)), vec![1, 2]);
+        assert!(octree.query_sphere(Vec3::new(2.0, 0.5, 0.5), 0.9).is_empty());
+        assert_eq!(materials(&octree.query_sphere(Vec3::splat(-2.0), 3.5)), vec![1, 3]);
+
+        let capsule = Shape::Capsule { start: Vec3::new(0.5, 0.5, 0.5), end: Vec3::splat(6.5), radius: 0.5 };
+        assert_eq!(materials(&octree.query_region(&capsule)), vec![1, 4]);
+    }
+
+    #[test]
+    fn nearest_voxel_finds_the_closest_leaf() {
+        let octree = octree();
+        let nearest = |point: Vec3, max_distance: f32| {
+            octree.nearest_voxel(point, max_distance).map(|(entry, distance)| (entry.voxel.material.0, distance))
+        };
+
+        assert_eq!(nearest(Vec3::new(2.25, 0.5, 0.5), 10.0), Some((2, 0.75)));
+        assert_eq!(nearest(Vec3::new(1.5, 0.5, 0.5), 10.0), Some((1, 0.5)));
+        assert_eq!(nearest(Vec3::new(0.25, 0.5, 0.75), 10.0), Some((1, 0.0)));
+
+        // A merged leaf is measured by its full bounds, not by its center.
+        let (entry, distance) = octree.nearest_voxel(Vec3::splat(-3.0), 10.0).unwrap();
+        assert_eq!((entry.voxel.material.0, entry.size), (3, 4.0));
+        assert!((distance - 3.0_f32.sqrt()).abs() < 1e-6);
+
+        // Nothing within reach.
+        assert_eq!(nearest(Vec3::new(6.5, 3.0, 6.5), 2.5), None);
+        assert_eq!(nearest(Vec3::new(6.5, 3.0, 6.5), 3.5), Some((4, 3.0)));
+        assert_eq!(SparseVoxelOctree::<Voxel>::new(4, 16.0, false, false, false).nearest_voxel(Vec3::ZERO, 100.0).map(|(_, distance)| distance), None);
+    }
+
+    #[test]
+    fn counts_match_brute_force() {
+        let mut octree = octree();
+        octree.union_shape(&Shape::Sphere { center: Vec3::new(2.3, -1.7, 3.1), radius: 3.4 }, voxel(5));
+        let regions = [
+            Shape::Box(AABB::new(Vec3::new(-6.3, -7.9, -5.2), Vec3::new(2.6, 0.4, 1.1))),
+            Shape::Sphere { center: Vec3::new(-1.2, -3.3, 0.7), radius: 4.6 },
+            Shape::Capsule { start: Vec3::new(-7.0, -6.0, -5.5), end: Vec3::new(4.0, 1.0, 2.0), radius: 1.7 },
+            Shape::Box(octree.root_bounds()),
+        ];
+        for region in regions {
+            let mut expected = 0;
+            for x in -8..8 {
+                for y in -8..8 {
+                    for z in -8..8 {
+                        let cell = AABB::new(Vec3::new(x as f32, y as f32, z as f32), Vec3::new(x as f32 + 1.0, y as f32 + 1.0, z as f32 + 1.0));
+                        if octree.get_voxel_at_world_coords(cell.center()).is_some() && region.contains_cell(&cell) {
+                            expected += 1;
+                        }
+                    }
+                }
+            }
+            assert!(expected > 0);
+            assert_eq!(octree.count_in_region(&region), expected, "{region:?}");
+        }
+        assert_eq!(octree.count_in_region(&Shape::Box(octree.root_bounds())), octree.stats().voxel_count);
+    }
+
+    #[test]
+    fn counts_merged_leaves_at_max_depth() {
+        let mut octree = SparseVoxelOctree::new(MAX_DEPTH, 16.0, false, false, false);
+        let root = octree.root_bounds();
+        octree.fill_aabb(&root, voxel(1));
+
+        assert_eq!(octree.count_in_region(&Shape::Box(root)), 1 << (3 * MAX_DEPTH));
+        // The leaf is only partially covered, so it is counted child by child.
+        let half = Shape::Box(AABB::new(root.min, Vec3::new(0.0, root.max.y, root.max.z)));
+        assert_eq!(octree.count_in_region(&half), 1 << (3 * MAX_DEPTH - 1));
+    }
+}
@@ -0,0 +1,126 @@
+use bevy::prelude::*;
+use crate::systems::voxels::events::VoxelChange;
+use crate::systems::voxels::structure::{NodeId, NodePool, SparseVoxelOctree, VoxelData, AABB};
+
+/// How a region relates to the bounds of an octree cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Containment {
+    /// The cell does not touch the region.
+    Outside,
+    /// The cell is partly covered by the region.
+    Partial,
+    /// The cell lies completely inside the region.
+    Inside,
+}
+
+/// A volume that can be tested against octree cells, used by the top-down region edits.
+pub trait Region {
+    /// World-space box enclosing the whole region.
+    fn bounds(&self) -> AABB;
+
+    /// Classifies a cell against the region.
+    fn classify(&self, cell: &AABB) -> Containment;
+
+    /// Decides whether a partially covered cell at max depth belongs to the region.
+    /// By default a cell belongs to the region if its center does.
+    fn contains_cell(&self, cell: &AABB) -> bool {
+        self.contains_point(cell.center())
+    }
+
+    fn contains_point(&self, point: Vec3) -> bool;
+}
+
+impl Region for AABB {
+    fn bounds(&self) -> AABB {
+        *self
+    }
+
+    fn classify(&self, cell: &AABB) -> Containment {
+        if !self.intersects(cell) {
+            Containment::Outside
+        } else if self.contains_aabb(cell) {
+            Containment::Inside
+        } else {
+            Containment::Partial
+        }
+    }
+
+    fn contains_point(&self, point: Vec3) -> bool {
+        AABB::contains_point(self, point)
+    }
+}
+
+impl<T: VoxelData> SparseVoxelOctree<T> {
+    /// Fills every cell inside `aabb` with `voxel`, expanding the root if the box reaches outside it.
+    /// Cells at max depth that the box only partly covers are filled if their center is inside.
+    /// Returns false, and changes nothing, if the root cannot grow to contain the box.
+    pub fn fill_aabb(&mut self, aabb: &AABB, voxel: T) -> bool {
+        if !self.ensure_contains(aabb) {
+            return false;
+        }
+        self.set_region(aabb, Some(voxel));
+        true
+    }
+
+    /// Removes every voxel inside `aabb`. Parts of the box outside the root are ignored.
+    pub fn clear_aabb(&mut self, aabb: &AABB) {
+        self.set_region(aabb, None);
+    }
+
+    /// Sets all cells covered by `region` to `voxel` (or empties them for `None`).
+    /// Works top-down: nodes fully inside the region are replaced as a whole,
+    /// only partially covered nodes are subdivided. Sends a single `RegionChanged` event.
+    pub fn set_region(&mut self, region: &impl Region, voxel: Option<T>) {
+        let root_bounds = self.root_bounds();
+        let Some(bounds) = region.bounds().intersection(&root_bounds) else {
+            return;
+        };
+        // One capture serves both the undo record and the event.
+        let record = self.capture_edit(bounds);
+        let old = record.voxels.clone();
+        self.push_record(record);
+        self.push_change(VoxelChange::Region { bounds, old, new: voxel });
+
+        self.set_region_recursive(NodePool::ROOT, root_bounds, 0, region, voxel);
+        if voxel.is_none() {
+            while self.shrink_root() {}
+        }
+        self.check_edit("set_region");
+    }
+
+    pub(crate) fn set_region_recursive(
+        &mut self,
+        id: NodeId,
+        bounds: AABB,
+        depth: u32,
+        region: &impl Region,
+        voxel: Option<T>,
+    ) {
+        // Nothing to do if this cell already holds the target value.
+        let node = self.nodes.get(id);
+        if node.is_leaf() && node.voxel == voxel {
+            return;
+        }
+
+        let inside = match region.classify(&bounds) {
+            Containment::Outside => false,
+            Containment::Inside => true,
+            Containment::Partial if depth >= self.max_depth => region.contains_cell(&bounds),
+            Containment::Partial => {
+                // Subdivide and let the children decide, then merge back if possible.
+                let first = self.nodes.split(id);
+                for i in 0..8 {
+                    let child_bounds = self.compute_child_bounds(&bounds, i);
+                    self.set_region_recursive(first + i as NodeId, child_bounds, depth + 1, region, voxel);
+                }
+                self.nodes.try_merge(id);
+                return;
+            }
+        };
+
+        if inside {
+            self.nodes.collapse(id);
+            self.nodes.get_mut(id).voxel = voxel;
+        }
+    }
+}
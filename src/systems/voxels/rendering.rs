@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use bevy::color::palettes::basic::BLUE;
 use bevy::prelude::*;
 use bevy::utils::info;
@@ -8,113 +8,155 @@ use bevy_render::render_resource::Face;
 use log::info;
 use crate::systems::ui_system::SpeedDisplay;
 use crate::systems::voxels::octree;
-use crate::systems::voxels::structure::{SparseVoxelOctree, NEIGHBOR_OFFSETS};
+use crate::systems::voxels::events::{RegionChanged, VoxelInserted, VoxelRemoved};
+use crate::systems::voxels::material::{MaterialId, MaterialPalette};
+use crate::systems::voxels::structure::{SparseVoxelOctree, VoxelKey, AABB, NEIGHBOR_OFFSETS};
 
 #[derive(Component)]
 pub struct VoxelTerrainMarker {}
 
 
+#[allow(clippy::too_many_arguments)]
 pub fn render(
     mut commands: Commands,
-    mut query: Query<&mut SparseVoxelOctree>,
-    render_object_query: Query<Entity, With<VoxelTerrainMarker>>,
+    query: Query<(Entity, &SparseVoxelOctree)>,
+    render_object_query: Query<(Entity, &Parent), With<VoxelTerrainMarker>>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
+    palette: Res<MaterialPalette>,
+    mut inserted: EventReader<VoxelInserted>,
+    mut removed: EventReader<VoxelRemoved>,
+    mut region_changed: EventReader<RegionChanged>,
 ) {
+    // Octrees that were edited since the last run.
+    let changed: HashSet<Entity> = inserted
+        .read()
+        .map(|event| event.entity)
+        .chain(removed.read().map(|event| event.entity))
+        .chain(region_changed.read().map(|event| event.entity))
+        .collect();
+
+    for (entity, octree) in query.iter() {
+        // Only update when edited
+        if changed.contains(&entity) {
+            // Remove the old render objects of this octree
+            for (render_entity, parent) in render_object_query.iter() {
+                if parent.get() == entity {
+                    commands.entity(render_entity).despawn_recursive();
+                }
+            }
 
-
-    for mut octree in query.iter_mut() {
-        // Only update when marked dirty
-        if !octree.dirty.is_empty() {
-            // Remove old render objects
-            for entity in render_object_query.iter() {
-                commands.entity(entity).despawn();
+            for (material_id, mesh) in generate_meshes(octree, None) {
+                let cube_handle = meshes.add(mesh);
+
+                // Resolve the voxel material through the palette
+                let material = materials.add(StandardMaterial {
+                    cull_mode: Some(Face::Back),
+                    ..palette.resolve(material_id).to_standard_material()
+                });
+
+
+                // Spawn the mesh as a child, so it follows the octree's transform
+                commands.entity(entity).with_children(|parent| {
+                    parent.spawn((
+                        PbrBundle {
+                            mesh: Mesh3d::from(cube_handle),
+                            material: MeshMaterial3d::from(material),
+                            transform: Transform::IDENTITY,
+                            ..Default::default()
+                        },
+                        VoxelTerrainMarker {},
+                    ));
+                });
             }
+        }
+    }
+}
 
-            // Get the voxel centers (world positions), color, and depth.
-            let voxels = octree.traverse();
-
-            // Debug: Log the number of voxels traversed.
-            info!("Voxel count: {}", voxels.len());
-
-            let mut voxel_meshes = Vec::new();
-
-            for (world_position, _color, depth) in voxels {
-                // Get the size of the voxel at the current depth.
-                let voxel_size = octree.get_spacing_at_depth(depth);
-
-                // The traverse method already returns the voxel center in world space.
-
-                // For each neighbor direction, check if this voxel face is exposed.
-                for &(dx, dy, dz) in NEIGHBOR_OFFSETS.iter() {
-                    // Pass the world-space voxel center directly.
-                    if !octree.has_neighbor(world_position, dx as i32, dy as i32, dz as i32, depth) {
-
-                        // Determine face normal and the local offset for the face.
-                        let (normal, offset) = match (dx, dy, dz) {
-                            (-1.0, 0.0, 0.0) => (
-                                Vec3::new(-1.0, 0.0, 0.0),
-                                Vec3::new(-voxel_size / 2.0, 0.0, 0.0),
-                            ),
-                            (1.0, 0.0, 0.0) => (
-                                Vec3::new(1.0, 0.0, 0.0),
-                                Vec3::new(voxel_size / 2.0, 0.0, 0.0),
-                            ),
-                            (0.0, -1.0, 0.0) => (
-                                Vec3::new(0.0, -1.0, 0.0),
-                                Vec3::new(0.0, -voxel_size / 2.0, 0.0),
-                            ),
-                            (0.0, 1.0, 0.0) => (
-                                Vec3::new(0.0, 1.0, 0.0),
-                                Vec3::new(0.0, voxel_size / 2.0, 0.0),
-                            ),
-                            (0.0, 0.0, -1.0) => (
-                                Vec3::new(0.0, 0.0, -1.0),
-                                Vec3::new(0.0, 0.0, -voxel_size / 2.0),
-                            ),
-                            (0.0, 0.0, 1.0) => (
-                                Vec3::new(0.0, 0.0, 1.0),
-                                Vec3::new(0.0, 0.0, voxel_size / 2.0),
-                            ),
-                            _ => continue,
-                        };
-
-                        voxel_meshes.push(generate_face(
-                            world_position + offset, // offset the face
-                            voxel_size / 2.0,
-                            normal
-                        ));
-                    }
+/// Builds the surface of the voxels, one merged mesh per material, with positions, normals and UVs.
+/// Faces are only generated where a voxel has no neighbor. With a region, only cells whose center lies
+/// in it are meshed, and faces towards cells outside it are kept, so the cut surface is closed.
+pub fn generate_meshes(octree: &SparseVoxelOctree, region: Option<&AABB>) -> HashMap<MaterialId, Mesh> {
+    let mut voxel_count = 0;
+    // Faces are grouped by material so each material gets its own mesh.
+    let mut voxel_meshes: HashMap<MaterialId, Vec<Mesh>> = HashMap::new();
+
+    // Walk the voxels lazily: world center, key and cell size of each leaf.
+    let entries = match region {
+        Some(region) => octree.iter_in_aabb(region),
+        None => octree.iter(),
+    };
+    for entry in entries {
+        voxel_count += 1;
+
+        // Leaves cut by the region are meshed cell by cell, keeping the cells whose center is inside it.
+        let cells: Vec<(Vec3, VoxelKey, f32)> = match region {
+            Some(region) if !region.contains_aabb(&octree.key_bounds(entry.key)) => {
+                let mut keys = Vec::new();
+                octree.region_cell_keys(entry.key, region, &mut keys);
+                keys.into_iter()
+                    .map(|key| (octree.key_center(key), key, octree.get_spacing_at_depth(key.depth)))
+                    .collect()
+            }
+            _ => vec![(entry.position, entry.key, entry.size)],
+        };
+
+        for (world_position, key, voxel_size) in cells {
+            // For each neighbor direction, check if this voxel face is exposed.
+            for &(dx, dy, dz) in NEIGHBOR_OFFSETS.iter() {
+                let offset = IVec3::new(dx as i32, dy as i32, dz as i32);
+                // Neighbors outside the region are left out, so the region gets a closed surface.
+                let neighbor_center = world_position + offset.as_vec3() * voxel_size;
+                let outside = region.is_some_and(|region| !region.contains_point(neighbor_center));
+                if outside || !octree.has_neighbor_key(key, offset) {
+
+                    // Determine face normal and the local offset for the face.
+                    let (normal, offset) = match (dx, dy, dz) {
+                        (-1.0, 0.0, 0.0) => (
+                            Vec3::new(-1.0, 0.0, 0.0),
+                            Vec3::new(-voxel_size / 2.0, 0.0, 0.0),
+                        ),
+                        (1.0, 0.0, 0.0) => (
+                            Vec3::new(1.0, 0.0, 0.0),
+                            Vec3::new(voxel_size / 2.0, 0.0, 0.0),
+                        ),
+                        (0.0, -1.0, 0.0) => (
+                            Vec3::new(0.0, -1.0, 0.0),
+                            Vec3::new(0.0, -voxel_size / 2.0, 0.0),
+                        ),
+                        (0.0, 1.0, 0.0) => (
+                            Vec3::new(0.0, 1.0, 0.0),
+                            Vec3::new(0.0, voxel_size / 2.0, 0.0),
+                        ),
+                        (0.0, 0.0, -1.0) => (
+                            Vec3::new(0.0, 0.0, -1.0),
+                            Vec3::new(0.0, 0.0, -voxel_size / 2.0),
+                        ),
+                        (0.0, 0.0, 1.0) => (
+                            Vec3::new(0.0, 0.0, 1.0),
+                            Vec3::new(0.0, 0.0, voxel_size / 2.0),
+                        ),
+                        _ => continue,
+                    };
+
+                    voxel_meshes.entry(entry.voxel.material).or_default().push(generate_face(
+                        world_position + offset, // offset the face
+                        voxel_size / 2.0,
+                        normal
+                    ));
                 }
             }
-
-            // Merge all the face meshes into a single mesh.
-            let mesh = merge_meshes(voxel_meshes);
-            let cube_handle = meshes.add(mesh);
-
-            // Create a material with cull_mode disabled to see both sides (for debugging)
-            let material = materials.add(StandardMaterial {
-                base_color: Color::srgba(0.8, 0.7, 0.6, 1.0),
-                cull_mode: Some(Face::Back), // disable culling for debugging
-                ..Default::default()
-            });
-
-
-            // Spawn the mesh into the scene
-            commands.spawn((
-                PbrBundle {
-                    mesh: Mesh3d::from(cube_handle),
-                    material: MeshMaterial3d::from(material),
-                    transform: Transform::from_translation(Vec3::new(0.0, 0.0, 0.0)),
-                    ..Default::default()
-                },
-                VoxelTerrainMarker {},
-            ));
-
-            // Reset the dirty flag after updating.
-            octree.dirty.clear();
         }
     }
+
+    // Debug: Log the number of voxels traversed.
+    info!("Voxel count: {}", voxel_count);
+
+    voxel_meshes
+        .into_iter()
+        // Merge all the face meshes of this material into a single mesh.
+        .map(|(material_id, faces)| (material_id, merge_meshes(faces)))
+        .collect()
 }
 
 fn generate_face(position: Vec3, face_size: f32, normal: Vec3) -> Mesh {
@@ -134,9 +176,14 @@ fn generate_face(position: Vec3, face_size: f32, normal: Vec3) -> Mesh {
     // Compute a rotation that aligns the default +Z with the provided normal
     let rotation = Quat::from_rotation_arc(Vec3::Z, normal);
 
+    // Faces are axis-aligned, so the rotated axes are rounded to exact unit vectors. This keeps
+    // shared corners bit-identical, which matters for exported meshes.
+    let tangent = (rotation * Vec3::X).round();
+    let bitangent = (rotation * Vec3::Y).round();
+
     // Rotate and translate the vertices based on the computed rotation and provided position
     for p in positions.iter_mut() {
-        let vertex = rotation * Vec3::from(*p) + position;
+        let vertex = tangent * p[0] + bitangent * p[1] + position;
         *p = [vertex.x, vertex.y, vertex.z];
     }
 
@@ -201,4 +248,49 @@ fn merge_meshes(meshes: Vec<Mesh>) -> Mesh {
     merged_mesh.insert_indices(Indices::U32(merged_indices));
 
     merged_mesh
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::systems::voxels::events::emit_voxel_events;
+    use crate::systems::voxels::material::MaterialId;
+    use crate::systems::voxels::structure::Voxel;
+    use super::*;
+
+    fn meshes_of(app: &mut App, octree: Entity) -> Vec<Entity> {
+        let mut query = app.world_mut().query_filtered::<(Entity, &Parent), With<VoxelTerrainMarker>>();
+        query.iter(app.world()).filter(|(_, parent)| parent.get() == octree).map(|(entity, _)| entity).collect()
+    }
+
+    #[test]
+    fn octrees_own_their_meshes() {
+        let mut app = App::new();
+        app.init_resource::<Assets<Mesh>>();
+        app.init_resource::<Assets<StandardMaterial>>();
+        app.init_resource::<MaterialPalette>();
+        app.add_event::<VoxelInserted>();
+        app.add_event::<VoxelRemoved>();
+        app.add_event::<RegionChanged>();
+        app.add_systems(Update, (emit_voxel_events::<Voxel>, render).chain());
+
+        let mut terrain = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        terrain.insert(Vec3::splat(0.5), Voxel::new(MaterialId(0)));
+        let mut prop = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        prop.insert(Vec3::splat(0.5), Voxel::new(MaterialId(0)));
+        prop.insert(Vec3::splat(2.5), Voxel::new(MaterialId(1)));
+        let terrain = app.world_mut().spawn(terrain).id();
+        let prop = app.world_mut().spawn(prop).id();
+        app.update();
+        let terrain_meshes = meshes_of(&mut app, terrain);
+        let prop_meshes = meshes_of(&mut app, prop);
+        assert_eq!(terrain_meshes.len(), 1);
+        assert_eq!(prop_meshes.len(), 2);
+
+        // Editing one octree rebuilds only its own meshes.
+        app.world_mut().get_mut::<SparseVoxelOctree>(prop).unwrap().remove(Vec3::splat(2.5));
+        app.update();
+        assert_eq!(meshes_of(&mut app, terrain), terrain_meshes);
+        assert_eq!(meshes_of(&mut app, prop).len(), 1);
+        assert!(app.world().get_entity(prop_meshes[0]).is_err());
+    }
+}
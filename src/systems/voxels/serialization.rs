@@ -0,0 +1,340 @@
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use crate::systems::voxels::events::VoxelChange;
+use crate::systems::voxels::structure::{NodeId, NodePool, SparseVoxelOctree, VoxelData, MAX_DEPTH};
+
+/// Magic bytes at the start of every saved octree.
+pub const MAGIC: [u8; 4] = *b"SVOX";
+
+/// Current version of the binary format. Bump it whenever the layout changes.
+/// Version 1 stored the payload type path instead of `VoxelData::PAYLOAD_ID`; it is still read.
+pub const FORMAT_VERSION: u16 = 2;
+
+// Node tags. Nodes are written in preorder; a branch is followed by its eight children.
+const TAG_EMPTY: u8 = 0;
+const TAG_LEAF: u8 = 1;
+const TAG_BRANCH: u8 = 2;
+
+// Layout (all numbers little-endian):
+//   magic [u8; 4], version u16,
+//   payload id (u16 length + UTF-8 bytes),
+//   max_depth u32, base_depth u32, size f32,
+//   node count u32, nodes in preorder (tag u8, payload after TAG_LEAF).
+
+impl<T: VoxelData> SparseVoxelOctree<T> {
+    /// Writes the octree in the binary format described above.
+    /// Only the tree is stored; display flags, pending changes and the undo history are not.
+    /// Wrap files in a `BufWriter`, nodes are written a few bytes at a time.
+    pub fn save_to_writer(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+
+        let payload_id = T::PAYLOAD_ID.as_bytes();
+        let payload_id_len = u16::try_from(payload_id.len())
+            .map_err(|_| invalid_data("payload id is too long"))?;
+        writer.write_all(&payload_id_len.to_le_bytes())?;
+        writer.write_all(payload_id)?;
+
+        writer.write_all(&self.max_depth.to_le_bytes())?;
+        writer.write_all(&self.base_depth.to_le_bytes())?;
+        writer.write_all(&self.size.to_le_bytes())?;
+        writer.write_all(&(self.nodes.len() as u32).to_le_bytes())?;
+
+        self.save_node(NodePool::ROOT, writer)
+    }
+
+    /// Reads an octree written by `save_to_writer`. Every filled leaf is queued as a region change,
+    /// so consumers of `RegionChanged` (e.g. meshing) pick up the loaded voxels.
+    /// Fails with `InvalidData` on a bad header, a different payload type or a malformed tree.
+    pub fn load_from_reader(reader: &mut impl Read) -> io::Result<Self> {
+        if read_array::<4>(reader)? != MAGIC {
+            return Err(invalid_data("not a voxel octree file"));
+        }
+        let version = u16::from_le_bytes(read_array(reader)?);
+        let expected_id = match version {
+            1 => T::type_path(),
+            FORMAT_VERSION => T::PAYLOAD_ID,
+            _ => return Err(invalid_data(format!("unsupported format version {version}"))),
+        };
+
+        let payload_id_len = u16::from_le_bytes(read_array(reader)?) as usize;
+        let mut payload_id = vec![0; payload_id_len];
+        reader.read_exact(&mut payload_id)?;
+        if payload_id != expected_id.as_bytes() {
+            return Err(invalid_data(format!(
+                "payload type mismatch: file has {}, expected {expected_id}",
+                String::from_utf8_lossy(&payload_id),
+            )));
+        }
+
+        let max_depth = u32::from_le_bytes(read_array(reader)?);
+        let base_depth = u32::from_le_bytes(read_array(reader)?);
+        let size = f32::from_le_bytes(read_array(reader)?);
+        let node_count = u32::from_le_bytes(read_array(reader)?);
+        if max_depth > MAX_DEPTH || base_depth > max_depth || !(size.is_finite() && size > 0.0) {
+            return Err(invalid_data("invalid octree header"));
+        }
+
+        let mut octree = Self::new(max_depth, size, false, false, false);
+        octree.base_depth = base_depth;
+        if octree.load_node(NodePool::ROOT, 0, reader)? != node_count {
+            return Err(invalid_data("node count does not match the header"));
+        }
+
+        let loaded: Vec<_> = octree
+            .iter()
+            .map(|entry| VoxelChange::Region { bounds: entry.bounds(), old: Arc::default(), new: Some(*entry.voxel) })
+            .collect();
+        octree.changes = loaded;
+        Ok(octree)
+    }
+
+    fn save_node(&self, id: NodeId, writer: &mut impl Write) -> io::Result<()> {
+        let node = self.nodes.get(id);
+        match (node.children, node.voxel) {
+            (Some(first), _) => {
+                writer.write_all(&[TAG_BRANCH])?;
+                for child in first..first + 8 {
+                    self.save_node(child, writer)?;
+                }
+                Ok(())
+            }
+            (None, Some(voxel)) => {
+                writer.write_all(&[TAG_LEAF])?;
+                voxel.write_to(writer)
+            }
+            (None, None) => writer.write_all(&[TAG_EMPTY]),
+        }
+    }
+
+    /// Reads the subtree of `id` and returns the number of nodes read. Branches whose children
+    /// are equal leaves are merged, so trees from other writers end up in canonical form.
+    fn load_node(&mut self, id: NodeId, depth: u32, reader: &mut impl Read) -> io::Result<u32> {
+        match read_array::<1>(reader)?[0] {
+            TAG_EMPTY => Ok(1),
+            TAG_LEAF => {
+                self.nodes.get_mut(id).voxel = Some(T::read_from(reader)?);
+                Ok(1)
+            }
+            TAG_BRANCH if depth < self.max_depth => {
+                let first = self.nodes.split(id);
+                let mut count = 1;
+                for i in 0..8 {
+                    count += self.load_node(first + i, depth + 1, reader)?;
+                }
+                self.nodes.try_merge(id);
+                Ok(count)
+            }
+            TAG_BRANCH => Err(invalid_data("branch below max depth")),
+            tag => Err(invalid_data(format!("unknown node tag {tag}"))),
+        }
+    }
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::*;
+    use bevy::reflect::TypePath;
+    use crate::systems::voxels::csg::Shape;
+    use crate::systems::voxels::material::MaterialId;
+    use crate::systems::voxels::structure::{SparseVoxelOctree, Voxel, VoxelData, AABB};
+    use super::*;
+
+    fn round_trip<T: VoxelData>(octree: &SparseVoxelOctree<T>) -> SparseVoxelOctree<T> {
+        let mut bytes = Vec::new();
+        octree.save_to_writer(&mut bytes).unwrap();
+        SparseVoxelOctree::load_from_reader(&mut bytes.as_slice()).unwrap()
+    }
+
+    fn assert_same<T: VoxelData + std::fmt::Debug>(a: &SparseVoxelOctree<T>, b: &SparseVoxelOctree<T>) {
+        assert_eq!(a.max_depth, b.max_depth);
+        assert_eq!(a.base_depth, b.base_depth);
+        assert_eq!(a.size, b.size);
+        assert_eq!(a.nodes.len(), b.nodes.len());
+        let a: Vec<_> = a.iter().map(|entry| (entry.key, *entry.voxel)).collect();
+        let b: Vec<_> = b.iter().map(|entry| (entry.key, *entry.voxel)).collect();
+        assert_eq!(a, b);
+    }
+
+    fn sample_octree() -> SparseVoxelOctree {
+        let mut octree = SparseVoxelOctree::new(5, 32.0, false, false, false);
+        octree.union_shape(
+            &Shape::Sphere { center: Vec3::new(1.0, -2.0, 3.0), radius: 9.0 },
+            Voxel::new(MaterialId(1)),
+        );
+        octree.fill_aabb(&AABB::new(Vec3::splat(-16.0), Vec3::new(16.0, -12.0, 16.0)), Voxel::new(MaterialId(3)));
+        octree.insert(Vec3::new(10.5, 10.5, 10.5), Voxel::new(MaterialId(7)));
+        octree.remove(Vec3::new(1.5, -1.5, 3.5));
+        octree
+    }
+
+    #[test]
+    fn empty_octree_round_trips() {
+        let octree: SparseVoxelOctree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        let loaded = round_trip(&octree);
+        assert_same(&octree, &loaded);
+        assert_eq!(loaded.nodes.len(), 1);
+    }
+
+    #[test]
+    fn filled_root_round_trips() {
+        let mut octree = SparseVoxelOctree::new(3, 8.0, false, false, false);
+        octree.fill_aabb(&AABB::new(Vec3::splat(-4.0), Vec3::splat(4.0)), Voxel::new(MaterialId(2)));
+        assert_same(&octree, &round_trip(&octree));
+    }
+
+    #[test]
+    fn mixed_octree_round_trips() {
+        let octree = sample_octree();
+        let loaded = round_trip(&octree);
+        assert_same(&octree, &loaded);
+        assert_eq!(loaded.changes.len(), loaded.iter().count());
+    }
+
+    #[test]
+    fn expanded_octree_round_trips() {
+        let mut octree = sample_octree();
+        octree.insert(Vec3::new(100.0, 0.0, 0.0), Voxel::new(MaterialId(4)));
+        assert!(octree.max_depth > octree.base_depth);
+        let mut loaded = round_trip(&octree);
+        assert_same(&octree, &loaded);
+
+        // The base depth survives, so the loaded tree still shrinks back.
+        loaded.remove(Vec3::new(100.0, 0.0, 0.0));
+        assert_eq!(loaded.max_depth, 5);
+    }
+
+    #[test]
+    fn custom_payload_round_trips() {
+        #[derive(Debug, Clone, Copy, PartialEq, TypePath)]
+        struct Temperature(f32);
+
+        impl VoxelData for Temperature {
+            const PAYLOAD_ID: &'static str = "temperature";
+
+            fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+                writer.write_all(&self.0.to_le_bytes())
+            }
+
+            fn read_from(reader: &mut impl Read) -> io::Result<Self> {
+                Ok(Temperature(f32::from_le_bytes(read_array(reader)?)))
+            }
+        }
+
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        octree.fill_aabb(&AABB::new(Vec3::splat(-8.0), Vec3::ZERO), Temperature(-12.5));
+        octree.insert(Vec3::new(3.5, 3.5, 3.5), Temperature(300.0));
+        assert_same(&octree, &round_trip(&octree));
+
+        // A file saved with one payload type cannot be loaded as another.
+        let mut bytes = Vec::new();
+        octree.save_to_writer(&mut bytes).unwrap();
+        let error = SparseVoxelOctree::<Voxel>::load_from_reader(&mut bytes.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn header_is_checked() {
+        let mut bytes = Vec::new();
+        sample_octree().save_to_writer(&mut bytes).unwrap();
+        assert_eq!(&bytes[..4], &MAGIC);
+        assert_eq!(u16::from_le_bytes([bytes[4], bytes[5]]), FORMAT_VERSION);
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert!(SparseVoxelOctree::<Voxel>::load_from_reader(&mut bad_magic.as_slice()).is_err());
+
+        let mut bad_version = bytes.clone();
+        bad_version[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(SparseVoxelOctree::<Voxel>::load_from_reader(&mut bad_version.as_slice()).is_err());
+    }
+
+    #[test]
+    fn malformed_trees_are_rejected() {
+        let mut bytes = Vec::new();
+        sample_octree().save_to_writer(&mut bytes).unwrap();
+
+        // Truncated data.
+        let truncated = &bytes[..bytes.len() - 3];
+        assert!(SparseVoxelOctree::<Voxel>::load_from_reader(&mut &truncated[..]).is_err());
+
+        // A chain of branches deeper than max depth.
+        let header_len = 4 + 2 + 2 + Voxel::PAYLOAD_ID.len() + 4 + 4 + 4 + 4;
+        let mut too_deep = bytes[..header_len].to_vec();
+        too_deep.extend(std::iter::repeat_n(TAG_BRANCH, 64));
+        let error = SparseVoxelOctree::<Voxel>::load_from_reader(&mut too_deep.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // An unknown tag.
+        let mut bad_tag = bytes[..header_len].to_vec();
+        bad_tag.push(9);
+        assert!(SparseVoxelOctree::<Voxel>::load_from_reader(&mut bad_tag.as_slice()).is_err());
+    }
+
+    /// A header for `Voxel` payloads with the given format version and payload id.
+    fn header(version: u16, payload_id: &str, max_depth: u32, node_count: u32) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(version.to_le_bytes());
+        bytes.extend((payload_id.len() as u16).to_le_bytes());
+        bytes.extend(payload_id.as_bytes());
+        bytes.extend(max_depth.to_le_bytes());
+        bytes.extend(max_depth.to_le_bytes());
+        bytes.extend(8.0_f32.to_le_bytes());
+        bytes.extend(node_count.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn version_1_files_still_load() {
+        let octree = sample_octree();
+        let mut bytes = Vec::new();
+        octree.save_to_writer(&mut bytes).unwrap();
+        let id_end = 8 + Voxel::PAYLOAD_ID.len();
+        assert_eq!(&bytes[8..id_end], Voxel::PAYLOAD_ID.as_bytes());
+
+        // Version 1 named the payload by its type path.
+        let type_path = Voxel::type_path();
+        let mut old = MAGIC.to_vec();
+        old.extend(1_u16.to_le_bytes());
+        old.extend((type_path.len() as u16).to_le_bytes());
+        old.extend(type_path.as_bytes());
+        old.extend(&bytes[id_end..]);
+        assert_same(&octree, &SparseVoxelOctree::load_from_reader(&mut old.as_slice()).unwrap());
+
+        // The type path is not accepted in the current version.
+        old[4..6].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+        assert!(SparseVoxelOctree::<Voxel>::load_from_reader(&mut old.as_slice()).is_err());
+    }
+
+    #[test]
+    fn equal_children_are_merged_on_load() {
+        let mut filled = header(FORMAT_VERSION, Voxel::PAYLOAD_ID, 3, 9);
+        filled.push(TAG_BRANCH);
+        for _ in 0..8 {
+            filled.push(TAG_LEAF);
+            Voxel::new(MaterialId(2)).write_to(&mut filled).unwrap();
+        }
+        let loaded = SparseVoxelOctree::<Voxel>::load_from_reader(&mut filled.as_slice()).unwrap();
+        assert_eq!(loaded.nodes.len(), 1);
+        assert_eq!(loaded.iter().map(|entry| (entry.depth, *entry.voxel)).collect::<Vec<_>>(), vec![(0, Voxel::new(MaterialId(2)))]);
+        assert_eq!(loaded.validate(), Ok(()));
+
+        let mut empty = header(FORMAT_VERSION, Voxel::PAYLOAD_ID, 3, 9);
+        empty.push(TAG_BRANCH);
+        empty.extend([TAG_EMPTY; 8]);
+        let loaded = SparseVoxelOctree::<Voxel>::load_from_reader(&mut empty.as_slice()).unwrap();
+        assert_eq!(loaded.nodes.len(), 1);
+        assert_eq!(loaded.iter().count(), 0);
+        assert_eq!(loaded.validate(), Ok(()));
+    }
+}
@@ -0,0 +1,179 @@
+use bevy::diagnostic::{Diagnostic, DiagnosticPath, Diagnostics};
+use bevy::prelude::*;
+use crate::systems::voxels::events::VoxelChange;
+use crate::systems::voxels::structure::{NodeId, NodePool, SparseVoxelOctree, VoxelData, VoxelKey, AABB};
+
+pub const NODE_COUNT: DiagnosticPath = DiagnosticPath::const_new("voxels/nodes");
+pub const LEAF_COUNT: DiagnosticPath = DiagnosticPath::const_new("voxels/leaves");
+pub const VOXEL_COUNT: DiagnosticPath = DiagnosticPath::const_new("voxels/voxels");
+pub const HEAP_BYTES: DiagnosticPath = DiagnosticPath::const_new("voxels/heap_bytes");
+
+/// Size and shape of an octree, as returned by `SparseVoxelOctree::stats`.
+#[derive(Debug, Clone, Default, PartialEq, Reflect)]
+pub struct OctreeStats {
+    /// Number of nodes at each depth, the root being depth 0.
+    pub nodes_per_depth: Vec<usize>,
+    pub node_count: usize,
+    /// Leaves holding a voxel. A merged leaf counts once.
+    pub leaf_count: usize,
+    /// Cells at max depth covered by voxels, i.e. the leaves with merged ones expanded.
+    pub voxel_count: u128,
+    /// Child slots that hold neither a voxel nor children.
+    pub empty_child_count: usize,
+    /// Estimated heap usage of the nodes, the pending changes and the undo history.
+    pub heap_bytes: usize,
+    /// Depth of the deepest leaf holding a voxel.
+    pub max_occupied_depth: Option<u32>,
+    /// Bounds of the filled leaves, in octree space.
+    pub occupied_bounds: Option<AABB>,
+}
+
+impl<T: VoxelData> SparseVoxelOctree<T> {
+    /// Walks the whole tree and collects its statistics.
+    pub fn stats(&self) -> OctreeStats {
+        let mut stats = OctreeStats {
+            heap_bytes: self.nodes.heap_bytes()
+                + self.changes.capacity() * std::mem::size_of::<VoxelChange<T>>()
+                + self.history.heap_bytes(),
+            ..Default::default()
+        };
+
+        let mut stack: Vec<(NodeId, VoxelKey)> = vec![(NodePool::ROOT, VoxelKey::default())];
+        while let Some((id, key)) = stack.pop() {
+            let depth = key.depth as usize;
+            if stats.nodes_per_depth.len() <= depth {
+                stats.nodes_per_depth.resize(depth + 1, 0);
+            }
+            stats.nodes_per_depth[depth] += 1;
+            stats.node_count += 1;
+
+            let node = self.nodes.get(id);
+            if let Some(first) = node.children {
+                for i in 0..8 {
+                    stack.push((first + i as NodeId, key.child(i)));
+                }
+            } else if node.voxel.is_some() {
+                stats.leaf_count += 1;
+                stats.voxel_count += self.cells_in_leaf(key.depth);
+                stats.max_occupied_depth = stats.max_occupied_depth.max(Some(key.depth));
+                let bounds = self.key_bounds(key);
+                stats.occupied_bounds = Some(match stats.occupied_bounds {
+                    Some(occupied) => AABB::new(occupied.min.min(bounds.min), occupied.max.max(bounds.max)),
+                    None => bounds,
+                });
+            } else if id != NodePool::ROOT {
+                stats.empty_child_count += 1;
+            }
+        }
+        stats
+    }
+}
+
+/// The octree diagnostics with their units, to be registered with `register_diagnostic`.
+pub fn octree_diagnostics() -> [Diagnostic; 4] {
+    [
+        Diagnostic::new(NODE_COUNT),
+        Diagnostic::new(LEAF_COUNT),
+        Diagnostic::new(VOXEL_COUNT),
+        Diagnostic::new(HEAP_BYTES).with_suffix(" B"),
+    ]
+}
+
+/// Refreshes `SparseVoxelOctree::stats` of edited octrees and reports the totals over all
+/// octrees as diagnostics.
+pub fn update_octree_stats<T: VoxelData>(
+    mut octrees: Query<&mut SparseVoxelOctree<T>>,
+    mut diagnostics: Diagnostics,
+) {
+    let mut totals = OctreeStats::default();
+    for mut octree in octrees.iter_mut() {
+        if octree.is_changed() {
+            let stats = octree.stats();
+            // Writing the stats is not an edit, so it must not mark the octree as changed again.
+            octree.bypass_change_detection().stats = stats;
+        }
+        totals.node_count += octree.stats.node_count;
+        totals.leaf_count += octree.stats.leaf_count;
+        totals.voxel_count += octree.stats.voxel_count;
+        totals.heap_bytes += octree.stats.heap_bytes;
+    }
+    diagnostics.add_measurement(&NODE_COUNT, || totals.node_count as f64);
+    diagnostics.add_measurement(&LEAF_COUNT, || totals.leaf_count as f64);
+    diagnostics.add_measurement(&VOXEL_COUNT, || totals.voxel_count as f64);
+    diagnostics.add_measurement(&HEAP_BYTES, || totals.heap_bytes as f64);
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::diagnostic::{DiagnosticsPlugin, DiagnosticsStore, RegisterDiagnostic};
+    use crate::systems::voxels::material::MaterialId;
+    use crate::systems::voxels::structure::{Voxel, MAX_DEPTH};
+    use super::*;
+
+    #[test]
+    fn counts_nodes_and_voxels() {
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        assert_eq!(octree.stats().node_count, 1);
+        assert_eq!(octree.stats().occupied_bounds, None);
+
+        octree.insert(Vec3::new(0.5, 0.5, 0.5), Voxel::new(MaterialId(1)));
+        // A merged 2x2x2 block one level up.
+        octree.fill_aabb(&AABB::new(Vec3::new(-4.0, -4.0, -4.0), Vec3::new(-2.0, -2.0, -2.0)), Voxel::new(MaterialId(2)));
+
+        let stats = octree.stats();
+        assert_eq!(stats.nodes_per_depth, vec![1, 8, 16, 16, 8]);
+        assert_eq!(stats.node_count, octree.nodes.len());
+        assert_eq!(stats.leaf_count, 2);
+        assert_eq!(stats.voxel_count, 9);
+        // 48 children, of which five are branches and two hold voxels.
+        assert_eq!(stats.empty_child_count, 48 - 5 - 2);
+        assert_eq!(stats.max_occupied_depth, Some(4));
+        assert_eq!(stats.occupied_bounds, Some(AABB::new(Vec3::splat(-4.0), Vec3::splat(1.0))));
+        assert!(stats.heap_bytes >= octree.nodes.heap_bytes());
+    }
+
+    #[test]
+    fn counts_a_filled_root_at_max_depth() {
+        let mut octree = SparseVoxelOctree::new(MAX_DEPTH, 16.0, false, false, false);
+        octree.fill_aabb(&octree.root_bounds(), Voxel::new(MaterialId(1)));
+
+        let stats = octree.stats();
+        assert_eq!(stats.leaf_count, 1);
+        assert_eq!(stats.max_occupied_depth, Some(0));
+        assert_eq!(stats.voxel_count, 1 << (3 * MAX_DEPTH));
+    }
+
+    #[test]
+    fn system_refreshes_stats_and_diagnostics() {
+        let mut app = App::new();
+        app.add_plugins(DiagnosticsPlugin);
+        for diagnostic in octree_diagnostics() {
+            app.register_diagnostic(diagnostic);
+        }
+        app.add_systems(Update, update_octree_stats::<Voxel>);
+
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        octree.insert(Vec3::new(0.5, 0.5, 0.5), Voxel::new(MaterialId(1)));
+        let first = app.world_mut().spawn(octree).id();
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        octree.fill_aabb(&AABB::new(Vec3::ZERO, Vec3::splat(2.0)), Voxel::new(MaterialId(1)));
+        app.world_mut().spawn(octree);
+        app.update();
+
+        let measured = |app: &App, path: &DiagnosticPath| {
+            app.world().resource::<DiagnosticsStore>().get(path).and_then(|diagnostic| diagnostic.value())
+        };
+        assert_eq!(app.world().get::<SparseVoxelOctree>(first).unwrap().stats.voxel_count, 1);
+        assert_eq!(measured(&app, &VOXEL_COUNT), Some(9.0));
+        assert_eq!(measured(&app, &LEAF_COUNT), Some(2.0));
+
+        // Stats follow later edits, and refreshing them does not count as an edit.
+        app.world_mut().get_mut::<SparseVoxelOctree>(first).unwrap().remove(Vec3::new(0.5, 0.5, 0.5));
+        app.update();
+        assert_eq!(app.world().get::<SparseVoxelOctree>(first).unwrap().stats.voxel_count, 0);
+        assert_eq!(measured(&app, &VOXEL_COUNT), Some(8.0));
+        let mut changed = app.world_mut().query_filtered::<(), Changed<SparseVoxelOctree>>();
+        app.update();
+        assert_eq!(changed.iter(app.world()).count(), 0);
+    }
+}
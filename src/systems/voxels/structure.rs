@@ -1,65 +1,357 @@
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Read, Write};
 use bevy::color::Color;
-use bevy::math::{DVec3, Vec2};
-use bevy::prelude::{Component, Entity, Resource, Vec3};
-use bevy_reflect::Reflect;
+use bevy::math::{DVec3, IVec3, Vec2};
+use bevy::prelude::{Component, Entity, Resource, Vec3, Visibility};
+use bevy_reflect::{Reflect, TypePath};
+use crate::systems::double_transform::DoubleTransform;
+use crate::systems::voxels::events::VoxelChange;
+use crate::systems::voxels::history::EditHistory;
+use crate::systems::voxels::material::MaterialId;
+use crate::systems::voxels::stats::OctreeStats;
 
-/// Represents a single voxel with a color.
-#[derive(Debug, Clone, Copy, Component, PartialEq, Default)]
+/// Payload stored in the leaves of a `SparseVoxelOctree`.
+/// Payloads are copied when a cell is split, and equal neighbours are merged into one leaf,
+/// so equality should mean "interchangeable". Implement it for game data such as health,
+/// temperature, owner ids or fluid levels.
+pub trait VoxelData: Copy + PartialEq + Send + Sync + TypePath + 'static {
+    /// Name of the payload in saved files, checked when loading. Unlike the type path it does not
+    /// change when the type is moved or renamed, so keep it once files have been written.
+    const PAYLOAD_ID: &'static str;
+
+    /// Writes the payload when saving an octree.
+    fn write_to(&self, writer: &mut impl Write) -> io::Result<()>;
+
+    /// Reads a payload written by `write_to`.
+    fn read_from(reader: &mut impl Read) -> io::Result<Self>;
+}
+
+/// Represents a single voxel. The material is resolved through the `MaterialPalette`.
+/// This is the default payload of the octree.
+#[derive(Debug, Clone, Copy, Component, PartialEq, Eq, Hash, Default, Reflect)]
 pub struct Voxel {
-    pub color: Color,
+    pub material: MaterialId,
 }
 
-#[derive(Debug, Clone, Copy,Reflect)]
-pub struct DirtyVoxel {
-    pub position: Vec3,
+/// Deepest level a tree may reach, also through root expansion. Cells are addressed with i32 keys,
+/// so `2^depth` cells per axis must stay well inside `i32::MAX`.
+pub const MAX_DEPTH: u32 = 30;
+
+/// Integer address of a cell in the octree.
+/// `position` is the cell index along each axis in `[0, 2^depth)`, `depth` is the level (0 = root).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Reflect)]
+pub struct VoxelKey {
+    pub position: IVec3,
+    pub depth: u32,
 }
 
+/// Index of a node inside a `NodePool`.
+pub type NodeId = u32;
+
 /// Represents a node in the sparse voxel octree.
+/// A node is either a branch (`children` points at the first of eight consecutive slots
+/// in the pool) or a leaf, which may hold a voxel covering its whole cell.
+#[derive(Debug, Component, Clone, Copy, Default)]
+pub struct OctreeNode<T: VoxelData = Voxel> {
+    pub children: Option<NodeId>,
+    pub voxel: Option<T>,
+}
 
-#[derive(Debug, Component, Clone)]
-pub struct OctreeNode {
-    pub children: Option<Box<[OctreeNode; 8]>>,
-    pub voxel: Option<Voxel>,
-    pub is_leaf: bool,
+/// Flat arena holding every node of an octree.
+/// Children are allocated in blocks of eight; freed blocks go to a free list and are reused.
+#[derive(Debug, Clone)]
+pub struct NodePool<T: VoxelData = Voxel> {
+    nodes: Vec<OctreeNode<T>>,
+    free: Vec<NodeId>,
 }
+
 /// Represents the root of the sparse voxel octree.
-/// Represents the root of the sparse voxel octree.
+/// Voxel coordinates are local to the entity's `DoubleTransform`, which may translate, rotate and
+/// scale the whole octree in f64 world space. Its `Transform` is derived from it relative to the
+/// floating origin. Generated meshes are spawned as children, so they follow it.
 #[derive(Debug, Component, Reflect)]
+#[require(DoubleTransform, Visibility)]
 #[reflect(from_reflect = false)]
-pub struct SparseVoxelOctree {
+pub struct SparseVoxelOctree<T: VoxelData = Voxel> {
 
     #[reflect(ignore)]
-    pub root: OctreeNode,
+    pub nodes: NodePool<T>,
     pub max_depth: u32,
+    /// Depth the octree was created with. `shrink_root` never goes below it.
+    pub base_depth: u32,
     pub size: f32,
     pub show_wireframe: bool,
     pub show_world_grid: bool,
     pub show_chunks: bool,
+    /// Debug builds only: run `validate` after every edit and panic if it fails.
+    /// Costs a full tree walk per edit, so it is meant for tests and while debugging.
+    pub validate_edits: bool,
+
+    /// Edits since the last `emit_voxel_events`, oldest first.
+    #[reflect(ignore)]
+    pub changes: Vec<VoxelChange<T>>,
 
-    pub dirty: Vec<DirtyVoxel>,
+    #[reflect(ignore)]
+    pub history: EditHistory<T>,
+
+    /// Statistics as of the last frame the octree was edited in, kept up to date by
+    /// `update_octree_stats`. Call `stats()` for current numbers outside the app.
+    pub stats: OctreeStats,
 }
 
-impl OctreeNode {
+impl<T: VoxelData> OctreeNode<T> {
     /// Creates a new empty octree node.
     pub fn new() -> Self {
         Self {
             children: None,
             voxel: None,
-            is_leaf: true,
         }
     }
 
+    /// Creates a leaf node holding the given voxel (or nothing).
+    pub fn leaf(voxel: Option<T>) -> Self {
+        Self {
+            children: None,
+            voxel,
+        }
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.children.is_none()
+    }
+
     pub fn is_empty(&self) -> bool {
         self.voxel.is_none() && self.children.is_none()
     }
 }
 
+impl NodePool {
+    /// Index of the root node, which is always present. It does not depend on the payload type.
+    pub const ROOT: NodeId = 0;
+}
+
+impl<T: VoxelData> NodePool<T> {
+    /// Creates a pool containing only an empty root.
+    pub fn new() -> Self {
+        Self {
+            nodes: vec![OctreeNode::new()],
+            free: Vec::new(),
+        }
+    }
+
+    pub fn get(&self, id: NodeId) -> &OctreeNode<T> {
+        &self.nodes[id as usize]
+    }
+
+    pub fn get_mut(&mut self, id: NodeId) -> &mut OctreeNode<T> {
+        &mut self.nodes[id as usize]
+    }
+
+    /// Returns the id of child `index` (0..8) of `id`, if it is a branch.
+    pub fn child(&self, id: NodeId, index: usize) -> Option<NodeId> {
+        self.get(id).children.map(|first| first + index as NodeId)
+    }
+
+    /// Returns the eight children of `id`, if it is a branch.
+    pub fn children(&self, id: NodeId) -> Option<&[OctreeNode<T>]> {
+        self.get(id)
+            .children
+            .map(|first| &self.nodes[first as usize..first as usize + 8])
+    }
+
+    /// Turns a leaf into a branch whose eight children inherit the leaf's voxel.
+    /// Returns the id of the first child. Branches are returned unchanged.
+    pub fn split(&mut self, id: NodeId) -> NodeId {
+        if let Some(first) = self.get(id).children {
+            return first;
+        }
+        let fill = OctreeNode::leaf(self.get(id).voxel);
+        let first = match self.free.pop() {
+            Some(first) => {
+                self.nodes[first as usize..first as usize + 8].fill(fill);
+                first
+            }
+            None => {
+                let first = self.nodes.len() as NodeId;
+                self.nodes.extend(std::iter::repeat_n(fill, 8));
+                first
+            }
+        };
+        let node = self.get_mut(id);
+        node.children = Some(first);
+        node.voxel = None;
+        first
+    }
+
+    /// Releases all descendants of `id`, turning it into a leaf. The node's voxel is left untouched.
+    pub fn collapse(&mut self, id: NodeId) {
+        let Some(first) = self.get_mut(id).children.take() else {
+            return;
+        };
+        for child in first..first + 8 {
+            self.collapse(child);
+        }
+        self.free.push(first);
+    }
+
+    /// Collapses a branch whose eight children are leaves holding the same voxel (or all empty)
+    /// into a single leaf with that voxel. Returns true if the node was merged.
+    pub fn try_merge(&mut self, id: NodeId) -> bool {
+        let Some(children) = self.children(id) else {
+            return false;
+        };
+        let voxel = children[0].voxel;
+        if !children.iter().all(|child| child.is_leaf() && child.voxel == voxel) {
+            return false;
+        }
+        self.collapse(id);
+        self.get_mut(id).voxel = voxel;
+        true
+    }
+
+    /// Wraps the tree in a new root twice as large, centered on the old one.
+    /// Child `i` of the old root becomes the inner child (`7 - i`) of the new child `i`;
+    /// subtrees are moved, not copied.
+    pub fn grow(&mut self) {
+        let root = *self.get(NodePool::ROOT);
+        if root.is_empty() {
+            return;
+        }
+        self.get_mut(NodePool::ROOT).children = None;
+        self.get_mut(NodePool::ROOT).voxel = None;
+
+        let first = self.split(NodePool::ROOT);
+        for i in 0..8 {
+            let moved = match root.children {
+                Some(old_first) => *self.get(old_first + i as NodeId),
+                // A root filled as a whole fills the inner half of every new child.
+                None => OctreeNode::leaf(root.voxel),
+            };
+            if moved.is_empty() {
+                continue;
+            }
+            let grandchildren = self.split(first + i as NodeId);
+            *self.get_mut(grandchildren + (7 - i) as NodeId) = moved;
+        }
+        // The old child block is no longer referenced; its subtrees live on under the new children.
+        if let Some(old_first) = root.children {
+            self.free.push(old_first);
+        }
+    }
+
+    /// Inverse of `grow`: replaces the root by its inner half if nothing is stored outside it,
+    /// i.e. every child `i` of the root is empty or only has its inner child (`7 - i`) occupied.
+    /// Returns false and leaves the tree untouched otherwise.
+    pub fn shrink(&mut self) -> bool {
+        let Some(first) = self.get(NodePool::ROOT).children else {
+            return false;
+        };
+        let shrinkable = (0..8).all(|i| {
+            let child = self.get(first + i as NodeId);
+            match self.children(first + i as NodeId) {
+                Some(grandchildren) => grandchildren
+                    .iter()
+                    .enumerate()
+                    .all(|(j, grandchild)| j == 7 - i || grandchild.is_empty()),
+                None => child.is_empty(),
+            }
+        });
+        if !shrinkable {
+            return false;
+        }
+
+        for i in 0..8 {
+            let id = first + i as NodeId;
+            let Some(grandchildren) = self.get_mut(id).children.take() else {
+                continue;
+            };
+            // Pull the inner grandchild up; the rest of its block is empty and gets released.
+            let inner = grandchildren + (7 - i) as NodeId;
+            *self.get_mut(id) = *self.get(inner);
+            *self.get_mut(inner) = OctreeNode::new();
+            self.free.push(grandchildren);
+        }
+        self.try_merge(NodePool::ROOT);
+        true
+    }
+
+    /// Number of nodes in use, excluding released blocks.
+    pub fn len(&self) -> usize {
+        self.nodes.len() - self.free.len() * 8
+    }
+
+    /// Number of slots in the arena, including released blocks.
+    pub fn slot_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// First ids of the released blocks waiting to be reused.
+    pub fn free_blocks(&self) -> &[NodeId] {
+        &self.free
+    }
+
+    /// Bytes allocated on the heap by the pool.
+    pub fn heap_bytes(&self) -> usize {
+        self.nodes.capacity() * std::mem::size_of::<OctreeNode<T>>()
+            + self.free.capacity() * std::mem::size_of::<NodeId>()
+    }
+}
+
+impl VoxelData for Voxel {
+    const PAYLOAD_ID: &'static str = "voxel";
+
+    fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&self.material.0.to_le_bytes())
+    }
+
+    fn read_from(reader: &mut impl Read) -> io::Result<Self> {
+        let mut bytes = [0; 2];
+        reader.read_exact(&mut bytes)?;
+        Ok(Voxel::new(MaterialId(u16::from_le_bytes(bytes))))
+    }
+}
+
+impl VoxelKey {
+    pub fn new(position: IVec3, depth: u32) -> Self {
+        Self { position, depth }
+    }
+
+    /// Number of cells along one axis at this key's depth.
+    pub fn cells_per_axis(&self) -> i32 {
+        1 << self.depth
+    }
+
+    /// Returns true if the key addresses a cell inside the root bounds.
+    pub fn is_valid(&self) -> bool {
+        let cells = self.cells_per_axis();
+        self.position.cmpge(IVec3::ZERO).all() && self.position.cmplt(IVec3::splat(cells)).all()
+    }
+
+    /// The key of the cell `offset` cells away at the same depth.
+    pub fn offset(&self, offset: IVec3) -> Self {
+        Self::new(self.position + offset, self.depth)
+    }
+
+    /// The key of child `index` (bits x=1, y=2, z=4) one level deeper.
+    pub fn child(&self, index: usize) -> Self {
+        let bits = IVec3::new((index & 1) as i32, ((index >> 1) & 1) as i32, ((index >> 2) & 1) as i32);
+        Self::new(self.position * 2 + bits, self.depth + 1)
+    }
+
+    /// Child octant taken at `level` (0 = child of the root) when descending towards this key.
+    /// Uses the same bit layout as the tree: x=1, y=2, z=4.
+    pub fn child_index(&self, level: u32) -> usize {
+        let shift = self.depth - 1 - level;
+        let bits = (self.position >> shift) & IVec3::ONE;
+        (bits.x | (bits.y << 1) | (bits.z << 2)) as usize
+    }
+}
+
 impl Voxel {
-    /// Creates a new empty octree node.
-    pub fn new(color: Color) -> Self {
+    /// Creates a voxel of the given material.
+    pub fn new(material: MaterialId) -> Self {
         Self {
-            color,
+            material,
         }
     }
 }
@@ -81,8 +373,159 @@ pub struct Ray {
     pub direction: Vec3,
 }
 
-#[derive(Clone)]
+/// First voxel hit by `SparseVoxelOctree::raycast`.
+/// Positions, normal and distance are in the space of the ray: octree-local for `raycast`,
+/// world space for `raycast_world`, world space relative to the ray origin for `raycast_double`.
+/// Keys always address the octree.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RaycastHit<T: VoxelData = Voxel> {
+    /// Point where the ray enters the voxel.
+    pub point: Vec3,
+    /// Distance from the ray origin to `point`.
+    pub distance: f32,
+    /// Normal of the face the ray entered through. Zero if the ray starts inside the voxel.
+    pub normal: Vec3,
+    /// Cell at max depth that was hit, even if the voxel is stored in a larger merged leaf.
+    pub key: VoxelKey,
+    /// Center of `key`.
+    pub center: Vec3,
+    /// Cell at max depth in front of the hit face, where a voxel placed on the hit face goes.
+    /// May lie outside the root bounds. Equal to `key` if `normal` is zero.
+    pub adjacent_key: VoxelKey,
+    pub voxel: T,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Reflect)]
 pub struct AABB {
     pub min: Vec3,
     pub max: Vec3,
-}
\ No newline at end of file
+}
+
+impl AABB {
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Self { min, max }
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn size(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    /// Half-open containment test: `min <= point < max` on every axis.
+    pub fn contains_point(&self, point: Vec3) -> bool {
+        point.cmpge(self.min).all() && point.cmplt(self.max).all()
+    }
+
+    /// Returns true if `other` lies completely inside this box (shared faces count as inside).
+    pub fn contains_aabb(&self, other: &AABB) -> bool {
+        other.min.cmpge(self.min).all() && other.max.cmple(self.max).all()
+    }
+
+    /// Returns true if the boxes share some volume. Boxes that only touch do not intersect.
+    pub fn intersects(&self, other: &AABB) -> bool {
+        self.min.cmplt(other.max).all() && other.min.cmplt(self.max).all()
+    }
+
+    /// The overlapping part of both boxes, if any.
+    pub fn intersection(&self, other: &AABB) -> Option<AABB> {
+        self.intersects(other)
+            .then(|| AABB::new(self.min.max(other.min), self.max.min(other.max)))
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn child_index_walks_back_to_the_child() {
+        let root = VoxelKey::default();
+        for path in [[0, 0, 0, 0], [7, 7, 7, 7], [1, 2, 4, 3], [6, 5, 0, 7]] {
+            let key = path.iter().fold(root, |key, &index| key.child(index));
+            assert_eq!(key.depth, 4);
+            assert!(key.is_valid());
+            for (level, &index) in path.iter().enumerate() {
+                assert_eq!(key.child_index(level as u32), index);
+            }
+        }
+
+        // Every key at depth 3 is reached again by descending along its child indices.
+        for x in 0..8 {
+            for y in 0..8 {
+                for z in 0..8 {
+                    let key = VoxelKey::new(IVec3::new(x, y, z), 3);
+                    let descended = (0..3).fold(root, |parent, level| parent.child(key.child_index(level)));
+                    assert_eq!(descended, key);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn keys_outside_the_root_are_invalid() {
+        assert_eq!(VoxelKey::new(IVec3::ZERO, 5).cells_per_axis(), 32);
+        assert!(VoxelKey::new(IVec3::new(0, 31, 31), 5).is_valid());
+        assert!(!VoxelKey::new(IVec3::new(0, 32, 0), 5).is_valid());
+        assert!(!VoxelKey::new(IVec3::new(-1, 0, 0), 5).is_valid());
+        assert!(VoxelKey::new(IVec3::splat((1 << MAX_DEPTH) - 1), MAX_DEPTH).is_valid());
+        assert_eq!(VoxelKey::new(IVec3::new(4, 0, 1), 3).offset(IVec3::new(-4, 1, 0)), VoxelKey::new(IVec3::new(0, 1, 1), 3));
+    }
+
+    #[test]
+    fn collapsed_blocks_are_reused() {
+        let mut pool: NodePool<Voxel> = NodePool::new();
+        let first = pool.split(NodePool::ROOT);
+        let grandchildren = pool.split(first + 3);
+        assert_eq!((first, grandchildren), (1, 9));
+        assert_eq!((pool.len(), pool.slot_count()), (17, 17));
+
+        // Collapsing the root releases both blocks, the deeper one first.
+        pool.collapse(NodePool::ROOT);
+        assert_eq!(pool.free_blocks(), &[9, 1]);
+        assert_eq!((pool.len(), pool.slot_count()), (1, 17));
+
+        // Splits take released blocks before growing the arena, and reset their contents.
+        pool.get_mut(NodePool::ROOT).voxel = Some(Voxel::new(MaterialId(2)));
+        assert_eq!(pool.split(NodePool::ROOT), 1);
+        assert!(pool.children(NodePool::ROOT).unwrap().iter().all(|child| child.is_leaf() && child.voxel == Some(Voxel::new(MaterialId(2)))));
+        assert_eq!(pool.split(1), 9);
+        assert!(pool.free_blocks().is_empty());
+        assert_eq!(pool.split(2), 17);
+        assert_eq!((pool.len(), pool.slot_count()), (25, 25));
+    }
+
+    /// Builds the startup sphere one voxel at a time and reports the build and traversal times
+    /// and the arena size. Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn sphere_build_benchmark() {
+        for radius in [10, 40] {
+            let start = std::time::Instant::now();
+            let mut octree = SparseVoxelOctree::new(10, 64.0, false, false, false);
+            octree.history.enabled = false;
+            let step = octree.get_spacing_at_depth(octree.max_depth);
+            for x in -radius..=radius {
+                for y in -radius..=radius {
+                    for z in -radius..=radius {
+                        if x * x + y * y + z * z <= radius * radius {
+                            octree.insert(Vec3::new(x as f32, y as f32, z as f32) * step, Voxel::new(MaterialId(1)));
+                        }
+                    }
+                }
+            }
+            let build = start.elapsed();
+
+            let start = std::time::Instant::now();
+            let leaves = octree.iter().count();
+            let traverse = start.elapsed();
+            println!(
+                "radius {radius}: {leaves} leaves, build {build:?}, traverse {traverse:?}, {} nodes, {} KiB",
+                octree.nodes.len(),
+                octree.nodes.heap_bytes() / 1024
+            );
+            assert!(leaves > 0);
+        }
+    }
+}
@@ -0,0 +1,321 @@
+use std::collections::HashSet;
+use std::fmt;
+use crate::systems::voxels::structure::{NodeId, NodePool, SparseVoxelOctree, VoxelData, VoxelKey};
+
+/// A broken structural invariant found by `SparseVoxelOctree::validate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Violation {
+    /// Node the violation was found at, or `None` for problems of the node pool itself.
+    pub key: Option<VoxelKey>,
+    pub kind: ViolationKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// A branch also holds a voxel, which lookups and iteration would ignore.
+    BranchWithVoxel,
+    /// A branch below max depth, where cells can no longer be addressed.
+    TooDeep,
+    /// The children of a branch are leaves holding the same voxel (or nothing) and should have been merged.
+    Unmerged,
+    /// The child block does not start at a block boundary or runs past the end of the pool.
+    InvalidBlock { first: NodeId },
+    /// The child block is also used by another branch.
+    SharedBlock { first: NodeId },
+    /// The child block was released but is still in use.
+    FreedBlockInUse { first: NodeId },
+    /// A released block is listed twice or is not a valid block.
+    InvalidFreeBlock { first: NodeId },
+    /// A block is neither reachable from the root nor released.
+    LeakedBlock { first: NodeId },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The path from the root as child indices, e.g. `root/3/5`.
+        match self.key {
+            Some(key) => {
+                write!(f, "root")?;
+                for level in 0..key.depth {
+                    write!(f, "/{}", key.child_index(level))?;
+                }
+            }
+            None => write!(f, "pool")?,
+        }
+        match self.kind {
+            ViolationKind::BranchWithVoxel => write!(f, ": branch also holds a voxel"),
+            ViolationKind::TooDeep => write!(f, ": branch below max depth"),
+            ViolationKind::Unmerged => write!(f, ": children should have been merged"),
+            ViolationKind::InvalidBlock { first } => write!(f, ": invalid child block {first}"),
+            ViolationKind::SharedBlock { first } => write!(f, ": child block {first} is shared"),
+            ViolationKind::FreedBlockInUse { first } => write!(f, ": child block {first} was released"),
+            ViolationKind::InvalidFreeBlock { first } => write!(f, ": invalid released block {first}"),
+            ViolationKind::LeakedBlock { first } => write!(f, ": block {first} is leaked"),
+        }
+    }
+}
+
+impl<T: VoxelData> SparseVoxelOctree<T> {
+    /// Walks the whole tree and checks its structural invariants: branches hold no voxel and
+    /// stop above max depth, mergeable children are merged, and every block of the pool is
+    /// used by exactly one branch or released exactly once. Returns every violation found.
+    pub fn validate(&self) -> Result<(), Vec<Violation>> {
+        let mut violations = Vec::new();
+        let mut report = |key: Option<VoxelKey>, kind: ViolationKind| violations.push(Violation { key, kind });
+        let slots = self.nodes.slot_count();
+        let is_block = |first: NodeId| first % 8 == 1 && first as usize + 8 <= slots;
+
+        let mut released = HashSet::new();
+        for &first in self.nodes.free_blocks() {
+            if !is_block(first) || !released.insert(first) {
+                report(None, ViolationKind::InvalidFreeBlock { first });
+            }
+        }
+
+        let mut used = HashSet::new();
+        let mut stack = vec![(NodePool::ROOT, VoxelKey::default())];
+        while let Some((id, key)) = stack.pop() {
+            let node = self.nodes.get(id);
+            let Some(first) = node.children else {
+                continue;
+            };
+            if node.voxel.is_some() {
+                report(Some(key), ViolationKind::BranchWithVoxel);
+            }
+            if key.depth >= self.max_depth {
+                report(Some(key), ViolationKind::TooDeep);
+            }
+            if !is_block(first) {
+                report(Some(key), ViolationKind::InvalidBlock { first });
+                continue;
+            }
+            if released.contains(&first) {
+                report(Some(key), ViolationKind::FreedBlockInUse { first });
+            }
+            // Do not descend twice, shared blocks may form cycles.
+            if !used.insert(first) {
+                report(Some(key), ViolationKind::SharedBlock { first });
+                continue;
+            }
+
+            let children = self.nodes.children(id).unwrap_or_default();
+            if children.iter().all(|child| child.is_leaf() && child.voxel == children[0].voxel) {
+                report(Some(key), ViolationKind::Unmerged);
+            }
+            for i in 0..8 {
+                stack.push((first + i as NodeId, key.child(i)));
+            }
+        }
+
+        for first in (1..slots as NodeId).step_by(8) {
+            if is_block(first) && !used.contains(&first) && !released.contains(&first) {
+                report(None, ViolationKind::LeakedBlock { first });
+            }
+        }
+        if !(slots - 1).is_multiple_of(8) {
+            report(None, ViolationKind::InvalidBlock { first: (1 + (slots - 1) / 8 * 8) as NodeId });
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    /// Runs `validate` after an edit if `validate_edits` is set, panicking on a violation.
+    /// Does nothing in release builds.
+    pub(crate) fn check_edit(&self, edit: &str) {
+        if !cfg!(debug_assertions) || !self.validate_edits {
+            return;
+        }
+        if let Err(violations) = self.validate() {
+            let list: Vec<String> = violations.iter().map(|violation| violation.to_string()).collect();
+            panic!("octree invariants broken by {edit}:\n{}", list.join("\n"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use bevy::prelude::*;
+    use crate::systems::voxels::material::MaterialId;
+    use crate::systems::voxels::structure::{Voxel, AABB};
+    use super::*;
+
+    fn voxel(material: u16) -> Voxel {
+        Voxel::new(MaterialId(material))
+    }
+
+    fn kinds(octree: &SparseVoxelOctree) -> Vec<ViolationKind> {
+        octree.validate().unwrap_err().into_iter().map(|violation| violation.kind).collect()
+    }
+
+    #[test]
+    fn reports_broken_trees() {
+        let mut octree = SparseVoxelOctree::new(3, 8.0, false, false, false);
+        octree.insert(Vec3::new(0.5, 0.5, 0.5), voxel(1));
+        assert_eq!(octree.validate(), Ok(()));
+
+        let first = octree.nodes.get(NodePool::ROOT).children.unwrap();
+        octree.nodes.get_mut(NodePool::ROOT).voxel = Some(voxel(2));
+        assert_eq!(kinds(&octree), vec![ViolationKind::BranchWithVoxel]);
+        octree.nodes.get_mut(NodePool::ROOT).voxel = None;
+
+        // A filled leaf split by hand leaves eight equal children behind.
+        let filled = octree.nodes.split(first);
+        octree.nodes.get_mut(filled).voxel = Some(voxel(3));
+        octree.nodes.split(filled);
+        let violations = octree.validate().unwrap_err();
+        assert_eq!(violations, vec![Violation { key: Some(VoxelKey::default().child(0).child(0)), kind: ViolationKind::Unmerged }]);
+        assert_eq!(violations[0].to_string(), "root/0/0: children should have been merged");
+
+        // Dropping a branch's children without releasing them leaks the block.
+        let leaked = octree.nodes.get_mut(filled).children.take().unwrap();
+        octree.nodes.get_mut(filled).voxel = Some(voxel(3));
+        assert_eq!(kinds(&octree), vec![ViolationKind::LeakedBlock { first: leaked }]);
+    }
+
+    #[test]
+    fn reports_branches_below_max_depth() {
+        let mut octree = SparseVoxelOctree::new(1, 2.0, false, false, false);
+        octree.insert(Vec3::new(0.5, 0.5, 0.5), voxel(1));
+        let first = octree.nodes.get(NodePool::ROOT).children.unwrap();
+        let cell = first + octree.key_at(Vec3::new(0.5, 0.5, 0.5), 1).child_index(0) as NodeId;
+        let grandchildren = octree.nodes.split(cell);
+        octree.nodes.get_mut(grandchildren).voxel = Some(voxel(2));
+        assert_eq!(kinds(&octree), vec![ViolationKind::TooDeep]);
+    }
+
+    #[test]
+    #[should_panic(expected = "octree invariants broken by insert_key")]
+    fn checks_edits_when_enabled() {
+        let mut octree = SparseVoxelOctree::new(3, 8.0, false, false, false);
+        octree.validate_edits = true;
+        octree.insert(Vec3::new(0.5, 0.5, 0.5), voxel(1));
+        octree.nodes.get_mut(NodePool::ROOT).voxel = Some(voxel(2));
+        octree.insert(Vec3::new(1.5, 0.5, 0.5), voxel(1));
+    }
+
+    /// One edit of the model test, on 1 m cells.
+    #[derive(Debug, Clone, Copy)]
+    enum Edit {
+        Insert(IVec3, u16),
+        Remove(IVec3),
+        /// Fills the box of `size` cells starting at the cell.
+        Fill(IVec3, IVec3, u16),
+        /// Clears the 3x3x3 cells around the cell.
+        Clear(IVec3),
+    }
+
+    /// A reproducible sequence of random edits. Cells range over -10..10 and boxes reach up to
+    /// four cells further, so the root of `check_against_model` grows and shrinks along the way.
+    fn random_edits(seed: u32, count: usize) -> Vec<Edit> {
+        let mut state = seed;
+        let mut random = move |range: i32| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state % range as u32) as i32
+        };
+        (0..count)
+            .map(|_| {
+                let cell = IVec3::new(random(20) - 10, random(20) - 10, random(20) - 10);
+                let material = random(3) as u16;
+                match random(8) {
+                    0..=3 => Edit::Insert(cell, material),
+                    4 | 5 => Edit::Remove(cell),
+                    6 => Edit::Fill(cell, IVec3::new(random(4), random(4), random(4)) + 1, material),
+                    _ => Edit::Clear(cell),
+                }
+            })
+            .collect()
+    }
+
+    /// Applies `edits` to an octree and to a `HashMap` of the filled cells, checking the tree's
+    /// invariants and every cell after each edit. Returns the first failing step and the problem.
+    fn check_against_model(edits: &[Edit]) -> Result<(), (usize, String)> {
+        let mut octree = SparseVoxelOctree::new(3, 8.0, false, false, false);
+        let mut model: HashMap<IVec3, Voxel> = HashMap::new();
+        let center = |cell: IVec3| cell.as_vec3() + 0.5;
+        for (step, edit) in edits.iter().enumerate() {
+            match *edit {
+                Edit::Insert(cell, material) => {
+                    octree.insert(center(cell), voxel(material));
+                    model.insert(cell, voxel(material));
+                }
+                Edit::Remove(cell) => {
+                    octree.remove(center(cell));
+                    model.remove(&cell);
+                }
+                Edit::Fill(min, size, material) => {
+                    octree.fill_aabb(&AABB::new(min.as_vec3(), (min + size).as_vec3()), voxel(material));
+                    for x in min.x..min.x + size.x {
+                        for y in min.y..min.y + size.y {
+                            for z in min.z..min.z + size.z {
+                                model.insert(IVec3::new(x, y, z), voxel(material));
+                            }
+                        }
+                    }
+                }
+                Edit::Clear(cell) => {
+                    let min = cell - 1;
+                    octree.clear_aabb(&AABB::new(min.as_vec3(), (cell + 2).as_vec3()));
+                    model.retain(|filled, _| (*filled - min).cmplt(IVec3::ZERO).any() || (*filled - min).cmpge(IVec3::splat(3)).any());
+                }
+            }
+
+            let fail = |message: String| Err((step, message));
+            if let Err(violations) = octree.validate() {
+                return fail(format!("{violations:?}"));
+            }
+            for (filled, expected) in &model {
+                let found = octree.get_voxel_at_world_coords(center(*filled));
+                if found != Some(expected) {
+                    return fail(format!("cell {filled} holds {found:?}, expected {expected:?}"));
+                }
+            }
+            let voxel_count = octree.stats().voxel_count;
+            if voxel_count != model.len() as u128 {
+                return fail(format!("{voxel_count} voxels, expected {}", model.len()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops edits one at a time while the check still fails, leaving only the edits needed
+    /// to reproduce the failure.
+    fn shrink(mut edits: Vec<Edit>) -> Vec<Edit> {
+        let mut i = 0;
+        while i < edits.len() {
+            let mut candidate = edits.clone();
+            candidate.remove(i);
+            if check_against_model(&candidate).is_err() {
+                edits = candidate;
+            } else {
+                i += 1;
+            }
+        }
+        edits
+    }
+
+    /// Random edit sequences checked against the model. Runs `VOXEL_MODEL_CASES` sequences
+    /// (default 32), or only the one of `VOXEL_MODEL_SEED` to replay a reported failure.
+    #[test]
+    fn random_edits_match_model() {
+        let var = |name: &str| std::env::var(name).ok().and_then(|value| value.parse::<u32>().ok());
+        let seeds: Vec<u32> = match var("VOXEL_MODEL_SEED") {
+            Some(seed) => vec![seed],
+            // Multiples of an odd constant are never zero, which xorshift needs.
+            None => (1..=var("VOXEL_MODEL_CASES").unwrap_or(32)).map(|case| case.wrapping_mul(0x9e37_79b9)).collect(),
+        };
+        for seed in seeds {
+            let edits = random_edits(seed, 200);
+            if let Err((step, message)) = check_against_model(&edits) {
+                let minimal = shrink(edits[..=step].to_vec());
+                panic!("seed {seed}, step {step}: {message}\nsmallest failing edits: {minimal:?}");
+            }
+        }
+    }
+}
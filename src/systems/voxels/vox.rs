@@ -0,0 +1,922 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use bevy::math::I64Vec3;
+use bevy::prelude::*;
+use crate::systems::voxels::material::{MaterialId, MaterialPalette, VoxelMaterial};
+use crate::systems::voxels::structure::{SparseVoxelOctree, Voxel, AABB};
+
+/// A single model of a `.vox` file: its bounding size and its voxels as `[x, y, z, color index]`.
+/// Coordinates are MagicaVoxel's, which are Z-up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxModel {
+    pub size: UVec3,
+    pub voxels: Vec<[u8; 4]>,
+}
+
+/// A placement of a model in the scene, resolved from the nTRN/nGRP/nSHP scene graph.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VoxInstance {
+    pub model: usize,
+    /// Signed permutation matrix of the accumulated nTRN rotations.
+    pub rotation: Mat3,
+    pub translation: IVec3,
+}
+
+/// Contents of a MagicaVoxel `.vox` file.
+#[derive(Debug, Clone)]
+pub struct VoxFile {
+    pub models: Vec<VoxModel>,
+    /// RGBA colors by color index. Index 0 means "empty" and is never used by voxels.
+    pub palette: [[u8; 4]; 256],
+    pub instances: Vec<VoxInstance>,
+}
+
+/// Node of the scene graph, as stored in the file.
+enum SceneNode {
+    Transform { child: i32, rotation: Mat3, translation: IVec3 },
+    Group { children: Vec<i32> },
+    Shape { models: Vec<usize> },
+}
+
+impl VoxFile {
+    /// Reads and parses a `.vox` file.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&std::fs::read(path)?)
+    }
+
+    /// Parses the SIZE/XYZI model chunks, the RGBA palette and the scene graph.
+    /// Other chunks (materials, layers, cameras) are skipped.
+    pub fn parse(bytes: &[u8]) -> io::Result<Self> {
+        let mut reader = ByteReader { bytes };
+        if reader.take(4)? != b"VOX " {
+            return Err(invalid_data("not a MagicaVoxel file"));
+        }
+        let _version = reader.i32()?;
+
+        let (id, content, children) = reader.chunk()?;
+        if id != b"MAIN" || !content.is_empty() {
+            return Err(invalid_data("missing MAIN chunk"));
+        }
+
+        let mut models = Vec::new();
+        let mut size = None;
+        let mut palette = default_palette();
+        let mut nodes = HashMap::new();
+
+        let mut reader = ByteReader { bytes: children };
+        while !reader.bytes.is_empty() {
+            let (id, content, _) = reader.chunk()?;
+            let mut content = ByteReader { bytes: content };
+            match id {
+                b"SIZE" => {
+                    size = Some(UVec3::new(content.u32()?, content.u32()?, content.u32()?));
+                }
+                b"XYZI" => {
+                    let size = size.take().ok_or_else(|| invalid_data("XYZI chunk without SIZE"))?;
+                    let count = content.u32()? as usize;
+                    let data = content.take(count.checked_mul(4).ok_or_else(|| invalid_data("bad voxel count"))?)?;
+                    let voxels = data
+                        .chunks_exact(4)
+                        .map(|v| [v[0], v[1], v[2], v[3]])
+                        .filter(|v| v[3] != 0)
+                        .collect();
+                    models.push(VoxModel { size, voxels });
+                }
+                b"RGBA" => {
+                    // Entry i of the chunk is the color of index i + 1.
+                    for color in &mut palette[1..] {
+                        let rgba = content.take(4)?;
+                        *color = [rgba[0], rgba[1], rgba[2], rgba[3]];
+                    }
+                }
+                b"nTRN" => {
+                    let id = content.i32()?;
+                    content.dict()?;
+                    let child = content.i32()?;
+                    let _reserved = content.i32()?;
+                    let _layer = content.i32()?;
+                    let frame_count = content.i32()?;
+                    // Only the first animation frame is used.
+                    let frame = if frame_count > 0 { content.dict()? } else { HashMap::new() };
+                    let rotation = match frame.get("_r") {
+                        Some(r) => decode_rotation(r.parse().map_err(|_| invalid_data("bad nTRN rotation"))?)?,
+                        None => Mat3::IDENTITY,
+                    };
+                    let translation = match frame.get("_t") {
+                        Some(t) => parse_translation(t)?,
+                        None => IVec3::ZERO,
+                    };
+                    nodes.insert(id, SceneNode::Transform { child, rotation, translation });
+                }
+                b"nGRP" => {
+                    let id = content.i32()?;
+                    content.dict()?;
+                    let count = content.i32()?;
+                    let children = (0..count).map(|_| content.i32()).collect::<io::Result<_>>()?;
+                    nodes.insert(id, SceneNode::Group { children });
+                }
+                b"nSHP" => {
+                    let id = content.i32()?;
+                    content.dict()?;
+                    let count = content.i32()?;
+                    let mut shape_models = Vec::new();
+                    for _ in 0..count {
+                        shape_models.push(content.i32()? as usize);
+                        content.dict()?;
+                    }
+                    nodes.insert(id, SceneNode::Shape { models: shape_models });
+                }
+                _ => {}
+            }
+        }
+
+        let mut instances = Vec::new();
+        if nodes.is_empty() {
+            // Files without a scene graph place every model with its corner at the origin.
+            for (model, data) in models.iter().enumerate() {
+                instances.push(VoxInstance {
+                    model,
+                    rotation: Mat3::IDENTITY,
+                    translation: (data.size / 2).as_ivec3(),
+                });
+            }
+        } else {
+            collect_instances(&nodes, 0, Mat3::IDENTITY, IVec3::ZERO, 0, &mut instances)?;
+        }
+        if let Some(instance) = instances.iter().find(|instance| instance.model >= models.len()) {
+            return Err(invalid_data(format!("shape refers to missing model {}", instance.model)));
+        }
+
+        Ok(Self {
+            models,
+            palette,
+            instances,
+        })
+    }
+
+    /// Writes the file to `path`.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+
+    /// Writes the models, a scene graph placing every instance and the RGBA palette.
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut children = Vec::new();
+        for model in &self.models {
+            let mut size = Vec::new();
+            for extent in model.size.to_array() {
+                push_u32(&mut size, extent);
+            }
+            push_chunk(&mut children, b"SIZE", &size);
+
+            let mut xyzi = Vec::with_capacity(4 + model.voxels.len() * 4);
+            push_u32(&mut xyzi, model.voxels.len() as u32);
+            xyzi.extend(model.voxels.iter().flatten());
+            push_chunk(&mut children, b"XYZI", &xyzi);
+        }
+
+        // Scene graph: root transform (0) -> group (1) -> transform + shape per instance.
+        let mut content = Vec::new();
+        push_transform(&mut content, 0, 1, -1, &[]);
+        push_chunk(&mut children, b"nTRN", &content);
+
+        content.clear();
+        push_u32(&mut content, 1);
+        push_dict(&mut content, &[]);
+        push_u32(&mut content, self.instances.len() as u32);
+        for index in 0..self.instances.len() {
+            push_u32(&mut content, 2 + 2 * index as u32);
+        }
+        push_chunk(&mut children, b"nGRP", &content);
+
+        for (index, instance) in self.instances.iter().enumerate() {
+            let id = 2 + 2 * index as i32;
+            let t = instance.translation;
+            let mut frame = vec![("_t", format!("{} {} {}", t.x, t.y, t.z))];
+            if instance.rotation != Mat3::IDENTITY {
+                frame.push(("_r", encode_rotation(instance.rotation)?.to_string()));
+            }
+            content.clear();
+            push_transform(&mut content, id, id + 1, 0, &frame);
+            push_chunk(&mut children, b"nTRN", &content);
+
+            content.clear();
+            push_u32(&mut content, (id + 1) as u32);
+            push_dict(&mut content, &[]);
+            push_u32(&mut content, 1);
+            push_u32(&mut content, instance.model as u32);
+            push_dict(&mut content, &[]);
+            push_chunk(&mut children, b"nSHP", &content);
+        }
+
+        content.clear();
+        push_u32(&mut content, 0);
+        push_dict(&mut content, &[]);
+        push_u32(&mut content, u32::MAX);
+        push_chunk(&mut children, b"LAYR", &content);
+
+        // The chunk always holds 256 colors. Entry i is index i + 1, so the last one is unused.
+        let mut rgba = self.palette[1..].concat();
+        rgba.extend_from_slice(&[0; 4]);
+        push_chunk(&mut children, b"RGBA", &rgba);
+
+        writer.write_all(b"VOX ")?;
+        writer.write_all(&150_u32.to_le_bytes())?;
+        writer.write_all(b"MAIN")?;
+        writer.write_all(&0_u32.to_le_bytes())?;
+        writer.write_all(&(children.len() as u32).to_le_bytes())?;
+        writer.write_all(&children)
+    }
+
+    /// Iterates over every voxel of the scene as `(position, color index)`.
+    /// Positions are integer cells in MagicaVoxel's Z-up scene space.
+    pub fn voxels(&self) -> impl Iterator<Item = (IVec3, u8)> + '_ {
+        self.instances.iter().flat_map(move |instance| {
+            let model = &self.models[instance.model];
+            // MagicaVoxel rotates models around their center cell.
+            let pivot = (model.size / 2).as_vec3();
+            model.voxels.iter().map(move |&[x, y, z, color]| {
+                let local = Vec3::new(x as f32, y as f32, z as f32) - pivot;
+                let position = instance.rotation * local;
+                (position.round().as_ivec3() + instance.translation, color)
+            })
+        })
+    }
+}
+
+impl SparseVoxelOctree {
+    /// Inserts every voxel of `vox` into the octree. Each `.vox` cell becomes one cell at `depth`,
+    /// with the scene origin at `offset`. MagicaVoxel's Z-up axes are converted to Y-up.
+    /// Palette colors are registered in `palette` as materials named `vox #rrggbb`, reusing
+    /// existing entries of the same name. Returns false, and inserts nothing, if the root cannot
+    /// grow to contain the scene.
+    pub fn import_vox(&mut self, vox: &VoxFile, palette: &mut MaterialPalette, offset: Vec3, depth: u32) -> bool {
+        let depth = depth.min(self.max_depth);
+        let voxel_size = self.get_spacing_at_depth(depth);
+
+        let mut materials: HashMap<u8, MaterialId> = HashMap::new();
+        let cells: Vec<(Vec3, Voxel)> = vox
+            .voxels()
+            .map(|(position, color)| {
+                let material = *materials
+                    .entry(color)
+                    .or_insert_with(|| vox_material(palette, vox.palette[color as usize]));
+                // (x, y, z) Z-up -> (x, z, -y) Y-up. The -y cell spans [-y - 1, -y].
+                let cell = IVec3::new(position.x, position.z, -position.y - 1);
+                let center = offset + (cell.as_vec3() + Vec3::splat(0.5)) * voxel_size;
+                (center, Voxel::new(material))
+            })
+            .collect();
+
+        let Some(bounds) = cells
+            .iter()
+            .map(|&(center, _)| AABB::new(center - Vec3::splat(voxel_size * 0.5), center + Vec3::splat(voxel_size * 0.5)))
+            .reduce(|a, b| AABB::new(a.min.min(b.min), a.max.max(b.max)))
+        else {
+            return true;
+        };
+
+        // Expanding deepens the tree, so the cell size stays the same one level further down.
+        let max_depth = self.max_depth;
+        if !self.ensure_contains(&bounds) {
+            return false;
+        }
+        let depth = depth + (self.max_depth - max_depth);
+
+        // One undo step for the whole scene.
+        self.begin_group();
+        for (center, voxel) in cells {
+            let key = self.key_at(center, depth);
+            self.insert_key(key, voxel);
+        }
+        self.end_group();
+        true
+    }
+}
+
+/// Most cells `export_vox` writes. Every cell at max depth is one `.vox` voxel, so larger
+/// exports are refused instead of expanding merged leaves without bound.
+pub const MAX_VOX_EXPORT_CELLS: u128 = 1 << 26;
+
+impl SparseVoxelOctree {
+    /// Exports the voxels, or only those whose cell center lies in `region`, as a `.vox` scene.
+    /// Every cell at max depth becomes one `.vox` voxel, so merged leaves are written out in full.
+    /// Fails with `InvalidInput` if that is more than `MAX_VOX_EXPORT_CELLS` cells.
+    /// Scene coordinates are cell indices around the world origin with Z up, which makes
+    /// `import_vox` at max depth and a zero offset restore the same cells.
+    /// Material colors are reduced to 255 palette entries if needed, and the scene is split into
+    /// several models where it exceeds MagicaVoxel's 256-voxel model size.
+    pub fn export_vox(&self, palette: &MaterialPalette, region: Option<&AABB>) -> io::Result<VoxFile> {
+        let cell_count = self.count_in_region(region.unwrap_or(&self.root_bounds()));
+        if cell_count > MAX_VOX_EXPORT_CELLS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{cell_count} cells exceed the .vox export limit of {MAX_VOX_EXPORT_CELLS}"),
+            ));
+        }
+
+        let half = (1_i64 << self.max_depth) / 2;
+        let mut colors: HashMap<MaterialId, [u8; 4]> = HashMap::new();
+        let mut cells: Vec<(IVec3, MaterialId)> = Vec::new();
+
+        let entries = match region {
+            Some(region) => self.iter_in_aabb(region),
+            None => self.iter(),
+        };
+        for entry in entries {
+            let material = entry.voxel.material;
+            colors.entry(material).or_insert_with(|| {
+                let resolved = palette.resolve(material);
+                let [r, g, b, _] = resolved.color.to_srgba().to_u8_array();
+                [r, g, b, ((1.0 - resolved.transparency) * 255.0).round() as u8]
+            });
+
+            // Leaves cut by the region are split down to the cells inside it.
+            let bounds = self.key_bounds(entry.key);
+            if let Some(region) = region.filter(|region| !region.contains_aabb(&bounds)) {
+                let mut keys = Vec::new();
+                self.region_cell_keys(entry.key, region, &mut keys);
+                cells.extend(keys.into_iter().map(|key| ((key.position.as_i64vec3() - I64Vec3::splat(half)).as_ivec3(), material)));
+                continue;
+            }
+
+            // The cell count was checked above, so the leaf can be expanded in full.
+            let shift = self.max_depth - entry.depth;
+            let first = (entry.key.position.as_i64vec3() << shift) - I64Vec3::splat(half);
+            let cells_per_axis = 1_i64 << shift;
+            for x in 0..cells_per_axis {
+                for y in 0..cells_per_axis {
+                    for z in 0..cells_per_axis {
+                        cells.push(((first + I64Vec3::new(x, y, z)).as_ivec3(), material));
+                    }
+                }
+            }
+        }
+
+        // Assign palette indices, quantizing if there are more colors than entries.
+        let distinct: Vec<[u8; 4]> = {
+            let mut distinct: Vec<_> = colors.values().copied().collect();
+            distinct.sort_unstable();
+            distinct.dedup();
+            distinct
+        };
+        let entries = quantize(&distinct);
+        let mut vox_palette = [[0, 0, 0, 255]; 256];
+        vox_palette[0] = [0; 4];
+        vox_palette[1..=entries.len()].copy_from_slice(&entries);
+        let color_index: HashMap<MaterialId, u8> = colors
+            .iter()
+            .map(|(&material, &color)| (material, nearest_color(&entries, color) as u8 + 1))
+            .collect();
+
+        // Y-up cell (x, y, z) -> Z-up cell (x, -z - 1, y), grouped into 256^3 blocks
+        // counted from the lowest occupied cell.
+        let to_vox = |cell: IVec3| IVec3::new(cell.x, -cell.z - 1, cell.y);
+        let origin = cells.iter().fold(IVec3::MAX, |min, &(cell, _)| min.min(to_vox(cell)));
+        let mut blocks: BTreeMap<[i32; 3], Vec<(IVec3, u8)>> = BTreeMap::new();
+        for (cell, material) in cells {
+            let position = to_vox(cell);
+            let block = (position - origin).div_euclid(IVec3::splat(256));
+            blocks.entry(block.to_array()).or_default().push((position, color_index[&material]));
+        }
+
+        let mut models = Vec::with_capacity(blocks.len());
+        let mut instances = Vec::with_capacity(blocks.len());
+        for voxels in blocks.into_values() {
+            let min = voxels.iter().fold(IVec3::MAX, |min, &(position, _)| min.min(position));
+            let max = voxels.iter().fold(IVec3::MIN, |max, &(position, _)| max.max(position));
+            let size = (max - min + IVec3::ONE).as_uvec3();
+            instances.push(VoxInstance {
+                model: models.len(),
+                rotation: Mat3::IDENTITY,
+                // Models are placed around their center cell, see `VoxFile::voxels`.
+                translation: min + (size / 2).as_ivec3(),
+            });
+            models.push(VoxModel {
+                size,
+                voxels: voxels
+                    .into_iter()
+                    .map(|(position, color)| {
+                        let local = position - min;
+                        [local.x as u8, local.y as u8, local.z as u8, color]
+                    })
+                    .collect(),
+            });
+        }
+
+        Ok(VoxFile {
+            models,
+            palette: vox_palette,
+            instances,
+        })
+    }
+}
+
+/// Reduces `colors` to at most 255 entries with median cut. Fewer colors are returned unchanged.
+fn quantize(colors: &[[u8; 4]]) -> Vec<[u8; 4]> {
+    if colors.len() <= 255 {
+        return colors.to_vec();
+    }
+
+    let mut boxes = vec![colors.to_vec()];
+    while boxes.len() < 255 {
+        // Split the box with the widest range on any channel at its median.
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, colors)| colors.len() > 1)
+            .flat_map(|(index, colors)| {
+                (0..4).map(move |channel| {
+                    let (min, max) = colors.iter().fold((u8::MAX, u8::MIN), |(min, max), color| {
+                        (min.min(color[channel]), max.max(color[channel]))
+                    });
+                    (index, channel, max - min)
+                })
+            })
+            .max_by_key(|&(_, _, range)| range);
+        let Some((index, channel, _)) = widest else {
+            break;
+        };
+        let mut lower = boxes.swap_remove(index);
+        lower.sort_unstable_by_key(|color| color[channel]);
+        let upper = lower.split_off(lower.len() / 2);
+        boxes.push(lower);
+        boxes.push(upper);
+    }
+
+    boxes
+        .iter()
+        .map(|colors| {
+            let sum = colors.iter().fold([0_u32; 4], |mut sum, color| {
+                for channel in 0..4 {
+                    sum[channel] += color[channel] as u32;
+                }
+                sum
+            });
+            sum.map(|total| (total as f32 / colors.len() as f32).round() as u8)
+        })
+        .collect()
+}
+
+/// Index of the palette entry closest to `color`.
+fn nearest_color(palette: &[[u8; 4]], color: [u8; 4]) -> usize {
+    let distance = |entry: &[u8; 4]| -> i32 {
+        (0..4).map(|channel| (entry[channel] as i32 - color[channel] as i32).pow(2)).sum()
+    };
+    (0..palette.len()).min_by_key(|&index| distance(&palette[index])).unwrap_or(0)
+}
+
+/// Finds or registers the material for a `.vox` palette color.
+/// Falls back to the default material once the palette is full.
+fn vox_material(palette: &mut MaterialPalette, [r, g, b, a]: [u8; 4]) -> MaterialId {
+    let name = format!("vox #{r:02x}{g:02x}{b:02x}");
+    palette
+        .find(&name)
+        .or_else(|| {
+            palette.add(
+                VoxelMaterial::new(name, Color::srgb_u8(r, g, b)).with_transparency(1.0 - a as f32 / 255.0),
+            )
+        })
+        .unwrap_or_default()
+}
+
+/// Walks the scene graph from `id`, accumulating transforms down to the shape nodes.
+fn collect_instances(
+    nodes: &HashMap<i32, SceneNode>,
+    id: i32,
+    rotation: Mat3,
+    translation: IVec3,
+    depth: u32,
+    instances: &mut Vec<VoxInstance>,
+) -> io::Result<()> {
+    // Guards against cycles in malformed files.
+    if depth > 256 {
+        return Err(invalid_data("scene graph is too deep"));
+    }
+    match nodes.get(&id) {
+        Some(SceneNode::Transform { child, rotation: local_rotation, translation: local_translation }) => {
+            let child_translation = translation + (rotation * local_translation.as_vec3()).round().as_ivec3();
+            collect_instances(nodes, *child, rotation * *local_rotation, child_translation, depth + 1, instances)
+        }
+        Some(SceneNode::Group { children }) => {
+            for &child in children {
+                collect_instances(nodes, child, rotation, translation, depth + 1, instances)?;
+            }
+            Ok(())
+        }
+        Some(SceneNode::Shape { models }) => {
+            instances.extend(models.iter().map(|&model| VoxInstance { model, rotation, translation }));
+            Ok(())
+        }
+        None => Err(invalid_data(format!("missing scene node {id}"))),
+    }
+}
+
+/// Inverse of `decode_rotation`. Fails for matrices that are not signed permutations.
+fn encode_rotation(rotation: Mat3) -> io::Result<u8> {
+    let rows = rotation.transpose().to_cols_array_2d();
+    let mut packed = 0;
+    let mut used = [false; 3];
+    for (row, values) in rows.iter().enumerate() {
+        let column = values
+            .iter()
+            .position(|value| value.abs() == 1.0)
+            .filter(|&column| !used[column] && values.iter().filter(|value| **value != 0.0).count() == 1)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "rotation is not a signed permutation"))?;
+        used[column] = true;
+        if row < 2 {
+            packed |= (column as u8) << (2 * row);
+        }
+        if values[column] < 0.0 {
+            packed |= 1 << (4 + row);
+        }
+    }
+    Ok(packed)
+}
+
+/// Decodes the packed `_r` rotation of an nTRN frame.
+/// Bits 0-1 and 2-3 hold the column of the non-zero entry in rows 0 and 1; bits 4-6 the row signs.
+fn decode_rotation(packed: u8) -> io::Result<Mat3> {
+    let first = (packed & 3) as usize;
+    let second = ((packed >> 2) & 3) as usize;
+    if first > 2 || second > 2 || first == second {
+        return Err(invalid_data("bad nTRN rotation"));
+    }
+    let third = 3 - first - second;
+
+    let mut rows = [Vec3::ZERO; 3];
+    for (row, (column, sign_bit)) in [(first, 4), (second, 5), (third, 6)].into_iter().enumerate() {
+        rows[row][column] = if packed & (1 << sign_bit) != 0 { -1.0 } else { 1.0 };
+    }
+    Ok(Mat3::from_cols(rows[0], rows[1], rows[2]).transpose())
+}
+
+fn parse_translation(value: &str) -> io::Result<IVec3> {
+    let parts = value
+        .split_whitespace()
+        .map(|part| part.parse::<i32>().map_err(|_| invalid_data("bad nTRN translation")))
+        .collect::<io::Result<Vec<_>>>()?;
+    match parts[..] {
+        [x, y, z] => Ok(IVec3::new(x, y, z)),
+        _ => Err(invalid_data("bad nTRN translation")),
+    }
+}
+
+/// MagicaVoxel's built-in palette, used when a file has no RGBA chunk:
+/// a 6x6x6 color cube followed by red, green, blue and gray ramps.
+fn default_palette() -> [[u8; 4]; 256] {
+    const CUBE: [u8; 6] = [0xff, 0xcc, 0x99, 0x66, 0x33, 0x00];
+    const RAMP: [u8; 10] = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+
+    let mut palette = [[0; 4]; 256];
+    let mut colors = Vec::with_capacity(255);
+    for r in CUBE {
+        for g in CUBE {
+            for b in CUBE {
+                colors.push([r, g, b, 0xff]);
+            }
+        }
+    }
+    // The cube ends with black, which the palette does not include.
+    colors.pop();
+    colors.extend(RAMP.map(|v| [v, 0, 0, 0xff]));
+    colors.extend(RAMP.map(|v| [0, v, 0, 0xff]));
+    colors.extend(RAMP.map(|v| [0, 0, v, 0xff]));
+    colors.extend(RAMP.map(|v| [v, v, v, 0xff]));
+    palette[1..].copy_from_slice(&colors);
+    palette
+}
+
+/// Little-endian cursor over the file contents.
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, count: usize) -> io::Result<&'a [u8]> {
+        if count > self.bytes.len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated .vox file"));
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(self.u32()? as i32)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let length = self.u32()? as usize;
+        Ok(String::from_utf8_lossy(self.take(length)?).into_owned())
+    }
+
+    fn dict(&mut self) -> io::Result<HashMap<String, String>> {
+        let count = self.u32()?;
+        (0..count).map(|_| Ok((self.string()?, self.string()?))).collect()
+    }
+
+    /// Reads a chunk header and returns its id, content and children bytes.
+    fn chunk(&mut self) -> io::Result<(&'a [u8], &'a [u8], &'a [u8])> {
+        let id = self.take(4)?;
+        let content_size = self.u32()? as usize;
+        let children_size = self.u32()? as usize;
+        Ok((id, self.take(content_size)?, self.take(children_size)?))
+    }
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend(value.to_le_bytes());
+}
+
+fn push_dict(out: &mut Vec<u8>, entries: &[(&str, String)]) {
+    push_u32(out, entries.len() as u32);
+    for (key, value) in entries {
+        for string in [*key, value.as_str()] {
+            push_u32(out, string.len() as u32);
+            out.extend(string.as_bytes());
+        }
+    }
+}
+
+/// Writes the content of an nTRN node with a single frame.
+fn push_transform(out: &mut Vec<u8>, id: i32, child: i32, layer: i32, frame: &[(&str, String)]) {
+    push_u32(out, id as u32);
+    push_dict(out, &[]);
+    push_u32(out, child as u32);
+    push_u32(out, u32::MAX);
+    push_u32(out, layer as u32);
+    push_u32(out, 1);
+    push_dict(out, frame);
+}
+
+/// Appends a chunk without children.
+fn push_chunk(out: &mut Vec<u8>, id: &[u8; 4], content: &[u8]) {
+    out.extend(id);
+    push_u32(out, content.len() as u32);
+    push_u32(out, 0);
+    out.extend(content);
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::systems::voxels::structure::MAX_DEPTH;
+    use super::*;
+
+    const SINGLE_MODEL: &[u8] = include_bytes!("../../../assets/vox/single_model.vox");
+    const SCENE_GRAPH: &[u8] = include_bytes!("../../../assets/vox/scene_graph.vox");
+
+    fn sorted_voxels(vox: &VoxFile) -> Vec<(IVec3, u8)> {
+        let mut voxels: Vec<_> = vox.voxels().collect();
+        voxels.sort_by_key(|&(position, color)| (position.to_array(), color));
+        voxels
+    }
+
+    #[test]
+    fn parses_single_model_with_palette() {
+        let vox = VoxFile::parse(SINGLE_MODEL).unwrap();
+        assert_eq!(vox.models.len(), 1);
+        assert_eq!(vox.models[0].size, UVec3::new(3, 2, 4));
+        assert_eq!(vox.palette[1], [255, 0, 0, 255]);
+        assert_eq!(vox.palette[2], [0, 0, 255, 128]);
+
+        // Without a scene graph the model keeps its own coordinates.
+        assert_eq!(
+            sorted_voxels(&vox),
+            vec![
+                (IVec3::new(0, 0, 0), 1),
+                (IVec3::new(1, 0, 2), 1),
+                (IVec3::new(2, 0, 0), 1),
+                (IVec3::new(2, 1, 3), 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn applies_scene_graph_transforms() {
+        let vox = VoxFile::parse(SCENE_GRAPH).unwrap();
+        assert_eq!(vox.models.len(), 2);
+        assert_eq!(vox.instances.len(), 2);
+        // No RGBA chunk: the built-in palette is used.
+        assert_eq!(vox.palette[1], [0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(vox.palette[10], [0xff, 0xcc, 0x66, 0xff]);
+        assert_eq!(vox.palette[216], [0xee, 0x00, 0x00, 0xff]);
+        assert_eq!(vox.palette[255], [0x11, 0x11, 0x11, 0xff]);
+
+        assert_eq!(
+            sorted_voxels(&vox),
+            vec![
+                // Second model, rotated 90 degrees around Z and translated by (-5, 3, 0).
+                (IVec3::new(-5, 2, 0), 200),
+                (IVec3::new(-5, 3, 0), 201),
+                (IVec3::new(-5, 4, 0), 202),
+                // First model, translated by (10, 0, 1) around its center cell (1, 1, 1).
+                (IVec3::new(9, -1, 0), 10),
+                (IVec3::new(10, 0, 1), 10),
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_rotations() {
+        assert_eq!(decode_rotation(0b0000100).unwrap(), Mat3::IDENTITY);
+        let rotation = decode_rotation(17).unwrap();
+        assert_eq!(rotation * Vec3::X, Vec3::Y);
+        assert_eq!(rotation * Vec3::Y, -Vec3::X);
+        assert!(decode_rotation(0b0000000).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        assert!(VoxFile::parse(b"PNG\0").is_err());
+        assert!(VoxFile::parse(&SINGLE_MODEL[..SINGLE_MODEL.len() - 10]).is_err());
+    }
+
+    #[test]
+    fn imports_into_octree() {
+        let vox = VoxFile::parse(SINGLE_MODEL).unwrap();
+        let mut palette = MaterialPalette::default();
+        let materials = palette.len();
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        octree.import_vox(&vox, &mut palette, Vec3::new(2.0, 0.0, 0.0), 4);
+
+        // Two colors were registered as materials, the blue one translucent.
+        assert_eq!(palette.len(), materials + 2);
+        let red = palette.find("vox #ff0000").unwrap();
+        let blue = palette.find("vox #0000ff").unwrap();
+        assert!(palette.resolve(blue).transparency > 0.0);
+
+        // Z-up (x, y, z) lands in Y-up cell (x, z, -y - 1), one unit per cell at depth 4.
+        assert_eq!(octree.iter().count(), 4);
+        assert_eq!(octree.get_voxel_at_world_coords(Vec3::new(2.5, 0.5, -0.5)), Some(&Voxel::new(red)));
+        assert_eq!(octree.get_voxel_at_world_coords(Vec3::new(4.5, 3.5, -1.5)), Some(&Voxel::new(blue)));
+
+        // Importing again reuses the materials.
+        octree.import_vox(&vox, &mut palette, Vec3::new(2.0, 0.0, 0.0), 4);
+        assert_eq!(palette.len(), materials + 2);
+    }
+
+    #[test]
+    fn import_expands_root_and_keeps_voxel_size() {
+        let vox = VoxFile::parse(SCENE_GRAPH).unwrap();
+        let mut palette = MaterialPalette::default();
+        let mut octree = SparseVoxelOctree::new(3, 4.0, false, false, false);
+        octree.import_vox(&vox, &mut palette, Vec3::ZERO, 3);
+
+        assert!(octree.max_depth > 3);
+        assert_eq!(octree.iter().count(), 5);
+        assert!(octree.iter().all(|entry| entry.size == 0.5));
+        // Scene cell (10, 0, 1) is Y-up cell (10, 1, -1) with half-unit voxels.
+        assert!(octree.get_voxel_at_world_coords(Vec3::new(5.25, 0.75, -0.25)).is_some());
+    }
+
+    fn vox_cells(octree: &SparseVoxelOctree, palette: &MaterialPalette) -> Vec<(IVec3, [u8; 4])> {
+        let mut cells = Vec::new();
+        for entry in octree.iter() {
+            let shift = octree.max_depth - entry.depth;
+            let color = palette.color(entry.voxel.material).to_srgba().to_u8_array();
+            for x in 0..1 << shift {
+                for y in 0..1 << shift {
+                    for z in 0..1 << shift {
+                        cells.push(((entry.key.position << shift) + IVec3::new(x, y, z), color));
+                    }
+                }
+            }
+        }
+        cells.sort_by_key(|&(position, _)| position.to_array());
+        cells
+    }
+
+    #[test]
+    fn export_round_trips_through_import() {
+        let palette = MaterialPalette::default();
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        octree.fill_aabb(&AABB::new(Vec3::new(-4.0, -2.0, 0.0), Vec3::new(0.0, 2.0, 4.0)), Voxel::new(MaterialId(1)));
+        octree.insert(Vec3::new(5.5, -6.5, -7.5), Voxel::new(MaterialId(4)));
+        octree.insert(Vec3::new(-7.5, 7.5, 2.5), Voxel::new(MaterialId(6)));
+
+        let vox = octree.export_vox(&palette, None).unwrap();
+        assert_eq!(vox.models.len(), 1);
+        assert_eq!(vox.voxels().count(), 4 * 4 * 4 + 2);
+
+        let mut bytes = Vec::new();
+        vox.write(&mut bytes).unwrap();
+        let parsed = VoxFile::parse(&bytes).unwrap();
+        assert_eq!(parsed.models, vox.models);
+        assert_eq!(parsed.instances, vox.instances);
+        assert_eq!(parsed.palette, vox.palette);
+
+        let mut imported_palette = palette.clone();
+        let mut imported = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        imported.import_vox(&parsed, &mut imported_palette, Vec3::ZERO, 4);
+        assert_eq!(vox_cells(&imported, &imported_palette), vox_cells(&octree, &palette));
+    }
+
+    #[test]
+    fn writes_a_full_rgba_chunk() {
+        let mut vox = VoxFile::parse(SINGLE_MODEL).unwrap();
+        vox.palette[255] = [1, 2, 3, 4];
+        let mut bytes = Vec::new();
+        vox.write(&mut bytes).unwrap();
+
+        let start = bytes.windows(4).position(|id| id == b"RGBA").unwrap();
+        let content_size = u32::from_le_bytes(bytes[start + 4..start + 8].try_into().unwrap());
+        assert_eq!(content_size, 1024);
+        assert_eq!(&bytes[start + 12 + 254 * 4..start + 12 + 1024], &[1, 2, 3, 4, 0, 0, 0, 0]);
+        assert_eq!(VoxFile::parse(&bytes).unwrap().palette, vox.palette);
+    }
+
+    #[test]
+    fn export_region_only() {
+        let palette = MaterialPalette::default();
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        octree.fill_aabb(&AABB::new(Vec3::splat(-8.0), Vec3::splat(8.0)), Voxel::new(MaterialId(2)));
+
+        let region = AABB::new(Vec3::new(-1.0, 0.0, 2.0), Vec3::new(2.0, 1.0, 4.0));
+        let vox = octree.export_vox(&palette, Some(&region)).unwrap();
+        assert_eq!(vox.voxels().count(), 3 * 2);
+        assert_eq!(vox.models[0].size, UVec3::new(3, 2, 1));
+    }
+
+    #[test]
+    fn export_refuses_too_many_cells() {
+        let palette = MaterialPalette::default();
+        let mut octree = SparseVoxelOctree::new(MAX_DEPTH, 16.0, false, false, false);
+        octree.fill_aabb(&octree.root_bounds(), Voxel::new(MaterialId(2)));
+        let error = octree.export_vox(&palette, None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        // A small region of a large merged leaf only visits the cells it covers.
+        let mut octree = SparseVoxelOctree::new(24, 16_777_216.0, false, false, false);
+        octree.fill_aabb(&AABB::new(Vec3::ZERO, Vec3::splat(1_048_576.0)), Voxel::new(MaterialId(2)));
+        let region = AABB::new(Vec3::splat(1.0), Vec3::splat(3.0));
+        let vox = octree.export_vox(&palette, Some(&region)).unwrap();
+        assert_eq!(vox.voxels().count(), 2 * 2 * 2);
+    }
+
+    #[test]
+    fn export_splits_large_scenes() {
+        let palette = MaterialPalette::default();
+        let mut octree = SparseVoxelOctree::new(10, 1024.0, false, false, false);
+        octree.insert(Vec3::new(-300.5, 0.5, 0.5), Voxel::new(MaterialId(1)));
+        octree.insert(Vec3::new(300.5, 0.5, 0.5), Voxel::new(MaterialId(1)));
+        octree.insert(Vec3::new(0.5, 0.5, 0.5), Voxel::new(MaterialId(2)));
+
+        let vox = octree.export_vox(&palette, None).unwrap();
+        assert_eq!(vox.models.len(), 3);
+        assert!(vox.models.iter().all(|model| model.size.max_element() <= 256));
+
+        let mut bytes = Vec::new();
+        vox.write(&mut bytes).unwrap();
+        let mut imported_palette = palette.clone();
+        let mut imported = SparseVoxelOctree::new(10, 1024.0, false, false, false);
+        imported.import_vox(&VoxFile::parse(&bytes).unwrap(), &mut imported_palette, Vec3::ZERO, 10);
+        assert_eq!(vox_cells(&imported, &imported_palette), vox_cells(&octree, &palette));
+    }
+
+    #[test]
+    fn export_quantizes_large_palettes() {
+        let mut palette = MaterialPalette::new();
+        let mut octree = SparseVoxelOctree::new(5, 32.0, false, false, false);
+        for i in 0..400 {
+            let material = palette.add(VoxelMaterial::new(
+                format!("color {i}"),
+                Color::srgb_u8((i % 20 * 12) as u8, (i / 20 * 12) as u8, 128),
+            )).unwrap();
+            let position = Vec3::new((i % 20) as f32, (i / 20) as f32, 0.0) - Vec3::splat(12.0);
+            octree.insert(position + Vec3::splat(0.5), Voxel::new(material));
+        }
+
+        let vox = octree.export_vox(&palette, None).unwrap();
+        assert_eq!(vox.voxels().count(), 400);
+        // Every voxel maps to a palette entry close to its material color.
+        for (position, color) in vox.voxels() {
+            assert!(color >= 1);
+            let cell = IVec3::new(position.x, position.z, -position.y - 1) + IVec3::splat(12);
+            let [r, g, _, _] = vox.palette[color as usize];
+            assert!((r as i32 - cell.x * 12).abs() <= 12 && (g as i32 - cell.y * 12).abs() <= 12);
+        }
+    }
+
+    #[test]
+    fn encodes_rotations() {
+        for packed in [4, 17, 0b0100001, 0b1110110, 0b0011001] {
+            let rotation = decode_rotation(packed).unwrap();
+            assert_eq!(encode_rotation(rotation).unwrap(), packed);
+        }
+        assert!(encode_rotation(Mat3::from_rotation_z(0.3)).is_err());
+    }
+}
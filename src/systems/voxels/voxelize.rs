@@ -0,0 +1,737 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use base64::prelude::*;
+use bevy::prelude::*;
+use bevy::render::mesh::{PrimitiveTopology, VertexAttributeValues};
+use crate::systems::voxels::material::{MaterialId, MaterialPalette, VoxelMaterial};
+use crate::systems::voxels::region::{Containment, Region};
+use crate::systems::voxels::structure::{SparseVoxelOctree, Voxel, VoxelData, AABB};
+
+/// Which cells of a mesh become voxels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoxelizeMode {
+    /// Only cells touched by a triangle.
+    Surface,
+    /// Surface cells plus every cell inside the mesh. The mesh should be closed.
+    Solid,
+}
+
+/// Triangle soup ready to be voxelized, in world space.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TriangleMesh {
+    pub triangles: Vec<[Vec3; 3]>,
+    /// One color per triangle (the average of its vertex colors), if the source had vertex colors.
+    pub colors: Option<Vec<Color>>,
+}
+
+/// A mesh used as a region: cells overlapping a triangle are on its surface, and in solid mode
+/// cells that touch no triangle are inside or outside as a whole.
+/// Cells of `voxel_size` are always decided, so edits stop at that depth.
+pub struct MeshRegion<'a> {
+    triangles: &'a [[Vec3; 3]],
+    bvh: Bvh,
+    mode: VoxelizeMode,
+    voxel_size: f32,
+}
+
+/// Bounding volume hierarchy over the triangles, so cell tests only look at nearby triangles.
+struct Bvh {
+    nodes: Vec<BvhNode>,
+    /// Triangle indices; every node covers a consecutive range.
+    order: Vec<u32>,
+}
+
+struct BvhNode {
+    bounds: AABB,
+    start: u32,
+    count: u32,
+    /// Index of the first of two children, 0 for leaves.
+    children: u32,
+}
+
+impl TriangleMesh {
+    /// Extracts the triangles of a triangle-list mesh, with per-triangle colors from
+    /// `Mesh::ATTRIBUTE_COLOR` if present. Returns `None` for other topologies or missing positions.
+    /// Meshes loaded through the asset server (`model.glb#Mesh0/Primitive0`) can be passed here;
+    /// to read a glTF file directly, use `load_gltf`.
+    pub fn from_mesh(mesh: &Mesh) -> Option<Self> {
+        if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
+            return None;
+        }
+        let Some(VertexAttributeValues::Float32x3(positions)) = mesh.attribute(Mesh::ATTRIBUTE_POSITION) else {
+            return None;
+        };
+        let indices: Vec<usize> = match mesh.indices() {
+            Some(indices) => indices.iter().collect(),
+            None => (0..positions.len()).collect(),
+        };
+        if indices.iter().any(|&index| index >= positions.len()) {
+            return None;
+        }
+
+        let vertex_colors = match mesh.attribute(Mesh::ATTRIBUTE_COLOR) {
+            Some(VertexAttributeValues::Float32x4(colors)) if colors.len() == positions.len() => Some(colors),
+            _ => None,
+        };
+
+        let corners = indices.chunks_exact(3);
+        Some(Self {
+            triangles: corners.clone().map(|c| [c[0], c[1], c[2]].map(|index| Vec3::from(positions[index]))).collect(),
+            colors: vertex_colors.map(|colors| {
+                corners
+                    .map(|c| {
+                        let sum = c.iter().fold(Vec4::ZERO, |sum, &index| sum + Vec4::from(colors[index]));
+                        Color::from(LinearRgba::from_vec4(sum / 3.0))
+                    })
+                    .collect()
+            }),
+        })
+    }
+
+    /// Reads a Wavefront OBJ file. See `parse_obj`.
+    pub fn load_obj(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse_obj(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parses the vertices (`v`) and faces (`f`) of an OBJ file; polygons are split into triangle fans.
+    /// Vertex colors written as `v x y z r g b` are used if every vertex has one.
+    pub fn parse_obj(source: &str) -> io::Result<Self> {
+        let invalid = |line: usize| io::Error::new(io::ErrorKind::InvalidData, format!("invalid OBJ data on line {line}"));
+
+        let mut positions = Vec::new();
+        let mut vertex_colors = Vec::new();
+        let mut faces: Vec<[usize; 3]> = Vec::new();
+        for (number, line) in source.lines().enumerate() {
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("v") => {
+                    let values = tokens
+                        .map(|token| token.parse::<f32>().map_err(|_| invalid(number + 1)))
+                        .collect::<io::Result<Vec<_>>>()?;
+                    match values[..] {
+                        [x, y, z] => positions.push(Vec3::new(x, y, z)),
+                        [x, y, z, r, g, b] | [x, y, z, _, r, g, b] => {
+                            positions.push(Vec3::new(x, y, z));
+                            vertex_colors.push(Color::srgb(r, g, b));
+                        }
+                        [x, y, z, _] => positions.push(Vec3::new(x, y, z)),
+                        _ => return Err(invalid(number + 1)),
+                    }
+                }
+                Some("f") => {
+                    let corners = tokens
+                        .map(|token| {
+                            // `v`, `v/vt`, `v//vn` or `v/vt/vn`; negative indices count from the end.
+                            let index: i64 = token.split('/').next().unwrap_or_default().parse().map_err(|_| invalid(number + 1))?;
+                            let index = if index < 0 { positions.len() as i64 + index } else { index - 1 };
+                            usize::try_from(index)
+                                .ok()
+                                .filter(|&index| index < positions.len())
+                                .ok_or_else(|| invalid(number + 1))
+                        })
+                        .collect::<io::Result<Vec<_>>>()?;
+                    if corners.len() < 3 {
+                        return Err(invalid(number + 1));
+                    }
+                    for i in 1..corners.len() - 1 {
+                        faces.push([corners[0], corners[i], corners[i + 1]]);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let has_colors = !vertex_colors.is_empty() && vertex_colors.len() == positions.len();
+        Ok(Self {
+            triangles: faces.iter().map(|face| face.map(|index| positions[index])).collect(),
+            colors: has_colors.then(|| {
+                faces
+                    .iter()
+                    .map(|face| {
+                        let sum = face.iter().fold(Vec4::ZERO, |sum, &index| sum + vertex_colors[index].to_linear().to_vec4());
+                        Color::from(LinearRgba::from_vec4(sum / 3.0))
+                    })
+                    .collect()
+            }),
+        })
+    }
+
+    /// Reads a glTF file, either binary (`.glb`) or JSON (`.gltf`) with embedded or external buffers.
+    /// See `parse_gltf`.
+    pub fn load_gltf(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        Self::read_gltf(&std::fs::read(path)?, path.parent())
+    }
+
+    /// Parses the triangles of the default scene of a glTF file, each placed by its node's transform.
+    /// Buffers must be in the binary chunk or embedded as base64 data URIs. Triangles take the
+    /// material's base color times their `COLOR_0` vertex colors; a file without materials or
+    /// vertex colors gives an uncolored mesh.
+    pub fn parse_gltf(bytes: &[u8]) -> io::Result<Self> {
+        Self::read_gltf(bytes, None)
+    }
+
+    /// Like `parse_gltf`, also reading external buffers from `directory` if given.
+    fn read_gltf(bytes: &[u8], directory: Option<&Path>) -> io::Result<Self> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, format!("invalid glTF data: {message}"));
+        let gltf = gltf::Gltf::from_slice(bytes).map_err(|error| invalid(error.to_string()))?;
+
+        let mut buffers = Vec::new();
+        for buffer in gltf.buffers() {
+            let data = match buffer.source() {
+                gltf::buffer::Source::Bin => gltf.blob.clone().ok_or_else(|| invalid("missing binary chunk".into()))?,
+                gltf::buffer::Source::Uri(uri) => match (uri.strip_prefix("data:"), directory) {
+                    (Some(data), _) => {
+                        let (_, encoded) = data.split_once(";base64,").ok_or_else(|| invalid("unsupported data URI".into()))?;
+                        BASE64_STANDARD.decode(encoded).map_err(|error| invalid(error.to_string()))?
+                    }
+                    (None, Some(directory)) => std::fs::read(directory.join(uri))?,
+                    (None, None) => return Err(invalid(format!("external buffer {uri}"))),
+                },
+            };
+            if data.len() < buffer.length() {
+                return Err(invalid(format!("buffer {} is too short", buffer.index())));
+            }
+            buffers.push(data);
+        }
+
+        let mut mesh = Self::default();
+        let mut colors = Vec::new();
+        let mut colored = false;
+        let Some(scene) = gltf.default_scene().or_else(|| gltf.scenes().next()) else {
+            return Ok(mesh);
+        };
+        let mut nodes: Vec<(gltf::Node, Mat4)> = scene.nodes().map(|node| (node, Mat4::IDENTITY)).collect();
+        while let Some((node, parent)) = nodes.pop() {
+            let transform = parent * Mat4::from_cols_array_2d(&node.transform().matrix());
+            nodes.extend(node.children().map(|child| (child, transform)));
+            let Some(node_mesh) = node.mesh() else {
+                continue;
+            };
+            for primitive in node_mesh.primitives().filter(|primitive| primitive.mode() == gltf::mesh::Mode::Triangles) {
+                let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+                let Some(positions) = reader.read_positions() else {
+                    continue;
+                };
+                let positions: Vec<Vec3> = positions.map(|position| transform.transform_point3(Vec3::from(position))).collect();
+                let indices: Vec<usize> = match reader.read_indices() {
+                    Some(indices) => indices.into_u32().map(|index| index as usize).collect(),
+                    None => (0..positions.len()).collect(),
+                };
+                if indices.iter().any(|&index| index >= positions.len()) {
+                    return Err(invalid("index out of range".into()));
+                }
+                let vertex_colors: Option<Vec<Vec4>> = reader
+                    .read_colors(0)
+                    .map(|colors| colors.into_rgba_f32().map(Vec4::from).collect())
+                    .filter(|colors: &Vec<Vec4>| colors.len() == positions.len());
+                let base_color = Vec4::from(primitive.material().pbr_metallic_roughness().base_color_factor());
+                colored |= vertex_colors.is_some() || primitive.material().index().is_some();
+
+                for corners in indices.chunks_exact(3) {
+                    mesh.triangles.push([corners[0], corners[1], corners[2]].map(|index| positions[index]));
+                    let color = match &vertex_colors {
+                        Some(vertex_colors) => corners.iter().fold(Vec4::ZERO, |sum, &index| sum + vertex_colors[index]) / 3.0,
+                        None => Vec4::ONE,
+                    };
+                    colors.push(Color::from(LinearRgba::from_vec4(color * base_color)));
+                }
+            }
+        }
+        mesh.colors = colored.then_some(colors);
+        Ok(mesh)
+    }
+
+    /// Returns the mesh moved into world space by `transform`.
+    pub fn transformed(&self, transform: &Transform) -> Self {
+        let matrix = transform.compute_matrix();
+        Self {
+            triangles: self
+                .triangles
+                .iter()
+                .map(|triangle| triangle.map(|vertex| matrix.transform_point3(vertex)))
+                .collect(),
+            colors: self.colors.clone(),
+        }
+    }
+
+    pub fn bounds(&self) -> Option<AABB> {
+        self.triangles.iter().map(triangle_bounds).reduce(union)
+    }
+}
+
+impl<'a> MeshRegion<'a> {
+    pub fn new(triangles: &'a [[Vec3; 3]], mode: VoxelizeMode, voxel_size: f32) -> Self {
+        Self {
+            triangles,
+            bvh: Bvh::build(triangles),
+            mode,
+            voxel_size,
+        }
+    }
+
+    /// Parity test: a point is inside a closed mesh if a ray from it crosses an odd number of triangles.
+    fn is_inside(&self, point: Vec3) -> bool {
+        // A slightly skewed direction avoids running exactly along edges of axis-aligned meshes.
+        let direction = Vec3::new(1.0, 0.000_913, 0.000_547).normalize();
+        let mut crossings = 0;
+        self.bvh.visit(
+            |bounds| ray_hits_aabb(point, direction, bounds),
+            |index| {
+                if ray_hits_triangle(point, direction, &self.triangles[index]) {
+                    crossings += 1;
+                }
+                true
+            },
+        );
+        crossings % 2 == 1
+    }
+
+    fn touches_surface(&self, cell: &AABB) -> bool {
+        let mut touched = false;
+        self.bvh.visit(
+            |bounds| overlaps(bounds, cell),
+            |index| {
+                touched = triangle_overlaps_aabb(&self.triangles[index], cell);
+                !touched
+            },
+        );
+        touched
+    }
+}
+
+impl Region for MeshRegion<'_> {
+    fn bounds(&self) -> AABB {
+        self.bvh.nodes.first().map_or(AABB::default(), |root| root.bounds)
+    }
+
+    fn classify(&self, cell: &AABB) -> Containment {
+        let decided = cell.size().max_element() <= self.voxel_size * 1.001;
+        if self.bvh.nodes.is_empty() || !overlaps(&self.bounds(), cell) {
+            return Containment::Outside;
+        }
+        if self.touches_surface(cell) {
+            return if decided { Containment::Inside } else { Containment::Partial };
+        }
+        match self.mode {
+            VoxelizeMode::Solid if self.is_inside(cell.center()) => Containment::Inside,
+            _ => Containment::Outside,
+        }
+    }
+
+    /// True for points inside a solid mesh. A surface has no volume, so it contains no points.
+    fn contains_point(&self, point: Vec3) -> bool {
+        self.mode == VoxelizeMode::Solid && overlaps(&self.bounds(), &AABB::new(point, point)) && self.is_inside(point)
+    }
+}
+
+impl<T: VoxelData> SparseVoxelOctree<T> {
+    /// Rasterizes `mesh` into cells at `depth` (at most max depth), expanding the root to fit it.
+    /// Subtrees the mesh does not touch are skipped; in solid mode the inside is filled top-down.
+    /// Returns false, and changes nothing, if the root cannot grow to contain the mesh.
+    pub fn voxelize_mesh(&mut self, mesh: &TriangleMesh, depth: u32, mode: VoxelizeMode, voxel: T) -> bool {
+        let Some(bounds) = mesh.bounds() else {
+            return true;
+        };
+        let Some(voxel_size) = self.prepare_voxelize(&bounds, depth) else {
+            return false;
+        };
+        self.set_region(&MeshRegion::new(&mesh.triangles, mode, voxel_size), Some(voxel));
+        true
+    }
+
+    /// Grows the root to contain `bounds` and returns the cell size at `depth`, or `None` if the
+    /// root cannot grow that far. Depths past max depth use max depth cells.
+    fn prepare_voxelize(&mut self, bounds: &AABB, depth: u32) -> Option<f32> {
+        // Expanding deepens the tree, so the cell size at the requested depth is kept.
+        let voxel_size = self.get_spacing_at_depth(depth.min(self.max_depth));
+        self.ensure_contains(bounds).then_some(voxel_size)
+    }
+}
+
+impl SparseVoxelOctree {
+    /// Like `voxelize_mesh`, but surface cells take the color of the triangles touching them.
+    /// Colors are registered in `palette` as materials named `mesh #rrggbb`. Meshes without
+    /// colors, and the inside of solid meshes, use `material`. Returns false like `voxelize_mesh`.
+    pub fn voxelize_mesh_colored(
+        &mut self,
+        mesh: &TriangleMesh,
+        depth: u32,
+        mode: VoxelizeMode,
+        material: MaterialId,
+        palette: &mut MaterialPalette,
+    ) -> bool {
+        let Some(colors) = &mesh.colors else {
+            return self.voxelize_mesh(mesh, depth, mode, Voxel::new(material));
+        };
+        let Some(bounds) = mesh.bounds() else {
+            return true;
+        };
+        let Some(voxel_size) = self.prepare_voxelize(&bounds, depth) else {
+            return false;
+        };
+        // The fill and every color are a single undo step.
+        self.begin_group();
+        if mode == VoxelizeMode::Solid {
+            self.set_region(&MeshRegion::new(&mesh.triangles, mode, voxel_size), Some(Voxel::new(material)));
+        }
+
+        // Paint the surface one color at a time.
+        let mut groups: HashMap<[u8; 3], Vec<[Vec3; 3]>> = HashMap::new();
+        for (triangle, color) in mesh.triangles.iter().zip(colors) {
+            let [r, g, b, _] = color.to_srgba().to_u8_array();
+            groups.entry([r, g, b]).or_default().push(*triangle);
+        }
+        let mut groups: Vec<_> = groups.into_iter().collect();
+        groups.sort_unstable_by_key(|(color, _)| *color);
+        for ([r, g, b], triangles) in groups {
+            let name = format!("mesh #{r:02x}{g:02x}{b:02x}");
+            // A full palette falls back to the default material.
+            let material = palette
+                .find(&name)
+                .or_else(|| palette.add(VoxelMaterial::new(name, Color::srgb_u8(r, g, b))))
+                .unwrap_or_default();
+            let region = MeshRegion::new(&triangles, VoxelizeMode::Surface, voxel_size);
+            self.set_region(&region, Some(Voxel::new(material)));
+        }
+        self.end_group();
+        true
+    }
+}
+
+impl Bvh {
+    /// Triangles per leaf.
+    const LEAF_SIZE: usize = 4;
+
+    fn build(triangles: &[[Vec3; 3]]) -> Self {
+        let mut bvh = Self {
+            nodes: Vec::new(),
+            order: (0..triangles.len() as u32).collect(),
+        };
+        if !triangles.is_empty() {
+            let bounds: Vec<AABB> = triangles.iter().map(triangle_bounds).collect();
+            bvh.nodes.push(BvhNode { bounds: AABB::default(), start: 0, count: 0, children: 0 });
+            bvh.build_node(0, 0, triangles.len(), &bounds);
+        }
+        bvh
+    }
+
+    fn build_node(&mut self, node: usize, start: usize, end: usize, bounds: &[AABB]) {
+        let range = &mut self.order[start..end];
+        let node_bounds = range.iter().map(|&index| bounds[index as usize]).reduce(union).unwrap_or_default();
+        self.nodes[node] = BvhNode { bounds: node_bounds, start: start as u32, count: (end - start) as u32, children: 0 };
+        if end - start <= Self::LEAF_SIZE {
+            return;
+        }
+
+        // Split at the median centroid along the longest axis.
+        let size = node_bounds.size();
+        let axis = if size.x >= size.y && size.x >= size.z {
+            0
+        } else if size.y >= size.z {
+            1
+        } else {
+            2
+        };
+        let middle = (end - start) / 2;
+        range.select_nth_unstable_by(middle, |&a, &b| {
+            bounds[a as usize].center()[axis].total_cmp(&bounds[b as usize].center()[axis])
+        });
+
+        let children = self.nodes.len();
+        self.nodes[node].children = children as u32;
+        for _ in 0..2 {
+            self.nodes.push(BvhNode { bounds: AABB::default(), start: 0, count: 0, children: 0 });
+        }
+        self.build_node(children, start, start + middle, bounds);
+        self.build_node(children + 1, start + middle, end, bounds);
+    }
+
+    /// Calls `triangle` for every triangle in nodes accepted by `enter`. Stops when `triangle` returns false.
+    fn visit(&self, enter: impl Fn(&AABB) -> bool, mut triangle: impl FnMut(usize) -> bool) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if !enter(&node.bounds) {
+                continue;
+            }
+            if node.children != 0 {
+                stack.push(node.children as usize);
+                stack.push(node.children as usize + 1);
+                continue;
+            }
+            for &triangle_index in &self.order[node.start as usize..(node.start + node.count) as usize] {
+                if !triangle(triangle_index as usize) {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn triangle_bounds(triangle: &[Vec3; 3]) -> AABB {
+    AABB::new(
+        triangle[0].min(triangle[1]).min(triangle[2]),
+        triangle[0].max(triangle[1]).max(triangle[2]),
+    )
+}
+
+fn union(a: AABB, b: AABB) -> AABB {
+    AABB::new(a.min.min(b.min), a.max.max(b.max))
+}
+
+/// Like `AABB::intersects`, but boxes that touch count as overlapping, so triangles lying
+/// exactly on a cell face still mark that cell.
+fn overlaps(a: &AABB, b: &AABB) -> bool {
+    a.min.cmple(b.max).all() && b.min.cmple(a.max).all()
+}
+
+/// Separating axis test between a triangle and a box (Akenine-Möller). Touching counts as overlap.
+fn triangle_overlaps_aabb(triangle: &[Vec3; 3], cell: &AABB) -> bool {
+    let center = cell.center();
+    let half = cell.size() * 0.5;
+    let v = triangle.map(|vertex| vertex - center);
+    let edges = [v[1] - v[0], v[2] - v[1], v[0] - v[2]];
+
+    // Separated if the projections of the triangle and the box on `axis` do not overlap.
+    let separated = |axis: Vec3| {
+        let projections = v.map(|vertex| vertex.dot(axis));
+        let radius = half.dot(axis.abs());
+        projections.iter().copied().fold(f32::INFINITY, f32::min) > radius
+            || projections.iter().copied().fold(f32::NEG_INFINITY, f32::max) < -radius
+    };
+
+    // Box face normals, the triangle normal, then the nine edge cross products.
+    if [Vec3::X, Vec3::Y, Vec3::Z].into_iter().any(separated) || separated(edges[0].cross(edges[1])) {
+        return false;
+    }
+    !edges
+        .iter()
+        .flat_map(|edge| [Vec3::X, Vec3::Y, Vec3::Z].map(|axis| edge.cross(axis)))
+        .any(separated)
+}
+
+fn ray_hits_aabb(origin: Vec3, direction: Vec3, bounds: &AABB) -> bool {
+    let inverse = direction.recip();
+    let t1 = (bounds.min - origin) * inverse;
+    let t2 = (bounds.max - origin) * inverse;
+    let t_enter = t1.min(t2).max_element();
+    let t_exit = t1.max(t2).min_element();
+    t_enter <= t_exit && t_exit >= 0.0
+}
+
+/// Möller-Trumbore intersection, counting only hits in front of the origin.
+fn ray_hits_triangle(origin: Vec3, direction: Vec3, triangle: &[Vec3; 3]) -> bool {
+    let edge1 = triangle[1] - triangle[0];
+    let edge2 = triangle[2] - triangle[0];
+    let p = direction.cross(edge2);
+    let determinant = edge1.dot(p);
+    if determinant.abs() < f32::EPSILON {
+        return false;
+    }
+    let inverse = 1.0 / determinant;
+    let s = origin - triangle[0];
+    let u = s.dot(p) * inverse;
+    if !(0.0..=1.0).contains(&u) {
+        return false;
+    }
+    let q = s.cross(edge1);
+    let v = direction.dot(q) * inverse;
+    if v < 0.0 || u + v > 1.0 {
+        return false;
+    }
+    edge2.dot(q) * inverse > 0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::render::render_asset::RenderAssetUsages;
+    use super::*;
+
+    const COLORED_CUBE: &str = include_str!("../../../assets/meshes/colored_cube.obj");
+
+    fn cuboid(half_size: f32) -> TriangleMesh {
+        TriangleMesh::from_mesh(&Cuboid::from_length(half_size * 2.0).mesh().build()).unwrap()
+    }
+
+    fn count(octree: &SparseVoxelOctree) -> u128 {
+        octree.count_in_region(&octree.root_bounds())
+    }
+
+    #[test]
+    fn parses_obj_faces_and_colors() {
+        let mesh = TriangleMesh::parse_obj(COLORED_CUBE).unwrap();
+        assert_eq!(mesh.triangles.len(), 12);
+        assert_eq!(mesh.bounds(), Some(AABB::new(Vec3::splat(-2.5), Vec3::splat(2.5))));
+        let colors = mesh.colors.unwrap();
+        assert_eq!(colors[0].to_srgba().to_u8_array(), [255, 0, 0, 255]);
+
+        // Negative indices, `v/vt/vn` corners and a quad split into two triangles.
+        let mesh = TriangleMesh::parse_obj("v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvn 0 0 1\nf -4//1 -3//1 -2//1 -1//1\n").unwrap();
+        assert_eq!(mesh.triangles, vec![
+            [Vec3::ZERO, Vec3::X, Vec3::new(1.0, 1.0, 0.0)],
+            [Vec3::ZERO, Vec3::new(1.0, 1.0, 0.0), Vec3::Y],
+        ]);
+        assert_eq!(mesh.colors, None);
+
+        assert!(TriangleMesh::parse_obj("v 0 0 0\nf 1 2 3\n").is_err());
+        assert!(TriangleMesh::parse_obj("v 0 zero 0\n").is_err());
+    }
+
+    #[test]
+    fn reads_gltf_files() {
+        // A quad scaled by its node and moved by the parent node, with an external buffer.
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/meshes/quad.gltf");
+        let mesh = TriangleMesh::load_gltf(path).unwrap();
+        assert_eq!(mesh.triangles, vec![
+            [Vec3::new(10.0, 0.0, 0.0), Vec3::new(12.0, 0.0, 0.0), Vec3::new(12.0, 2.0, 0.0)],
+            [Vec3::new(10.0, 0.0, 0.0), Vec3::new(12.0, 2.0, 0.0), Vec3::new(10.0, 2.0, 0.0)],
+        ]);
+        // Yellow vertices times the material's base color.
+        assert_eq!(mesh.colors, Some(vec![Color::linear_rgb(1.0, 0.5, 0.0); 2]));
+
+        // The same file with the buffer embedded as a data URI.
+        let source = std::fs::read_to_string(path).unwrap();
+        assert!(TriangleMesh::parse_gltf(source.as_bytes()).is_err());
+        let buffer = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/meshes/quad.bin")).unwrap();
+        let uri = format!("data:application/octet-stream;base64,{}", BASE64_STANDARD.encode(buffer));
+        let embedded = source.replace("quad.bin", &uri);
+        assert_eq!(TriangleMesh::parse_gltf(embedded.as_bytes()).unwrap(), mesh);
+
+        assert!(TriangleMesh::parse_gltf(b"{}").is_err());
+        assert!(TriangleMesh::parse_gltf(&embedded.as_bytes()[..100]).is_err());
+    }
+
+    #[test]
+    fn reads_exported_glb() {
+        let mut palette = MaterialPalette::default();
+        let red = palette.add(VoxelMaterial::new("red", Color::linear_rgb(1.0, 0.0, 0.0))).unwrap();
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        octree.fill_aabb(&AABB::new(Vec3::ZERO, Vec3::new(2.0, 1.0, 1.0)), Voxel::new(red));
+        let mut bytes = Vec::new();
+        octree.export_gltf(&palette, None, &mut bytes).unwrap();
+
+        let mesh = TriangleMesh::parse_gltf(&bytes).unwrap();
+        assert_eq!(mesh.triangles.len(), 10 * 2);
+        assert_eq!(mesh.bounds(), Some(AABB::new(Vec3::ZERO, Vec3::new(2.0, 1.0, 1.0))));
+        assert!(mesh.colors.unwrap().iter().all(|&color| color == Color::linear_rgb(1.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn reads_bevy_meshes() {
+        let mesh = cuboid(1.0);
+        assert_eq!(mesh.triangles.len(), 12);
+        assert_eq!(mesh.colors, None);
+
+        let mut colored = Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::MAIN_WORLD);
+        colored.insert_attribute(Mesh::ATTRIBUTE_POSITION, vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]]);
+        colored.insert_attribute(Mesh::ATTRIBUTE_COLOR, vec![[1.0, 0.0, 0.0, 1.0], [0.0, 1.0, 0.0, 1.0], [0.0, 0.0, 1.0, 1.0]]);
+        let colors = TriangleMesh::from_mesh(&colored).unwrap().colors.unwrap();
+        assert_eq!(colors[0].to_linear(), LinearRgba::new(1.0 / 3.0, 1.0 / 3.0, 1.0 / 3.0, 1.0));
+
+        let lines = Mesh::new(PrimitiveTopology::LineList, RenderAssetUsages::MAIN_WORLD);
+        assert!(TriangleMesh::from_mesh(&lines).is_none());
+    }
+
+    #[test]
+    fn triangle_box_overlap() {
+        let cell = AABB::new(Vec3::ZERO, Vec3::ONE);
+        let triangle = |a: [f32; 3], b: [f32; 3], c: [f32; 3]| [Vec3::from(a), Vec3::from(b), Vec3::from(c)];
+
+        assert!(triangle_overlaps_aabb(&triangle([0.5, 0.5, -1.0], [0.5, 0.5, 2.0], [0.5, 2.0, 0.5]), &cell));
+        // A large triangle cutting through the cell with all vertices outside.
+        assert!(triangle_overlaps_aabb(&triangle([-5.0, -5.0, 0.5], [10.0, -5.0, 0.5], [-5.0, 10.0, 0.5]), &cell));
+        // Touching a face counts.
+        assert!(triangle_overlaps_aabb(&triangle([1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [1.0, 0.0, 1.0]), &cell));
+        assert!(!triangle_overlaps_aabb(&triangle([1.1, 0.0, 0.0], [1.1, 1.0, 0.0], [1.1, 0.0, 1.0]), &cell));
+        // Near a corner, only separated by an edge cross product axis.
+        assert!(!triangle_overlaps_aabb(&triangle([2.1, 0.0, 0.5], [0.0, 2.1, 0.5], [2.1, 2.1, 0.5]), &cell));
+    }
+
+    #[test]
+    fn solid_and_surface_cubes() {
+        let mesh = cuboid(2.5);
+
+        // Cells from -3 to 3: the 4^3 inside plus the shell cut by the faces.
+        let mut solid = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        solid.voxelize_mesh(&mesh, 4, VoxelizeMode::Solid, Voxel::new(MaterialId(1)));
+        assert_eq!(count(&solid), 6 * 6 * 6);
+        assert!(solid.get_voxel_at_world_coords(Vec3::splat(0.5)).is_some());
+        assert!(solid.get_voxel_at_world_coords(Vec3::splat(3.5)).is_none());
+
+        let mut surface = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        surface.voxelize_mesh(&mesh, 4, VoxelizeMode::Surface, Voxel::new(MaterialId(1)));
+        assert_eq!(count(&surface), 6 * 6 * 6 - 4 * 4 * 4);
+        assert!(surface.get_voxel_at_world_coords(Vec3::splat(0.5)).is_none());
+        assert!(surface.get_voxel_at_world_coords(Vec3::new(2.5, 0.5, 0.5)).is_some());
+    }
+
+    #[test]
+    fn voxelizes_at_the_requested_depth() {
+        // Depth 3 of a 16 unit tree has 2 unit cells: the cube covers -4..4.
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        octree.voxelize_mesh(&cuboid(2.5), 3, VoxelizeMode::Solid, Voxel::new(MaterialId(1)));
+        assert_eq!(count(&octree), 8 * 8 * 8);
+    }
+
+    #[test]
+    fn clamps_the_depth_to_max_depth() {
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        assert!(octree.voxelize_mesh(&cuboid(2.5), 6, VoxelizeMode::Solid, Voxel::new(MaterialId(1))));
+        assert_eq!(octree.max_depth, 4);
+        assert_eq!(count(&octree), 6 * 6 * 6);
+        assert_eq!(octree.validate(), Ok(()));
+    }
+
+    #[test]
+    fn solid_sphere_matches_its_volume() {
+        let sphere = Sphere::new(6.0).mesh().ico(4).unwrap();
+        let mesh = TriangleMesh::from_mesh(&sphere).unwrap().transformed(&Transform::from_xyz(0.3, 0.2, 0.1));
+        let mut octree = SparseVoxelOctree::new(5, 32.0, false, false, false);
+        octree.voxelize_mesh(&mesh, 5, VoxelizeMode::Solid, Voxel::new(MaterialId(1)));
+
+        // Conservative rasterization adds about half a shell of cells on top of the volume.
+        let volume = 4.0 / 3.0 * std::f32::consts::PI * 6.0_f32.powi(3);
+        let cells = count(&octree) as f32;
+        assert!(cells > volume && cells < volume * 1.5, "{cells} cells for a volume of {volume}");
+        for entry in octree.iter() {
+            let distance = entry.position.distance(Vec3::new(0.3, 0.2, 0.1));
+            assert!(distance < 6.0 + 3.0_f32.sqrt(), "voxel at {} is too far out", entry.position);
+        }
+    }
+
+    #[test]
+    fn expands_the_root_to_fit() {
+        let mesh = cuboid(1.5).transformed(&Transform::from_xyz(20.0, 0.0, 0.0));
+        let mut octree = SparseVoxelOctree::new(3, 8.0, false, false, false);
+        octree.voxelize_mesh(&mesh, 3, VoxelizeMode::Solid, Voxel::new(MaterialId(1)));
+        assert!(octree.max_depth > 3);
+        assert_eq!(count(&octree), 4 * 4 * 4);
+        assert!(octree.get_voxel_at_world_coords(Vec3::new(20.5, 0.5, 0.5)).is_some());
+    }
+
+    #[test]
+    fn paints_vertex_colors() {
+        let mesh = TriangleMesh::parse_obj(COLORED_CUBE).unwrap();
+        let mut palette = MaterialPalette::default();
+        let stone = palette.add(VoxelMaterial::new("stone", Color::srgb(0.5, 0.5, 0.5))).unwrap();
+        let mut octree = SparseVoxelOctree::new(4, 16.0, false, false, false);
+        octree.voxelize_mesh_colored(&mesh, 4, VoxelizeMode::Solid, stone, &mut palette);
+
+        assert_eq!(count(&octree), 6 * 6 * 6);
+        let material = |position: Vec3| octree.get_voxel_at_world_coords(position).unwrap().material;
+        assert_eq!(material(Vec3::splat(0.5)), stone);
+        assert_eq!(material(Vec3::new(2.5, 0.5, 0.5)), palette.find("mesh #ff0000").unwrap());
+        assert_eq!(material(Vec3::new(-2.5, 0.5, 0.5)), palette.find("mesh #00ffff").unwrap());
+        assert_eq!(material(Vec3::new(0.5, 0.5, 2.5)), palette.find("mesh #0000ff").unwrap());
+
+        // Voxelizing again reuses the registered colors.
+        let materials = palette.len();
+        octree.voxelize_mesh_colored(&mesh, 4, VoxelizeMode::Surface, stone, &mut palette);
+        assert_eq!(palette.len(), materials);
+    }
+}